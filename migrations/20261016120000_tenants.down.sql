@@ -0,0 +1,5 @@
+-- Add down migration script here
+DELETE FROM images WHERE tenant <> '';
+ALTER TABLE images DROP CONSTRAINT images_pkey;
+ALTER TABLE images DROP COLUMN tenant;
+ALTER TABLE images ADD PRIMARY KEY(image_identifier, image_format);
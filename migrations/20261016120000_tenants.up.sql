@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN tenant VARCHAR(64) NOT NULL DEFAULT '';
+ALTER TABLE images DROP CONSTRAINT images_pkey;
+ALTER TABLE images ADD PRIMARY KEY(tenant, image_identifier, image_format);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DELETE FROM images WHERE expires_at IS NULL;
+ALTER TABLE images ALTER COLUMN expires_at SET NOT NULL;
+ALTER TABLE images DROP COLUMN immutable;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN immutable BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE images ALTER COLUMN expires_at DROP NOT NULL;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN source BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE images SET source = TRUE WHERE image_format = 'png';
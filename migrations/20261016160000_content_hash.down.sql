@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX images_content_hash;
+ALTER TABLE images DROP COLUMN content_hash;
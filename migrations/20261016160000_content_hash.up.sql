@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN content_hash TEXT;
+CREATE INDEX images_content_hash ON images (tenant, content_hash);
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN uploaded_type TEXT;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+CREATE INDEX images_listing ON images (tenant, created_at, image_identifier) WHERE source;
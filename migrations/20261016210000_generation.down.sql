@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE images DROP COLUMN generation;
+DROP SEQUENCE image_generation;
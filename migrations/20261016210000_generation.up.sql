@@ -0,0 +1,3 @@
+-- Add up migration script here
+CREATE SEQUENCE image_generation;
+ALTER TABLE images ADD COLUMN generation BIGINT NOT NULL DEFAULT nextval('image_generation');
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX images_alias;
+ALTER TABLE images DROP COLUMN alias;
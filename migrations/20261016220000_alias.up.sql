@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN alias TEXT;
+CREATE UNIQUE INDEX images_alias ON images (tenant, alias);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE images DROP COLUMN deleted_at, DROP COLUMN restore_expires_at;
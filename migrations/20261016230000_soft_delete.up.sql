@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE images ADD COLUMN deleted_at TIMESTAMPTZ, ADD COLUMN restore_expires_at TIMESTAMPTZ;
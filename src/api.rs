@@ -1,91 +1,962 @@
+#[cfg(feature = "remote-upload")]
+use crate::remote::{FetchError, RemoteFetcher};
+#[cfg(feature = "webhooks")]
+use crate::webhook::WebhookEvent;
 use crate::{
+    contact_sheet,
+    histogram::{self, Histogram},
     image_format::ImageFormat,
-    transcode::{self, TranscoderError},
+    pipeline::Pipeline,
+    short_id, svg,
+    transcode::{self, ImageData, TranscoderError},
+    zip::ZipWriter,
 };
 use axum::{
+    async_trait,
     body::Bytes,
     debug_handler,
-    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
-    http::{Response, StatusCode},
-    response::{Html, IntoResponse},
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        rejection::{JsonRejection, QueryRejection},
+        DefaultBodyLimit, FromRequestParts, Multipart, OriginalUri, Path, Query, RawPathParams,
+        State,
+    },
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Response, StatusCode, Uri},
+    middleware,
+    response::IntoResponse,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
-use chrono::Duration;
-use image::ImageReader;
-use serde::{de, Deserialize, Deserializer};
-use std::{io::Cursor, str::FromStr, sync::Arc};
-use tracing::{info, warn};
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{DateTime, Duration, Utc};
+use image::{ImageError, ImageReader};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    error::Error,
+    io::Cursor,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::io::ReaderStream;
+use tower_http::decompression::RequestDecompressionLayer;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::{database::Database, transcode::TranscodeTarget};
+use crate::{
+    database::{
+        CacheInfo, Database, ImageMeta, DeleteImageError, KeptUpload, NewSource, SaveImageError,
+        Tenant,
+    },
+    Config,
+    transcode::{
+        AnimationPolicy, ChromaSubsampling, ColorSpace, Sharpen, TranscodeEstimate, TranscodeTarget,
+        TranscodeTargetError, Watermark, WatermarkPosition,
+    },
+};
+
+mod admin;
+mod error;
+mod rate_limit;
+
+use error::ApiError;
+use rate_limit::RateLimiter;
 
 struct ApiState {
     pub database: Database,
+    pub fallback_image: Option<FallbackImage>,
+    pub read_only: AtomicBool,
+    pub upload_permits: Option<Arc<Semaphore>>,
+    pub config: Config,
+    pub not_computed_status: StatusCode,
+    #[cfg(feature = "remote-upload")]
+    pub remote_fetcher: Option<RemoteFetcher>,
+}
+
+impl ApiState {
+    fn ensure_writable(&self) -> Result<(), ApiError> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "read_only",
+                "Server is in read-only mode",
+            ));
+        }
+        Ok(())
+    }
+
+    //Fails fast instead of queueing, a waiting upload still holds its connection and buffer.
+    //Owned so background url uploads keep counting until they are stored.
+    fn acquire_upload_permit(&self) -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+        match &self.upload_permits {
+            Some(permits) => permits.clone().try_acquire_owned().map(Some).map_err(|_| {
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "too_many_uploads",
+                    "Too many concurrent uploads, try again later",
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct FallbackImage {
+    data: Bytes,
+    mime_type: String,
+    status: StatusCode,
 }
 
+impl FallbackImage {
+    pub async fn load(path: &std::path::Path, status: u16) -> Result<FallbackImage, Box<dyn Error>> {
+        let status = StatusCode::from_u16(status)?;
+        let data = tokio::fs::read(path).await.map_err(|e| {
+            format!("could not read fallback image {}: {e}", path.display())
+        })?;
+        let mime_type = mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .to_string();
+
+        Ok(FallbackImage {
+            data: Bytes::from(data),
+            mime_type,
+            status,
+        })
+    }
+
+    fn response(&self) -> Response<axum::body::Body> {
+        Response::builder()
+            .status(self.status)
+            .header("Content-Type", &self.mime_type)
+            //Stands in for a missing image, which may still be uploaded under the same id.
+            .header(header::CACHE_CONTROL, error::NO_STORE)
+            .body(axum::body::Body::from(self.data.clone()))
+            .unwrap()
+    }
+}
 
-pub fn router(body_limit: &DefaultBodyLimit, database: Database) -> Router {
+pub fn router(
+    config: &Config,
+    upload_limit: &DefaultBodyLimit,
+    batch_limit: &DefaultBodyLimit,
+    database: Database,
+    fallback_image: Option<FallbackImage>,
+) -> Router {
+    let admin_enabled = config.admin_token.is_some();
     let api_state = Arc::new(ApiState {
         database,
+        fallback_image,
+        read_only: AtomicBool::new(config.read_only),
+        upload_permits: config
+            .max_concurrent_uploads
+            .map(|permits| Arc::new(Semaphore::new(permits))),
+        config: config.clone(),
+        not_computed_status: StatusCode::from_u16(config.not_computed_status)
+            .expect("NOT_COMPUTED_STATUS is checked before the router is built"),
+        #[cfg(feature = "remote-upload")]
+        remote_fetcher: config.remote_uploads.then(|| {
+            RemoteFetcher::new(
+                config.remote_upload_hosts.clone(),
+                config.remote_upload_allow_private,
+                std::time::Duration::from_secs(config.remote_upload_timeout_secs),
+                config.max_image_size.unwrap_or(DEFAULT_REMOTE_UPLOAD_SIZE),
+            )
+        }),
     });
 
-    Router::new()
-        .route("/upload", post(upload))
-        .layer(body_limit.clone())
-        .route("/:image_id", get(serve_image))
-        .with_state(api_state)
+    let routes = Router::new()
+        .route("/upload", post(upload).layer(upload_limit.clone()))
+        .route("/upload/batch", post(upload_batch).layer(batch_limit.clone()))
+        .route("/validate", post(validate_upload).layer(upload_limit.clone()))
+        .layer(RequestDecompressionLayer::new())
+        .route("/archive", get(archive))
+        .route("/contact-sheet", get(serve_contact_sheet))
+        .route("/images", get(list_images))
+        .route("/alias/:alias", get(serve_alias))
+        .route("/:image_id", get(serve_image).delete(delete_image))
+        .route("/:image_id/estimate", get(estimate_transform))
+        .route("/:image_id/exists", get(image_exists))
+        .route("/:image_id/meta", get(get_meta))
+        .route("/:image_id/formats", get(get_formats).post(warm_formats))
+        .route("/:image_id/histogram", get(get_histogram))
+        .route("/:image_id/raw", get(serve_raw))
+        .route("/:image_id/regenerate", post(regenerate));
+    #[cfg(feature = "remote-upload")]
+    let routes = routes.route("/upload/url", post(upload_url));
+
+    let mut router = Router::new()
+        .merge(routes.clone())
+        .nest("/tenants/:tenant", routes);
+    if admin_enabled {
+        router = router.nest("/admin", admin::router());
+    }
+    let router = router.with_state(api_state);
+    match config.rate_limit {
+        Some(max_requests) => {
+            let limiter = RateLimiter::new(
+                max_requests,
+                std::time::Duration::from_secs(config.rate_limit_window_secs),
+            );
+            router.layer(middleware::from_fn_with_state(
+                Arc::new(std::sync::Mutex::new(limiter)),
+                rate_limit::limit,
+            ))
+        }
+        None => router,
+    }
+}
+
+const TENANT_HEADER: &str = "X-Tenant";
+const AVAILABLE_FORMATS_HEADER: &str = "X-Available-Formats";
+const CACHE_HEADER: &str = "X-Cache";
+const IMAGE_WIDTH_HEADER: &str = "X-Image-Width";
+const IMAGE_HEIGHT_HEADER: &str = "X-Image-Height";
+const IMAGE_CHANNELS_HEADER: &str = "X-Image-Channels";
+const IMAGE_CAPTION_HEADER: &str = "X-Image-Caption";
+const ESTIMATED_WAIT_HEADER: &str = "x-estimated-wait-ms";
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Tenant
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = Option::<RawPathParams>::from_request_parts(parts, state)
+            .await
+            .unwrap_or_default();
+        let path_tenant = params
+            .iter()
+            .flat_map(|params| params.iter())
+            .find(|(key, _)| *key == "tenant")
+            .map(|(_, value)| value.to_string());
+        let header_tenant = parts
+            .headers
+            .get(TENANT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        match path_tenant.or(header_tenant) {
+            Some(name) => Tenant::new(&name)
+                .ok_or_else(|| ApiError::bad_request("invalid_tenant", "Invalid tenant")),
+            None => Ok(Tenant::default()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ImageParams {
+    image_id: String,
+}
+
+//Ids are accepted as full uuids and in their short base62 form, whichever the server hands out.
+fn parse_image_id(image_identifier: &str) -> Result<Uuid, ApiError> {
+    Uuid::from_str(image_identifier)
+        .ok()
+        .or_else(|| short_id::decode(image_identifier))
+        .ok_or_else(|| {
+            ApiError::bad_request("invalid_id", "Invalid image id, expected a uuid or short id")
+                .with_value(image_identifier)
+        })
+}
+
+fn ensure_enabled(format: ImageFormat) -> Result<ImageFormat, ApiError> {
+    if transcode::format_enabled(format) {
+        Ok(format)
+    } else {
+        Err(ApiError::bad_request(
+            "unsupported_format",
+            format!("{} output is not available on this server", format.to_str()),
+        ))
+    }
+}
+
+//`<uuid>.<ext>` picks the output format through the path, for URLs that cache well behind CDNs.
+fn split_extension(image_identifier: &str) -> Result<(&str, Option<ImageFormat>), ApiError> {
+    match image_identifier.rsplit_once('.') {
+        Some((image_identifier, extension)) => match ImageFormat::from_str(extension) {
+            Some(format) => Ok((image_identifier, Some(format))),
+            None => Err(ApiError::bad_request(
+                "unsupported_format",
+                format!("unsupported image format: {extension}"),
+            )),
+        },
+        None => Ok((image_identifier, None)),
+    }
 }
 
 #[derive(Deserialize)]
 struct UploadSettings{
     ttl_secs : Option<i64>,
+    #[serde(default)]
+    immutable: bool,
+    #[serde(default, deserialize_with = "empty_string_as_none_image_format")]
+    store_as: Option<ImageFormat>,
+    #[serde(default)]
+    sync: bool,
+    //Url uploads only, answers 202 before the image is fetched.
+    #[cfg(feature = "remote-upload")]
+    #[serde(default, rename = "async")]
+    background: bool,
+    caption: Option<String>,
+    alias: Option<String>,
+    #[cfg(feature = "svg")]
+    #[serde(default, deserialize_with = "empty_string_as_none_u32")]
+    width: Option<u32>,
+    #[cfg(feature = "svg")]
+    #[serde(default, deserialize_with = "empty_string_as_none_u32")]
+    height: Option<u32>,
+}
+
+#[cfg(feature = "svg")]
+async fn rasterize_svg(data: Vec<u8>, settings: &UploadSettings) -> Result<Vec<u8>, ApiError> {
+    let (width, height) = (settings.width, settings.height);
+    transcode::run_blocking(move || svg::rasterize(&data, width, height))
+        .await
+        .map_err(|_| ApiError::internal())?
+        .map_err(|e| ApiError::bad_request("invalid_svg", e))
+}
+
+#[cfg(not(feature = "svg"))]
+async fn rasterize_svg(_data: Vec<u8>, _settings: &UploadSettings) -> Result<Vec<u8>, ApiError> {
+    Err(ApiError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "svg_not_supported",
+        "SVG images are not supported",
+    ))
+}
+
+const IMAGE_SIZE_EXCEEDED: &str = "Upload exceeds the maximum image size";
+const BATCH_SIZE_EXCEEDED: &str = "Upload exceeds the maximum batch size";
+const SVG_MIME_TYPE: &str = "image/svg+xml";
+const KEPT_UPLOAD_CSP: &str = "default-src 'none'; sandbox";
+
+//The body limit counts decompressed bytes, so oversized compressed uploads are caught here too.
+fn multipart_error(e: MultipartError, limit_message: &str) -> ApiError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        info!("Upload exceeded the body limit: {e:?}");
+        return ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", limit_message);
+    }
+    info!("Malformed multipart body: {e:?}");
+    ApiError::bad_request("malformed_multipart", "Malformed multipart body")
 }
 
 #[debug_handler]
 async fn upload(
     State(state): State<Arc<ApiState>>,
-    Query(uploadsettings): Query<UploadSettings>,
-    mut multipart: Multipart,
-) -> Html<String> {
-    let ttl = uploadsettings.ttl_secs.map(Duration::seconds);
+    tenant: Tenant,
+    uploadsettings: Result<Query<UploadSettings>, QueryRejection>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Json<UploadResponse>, ApiError> {
+    state.ensure_writable()?;
+    let _permit = state.acquire_upload_permit()?;
+    let Query(mut uploadsettings) = uploadsettings?;
+    if let Some(store_as) = uploadsettings.store_as {
+        ensure_enabled(store_as)?;
+    }
 
-    let mut file_data: Vec<u8> = Vec::new();
-    while let Some(field) = multipart
-        .next_field()
-        .await
-        .expect("failed to get the next field")
-    {
-        let data = field.bytes().await.unwrap();
-        data.iter().for_each(|byte| file_data.push(*byte));
+    let (file_data, declared_type, caption) = read_multipart(multipart?).await?;
+    if caption.is_some() {
+        if uploadsettings.caption.is_some() {
+            return Err(ApiError::bad_request(
+                "conflicting_caption",
+                "caption can be passed as a form field or on the query, not both",
+            ));
+        }
+        uploadsettings.caption = caption;
+    }
+    store_upload(&state, &tenant, file_data, declared_type, &uploadsettings).await
+}
+
+const MAX_CAPTION_LENGTH: usize = 1000;
+const MAX_ALIAS_LENGTH: usize = 128;
+
+fn check_caption(uploadsettings: &UploadSettings) -> Result<(), ApiError> {
+    match &uploadsettings.caption {
+        Some(caption) if caption.chars().count() > MAX_CAPTION_LENGTH => Err(ApiError::bad_request(
+            "caption_too_long",
+            format!("caption exceeds {MAX_CAPTION_LENGTH} characters"),
+        )),
+        _ => Ok(()),
     }
+}
 
-    let file_data = file_data;
+//Aliases end up in paths, so they are kept to characters that need no escaping and never look like an extension.
+fn check_alias(uploadsettings: &UploadSettings) -> Result<(), ApiError> {
+    let Some(alias) = &uploadsettings.alias else {
+        return Ok(());
+    };
+    let valid = !alias.is_empty()
+        && alias.len() <= MAX_ALIAS_LENGTH
+        && alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::bad_request(
+            "invalid_alias",
+            format!("aliases are 1 to {MAX_ALIAS_LENGTH} letters, digits, '-' or '_'"),
+        ))
+    }
+}
 
-    let mut reader = ImageReader::new(Cursor::new(file_data));
-    reader.no_limits();
-    let image_data = reader.with_guessed_format().unwrap();
-    match image_data.format() {
-        Some(_image) => {
-            match state
+fn new_source(uploadsettings: &UploadSettings) -> NewSource {
+    NewSource {
+        api_ttl: uploadsettings.ttl_secs.map(Duration::seconds),
+        immutable: uploadsettings.immutable,
+        alias: uploadsettings.alias.clone(),
+    }
+}
+
+//Everything after the bytes of a single upload are in, wherever they came from.
+async fn store_upload(
+    state: &ApiState,
+    tenant: &Tenant,
+    file_data: Vec<u8>,
+    declared_type: Option<String>,
+    uploadsettings: &UploadSettings,
+) -> Result<Json<UploadResponse>, ApiError> {
+    let uuid = save_upload(state, tenant, file_data, declared_type, uploadsettings, None).await?;
+    Ok(uploaded(state, uuid))
+}
+
+//Stores into the row of `reserved` when given instead of creating one.
+async fn save_upload(
+    state: &ApiState,
+    tenant: &Tenant,
+    file_data: Vec<u8>,
+    declared_type: Option<String>,
+    uploadsettings: &UploadSettings,
+    reserved: Option<Uuid>,
+) -> Result<Uuid, ApiError> {
+    check_caption(uploadsettings)?;
+    check_alias(uploadsettings)?;
+    let upload = state.config.keep_uploads.then(|| file_data.clone());
+    let (image_data, format) =
+        prepare_upload(state, file_data, declared_type, uploadsettings).await?;
+    let kept_upload = upload.map(|upload| kept_upload(upload, format));
+
+    let store_as = state.database.store_format(uploadsettings.store_as, format);
+    let saved = match reserved {
+        Some(uuid) => {
+            state
+                .database
+                .save_reserved_image(tenant, uuid, image_data, store_as, kept_upload)
+                .await
+        }
+        None => {
+            state
                 .database
                 .save_image(
+                    tenant,
                     image_data,
-                    ImageFormat::PNG,
-                    ttl,
-                ) //TODO: This is not at 2 minutes, make it variable with config.
+                    store_as,
+                    new_source(uploadsettings),
+                    kept_upload,
+                    uploadsettings.sync,
+                )
                 .await
-            {
-                Ok(uuid) => Html(format!("Good job! file has uuid: {:?}", uuid)),
-                Err(e) => {
-                    warn!("Error trying to save new image to database: {e:?}");
-                    Html("Internal server error...".to_string())
-                }
+        }
+    };
+    let uuid = saved.map_err(save_image_error)?;
+    save_caption(state, tenant, uuid, uploadsettings).await?;
+    Ok(uuid)
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    id: String,
+}
+
+fn uploaded(state: &ApiState, uuid: Uuid) -> Json<UploadResponse> {
+    Json(UploadResponse {
+        id: response_id(state, uuid),
+    })
+}
+
+//Short ids when they are enabled, images are stored under their uuid either way.
+fn response_id(state: &ApiState, uuid: Uuid) -> String {
+    if state.config.short_ids {
+        short_id::encode(uuid)
+    } else {
+        uuid.to_string()
+    }
+}
+
+#[cfg(feature = "remote-upload")]
+const DEFAULT_REMOTE_UPLOAD_SIZE: usize = 50 * 1024 * 1024;
+
+#[cfg(feature = "remote-upload")]
+#[derive(Deserialize)]
+struct UrlUpload {
+    url: String,
+}
+
+//Fetches the image at `url` and stores it like an uploaded one. With `async` the id is
+//answered right away and served as not computed until the image is stored.
+#[cfg(feature = "remote-upload")]
+#[debug_handler]
+async fn upload_url(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    uploadsettings: Result<Query<UploadSettings>, QueryRejection>,
+    request: Result<Json<UrlUpload>, JsonRejection>,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let Some(fetcher) = &state.remote_fetcher else {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "remote_uploads_disabled",
+            "Uploading from a url is not enabled on this server",
+        ));
+    };
+    state.ensure_writable()?;
+    let permit = state.acquire_upload_permit()?;
+    let Query(uploadsettings) = uploadsettings?;
+    if let Some(store_as) = uploadsettings.store_as {
+        ensure_enabled(store_as)?;
+    }
+    let Json(request) = request?;
+
+    if !uploadsettings.background {
+        let fetched = fetcher.fetch(&request.url).await.map_err(fetch_error)?;
+        return store_upload(&state, &tenant, fetched.data, fetched.content_type, &uploadsettings)
+            .await
+            .map(IntoResponse::into_response);
+    }
+    check_caption(&uploadsettings)?;
+    check_alias(&uploadsettings)?;
+    fetcher.check_url(&request.url).await.map_err(fetch_error)?;
+    //The stored format is only known once the image is in, the reservation's is a placeholder.
+    let uuid = state
+        .database
+        .reserve_image(
+            &tenant,
+            uploadsettings.store_as.unwrap_or_default(),
+            &new_source(&uploadsettings),
+        )
+        .await
+        .map_err(save_image_error)?;
+    let background_state = state.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        import_url(&background_state, &tenant, uuid, &request.url, &uploadsettings).await;
+    });
+    Ok((StatusCode::ACCEPTED, uploaded(&state, uuid)).into_response())
+}
+
+//Failed imports drop their reservation, so the id turns from not computed into not found.
+#[cfg(feature = "remote-upload")]
+async fn import_url(
+    state: &ApiState,
+    tenant: &Tenant,
+    uuid: Uuid,
+    url: &str,
+    uploadsettings: &UploadSettings,
+) {
+    let Some(fetcher) = &state.remote_fetcher else {
+        return;
+    };
+    let stored = match fetcher.fetch(url).await {
+        Ok(fetched) => save_upload(
+            state,
+            tenant,
+            fetched.data,
+            fetched.content_type,
+            uploadsettings,
+            Some(uuid),
+        )
+        .await
+        .map(|_| ()),
+        Err(e) => Err(fetch_error(e)),
+    };
+    if let Err(e) = stored {
+        info!("Background url upload of image: {uuid} failed: {e:?}");
+        if let Err(e) = state.database.discard_reserved_image(tenant, &uuid).await {
+            warn!("Could not discard the reservation of image: {uuid} because: {e:?}");
+        }
+    }
+}
+
+#[cfg(feature = "remote-upload")]
+fn fetch_error(e: FetchError) -> ApiError {
+    info!("Could not fetch a url upload: {e:?}");
+    match e {
+        FetchError::InvalidUrl(message) => ApiError::bad_request("invalid_url", message),
+        FetchError::NotAllowed(message) => {
+            ApiError::new(StatusCode::FORBIDDEN, "url_not_allowed", message)
+        }
+        FetchError::TooLarge => ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            IMAGE_SIZE_EXCEEDED,
+        ),
+        FetchError::TimedOut => ApiError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "fetch_timed_out",
+            "Fetching the url timed out",
+        ),
+        FetchError::Failed(message) => {
+            ApiError::new(StatusCode::BAD_GATEWAY, "fetch_failed", message)
+        }
+    }
+}
+
+async fn save_caption(
+    state: &ApiState,
+    tenant: &Tenant,
+    uuid: Uuid,
+    uploadsettings: &UploadSettings,
+) -> Result<(), ApiError> {
+    let Some(caption) = &uploadsettings.caption else {
+        return Ok(());
+    };
+    state
+        .database
+        .set_caption(tenant, &uuid, caption)
+        .await
+        .map_err(|e| {
+            warn!("Could not save the caption of image: {uuid} because: {e:?}");
+            ApiError::internal()
+        })
+}
+
+//The upload as it was sent, served with `original=true` while the normalized source serves everything else.
+fn kept_upload(upload: Vec<u8>, format: image::ImageFormat) -> KeptUpload {
+    //SVGs are rasterized before the format is settled.
+    let content_type = if svg::is_svg(&upload) {
+        SVG_MIME_TYPE
+    } else {
+        format.to_mime_type()
+    };
+    KeptUpload {
+        data: upload,
+        content_type,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchImage<'a> {
+    Uploaded { id: String },
+    Failed(error::ErrorBody<'a>),
+}
+
+#[derive(Serialize)]
+struct BatchResponse<'a> {
+    images: Vec<BatchImage<'a>>,
+}
+
+//Every multipart field is one image, each is checked against MAX_IMAGE_SIZE and stored on its own.
+//Results are listed in upload order, a failing image doesn't stop the rest.
+#[debug_handler]
+async fn upload_batch(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    uploadsettings: Result<Query<UploadSettings>, QueryRejection>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    state.ensure_writable()?;
+    let _permit = state.acquire_upload_permit()?;
+    let Query(uploadsettings) = uploadsettings?;
+    if let Some(store_as) = uploadsettings.store_as {
+        ensure_enabled(store_as)?;
+    }
+    if uploadsettings.alias.is_some() {
+        return Err(ApiError::bad_request(
+            "invalid_alias",
+            "an alias names a single image, it can not be given to a batch",
+        ));
+    }
+    let mut multipart = multipart?;
+
+    let mut results = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(multipart_error(e, BATCH_SIZE_EXCEEDED)),
+        };
+        let declared_type = field.content_type().map(str::to_string);
+        let file_data = match field.bytes().await {
+            Ok(data) => data.to_vec(),
+            Err(e) => return Err(multipart_error(e, BATCH_SIZE_EXCEEDED)),
+        };
+        results.push(save_batch_image(&state, &tenant, file_data, declared_type, &uploadsettings).await);
+    }
+
+    if results.is_empty() {
+        info!("Empty upload...");
+        return Err(ApiError::bad_request("empty_upload", "empty upload"));
+    }
+    let images = results
+        .iter()
+        .map(|result| match result {
+            Ok(uuid) => BatchImage::Uploaded {
+                id: response_id(&state, *uuid),
+            },
+            Err(e) => BatchImage::Failed(e.body()),
+        })
+        .collect();
+    Ok(Json(BatchResponse { images }).into_response())
+}
+
+async fn save_batch_image(
+    state: &ApiState,
+    tenant: &Tenant,
+    file_data: Vec<u8>,
+    declared_type: Option<String>,
+    uploadsettings: &UploadSettings,
+) -> Result<Uuid, ApiError> {
+    if file_data.is_empty() {
+        return Err(ApiError::bad_request("empty_upload", "empty upload"));
+    }
+    check_caption(uploadsettings)?;
+    if state
+        .config
+        .max_image_size
+        .is_some_and(|max_image_size| file_data.len() > max_image_size)
+    {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            IMAGE_SIZE_EXCEEDED,
+        ));
+    }
+
+    let upload = state.config.keep_uploads.then(|| file_data.clone());
+    let (image_data, format) =
+        prepare_upload(state, file_data, declared_type, uploadsettings).await?;
+    let kept_upload = upload.map(|upload| kept_upload(upload, format));
+    let store_as = state.database.store_format(uploadsettings.store_as, format);
+    let uuid = state
+        .database
+        .save_image(
+            tenant,
+            image_data,
+            store_as,
+            new_source(uploadsettings),
+            kept_upload,
+            uploadsettings.sync,
+        )
+        .await
+        .map_err(save_image_error)?;
+    save_caption(state, tenant, uuid, uploadsettings).await?;
+    Ok(uuid)
+}
+
+#[derive(Serialize)]
+struct ValidateResponse {
+    format: &'static str,
+    stored_format: &'static str,
+    width: u32,
+    height: u32,
+}
+
+//Runs an upload through every check and a full decode, then throws it away without touching the database.
+#[debug_handler]
+async fn validate_upload(
+    State(state): State<Arc<ApiState>>,
+    uploadsettings: Result<Query<UploadSettings>, QueryRejection>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Json<ValidateResponse>, ApiError> {
+    let _permit = state.acquire_upload_permit()?;
+    let Query(uploadsettings) = uploadsettings?;
+    if let Some(store_as) = uploadsettings.store_as {
+        ensure_enabled(store_as)?;
+    }
+
+    let (file_data, declared_type, _) = read_multipart(multipart?).await?;
+    let (image_data, format) =
+        prepare_upload(&state, file_data, declared_type, &uploadsettings).await?;
+    let image_data = state.database.check_upload(image_data).map_err(|e| match e {
+        SaveImageError::InvalidImage(e) => {
+            info!("Rejecting upload with unreadable header: {e:?}");
+            invalid_image()
+        }
+        e => save_image_error(e),
+    })?;
+
+    let animation_policy = state.database.animation_policy();
+    let image = transcode::run_blocking(move || transcode::decode_still(image_data, animation_policy))
+        .await
+        .map_err(|_| ApiError::internal())?
+        .map_err(|e| {
+            info!("Rejecting upload that could not be decoded: {e:?}");
+            invalid_image()
+        })?;
+    let stored_format = state
+        .database
+        .store_format(uploadsettings.store_as, format)
+        .unwrap_or_else(|| transcode::content_store_format(&image));
+
+    Ok(Json(ValidateResponse {
+        //Uploads can be in formats that are never served, so these aren't limited to ImageFormat's names.
+        format: format.extensions_str().first().copied().unwrap_or("unknown"),
+        stored_format: stored_format.to_str(),
+        width: image.width(),
+        height: image.height(),
+    }))
+}
+
+fn invalid_image() -> ApiError {
+    ApiError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "invalid_image",
+        "Image could not be decoded",
+    )
+}
+
+const CAPTION_FIELD: &str = "caption";
+
+//The multipart fields concatenated, with the content type declared on the first one,
+//apart from a `caption` field which is returned as text.
+async fn read_multipart(
+    mut multipart: Multipart,
+) -> Result<(Vec<u8>, Option<String>, Option<String>), ApiError> {
+    let mut file_data: Vec<u8> = Vec::new();
+    let mut declared_type: Option<String> = None;
+    let mut caption: Option<String> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Err(multipart_error(e, IMAGE_SIZE_EXCEEDED)),
+        };
+        if field.name() == Some(CAPTION_FIELD) {
+            match field.text().await {
+                Ok(text) => caption = Some(text),
+                Err(e) => return Err(multipart_error(e, IMAGE_SIZE_EXCEEDED)),
             }
+            continue;
+        }
+        if declared_type.is_none() {
+            declared_type = field.content_type().map(str::to_string);
+        }
+        match field.bytes().await {
+            Ok(data) => file_data.extend_from_slice(&data),
+            Err(e) => return Err(multipart_error(e, IMAGE_SIZE_EXCEEDED)),
+        }
+    }
+
+    if file_data.is_empty() {
+        info!("Empty upload...");
+        return Err(ApiError::bad_request("empty_upload", "empty upload"));
+    }
+    Ok((file_data, declared_type, caption))
+}
+
+//Rasterizes SVGs, applies the animation policy and settles on a decodable format.
+async fn prepare_upload(
+    state: &ApiState,
+    file_data: Vec<u8>,
+    declared_type: Option<String>,
+    uploadsettings: &UploadSettings,
+) -> Result<(ImageReader<Cursor<Vec<u8>>>, image::ImageFormat), ApiError> {
+    let file_data = if svg::is_svg(&file_data) {
+        rasterize_svg(file_data, uploadsettings).await?
+    } else {
+        file_data
+    };
+
+    let file_data = if state.database.animation_policy() == AnimationPolicy::Error {
+        let (file_data, animated) = transcode::run_blocking(move || {
+            let animated = transcode::is_animated(&file_data);
+            (file_data, animated)
+        })
+        .await
+        .map_err(|_| ApiError::internal())?;
+        if animated {
+            info!("Rejecting animated upload...");
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "animated_image",
+                "Animated images are not accepted",
+            ));
+        }
+        file_data
+    } else {
+        file_data
+    };
+
+    //The upload is already in memory, the Cursor is all the buffering the reader needs.
+    let mut reader = ImageReader::new(Cursor::new(file_data));
+    reader.limits(transcode::decode_limits());
+    let mut image_data = match reader.with_guessed_format() {
+        Ok(image_data) => image_data,
+        Err(e) => {
+            warn!("Could not guess the format of an upload: {e:?}");
+            return Err(ApiError::internal());
+        }
+    };
+    //Magic bytes win, the declared content type is only a hint for inputs they can't identify.
+    if image_data.format().is_none() {
+        if let Some(format) = declared_type
+            .as_deref()
+            .and_then(image::ImageFormat::from_mime_type)
+        {
+            image_data.set_format(format);
         }
+    }
+    match image_data.format() {
+        Some(format) => Ok((image_data, format)),
         None => {
             info!("Invalid image format...");
-            Html("Invalid image format...".into())
+            Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_format",
+                "Invalid image format",
+            ))
+        }
+    }
+}
+
+fn save_image_error(e: SaveImageError) -> ApiError {
+    match e {
+        SaveImageError::InvalidDimensions(width, height) => {
+            info!("Rejecting upload with dimensions {width}x{height}...");
+            ApiError::bad_request(
+                "invalid_dimensions",
+                format!("Image has invalid dimensions {width}x{height}"),
+            )
+        }
+        SaveImageError::TooManyPixels(width, height) => {
+            info!("Rejecting upload with {width}x{height} pixels...");
+            ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too_many_pixels",
+                format!("Image of {width}x{height} exceeds the maximum pixel count"),
+            )
+        }
+        SaveImageError::LimitsExceeded(e) => {
+            info!("Rejecting upload that exceeds the decode limits: {e}");
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "limits_exceeded", e.to_string())
+        }
+        SaveImageError::InvalidImage(e) => {
+            info!("Rejecting upload with unreadable header: {e:?}");
+            ApiError::bad_request("invalid_image", "Image could not be read")
+        }
+        SaveImageError::NotStored => ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "not_stored",
+            "Image could not be decoded and stored",
+        ),
+        SaveImageError::AliasTaken => ApiError::new(
+            StatusCode::CONFLICT,
+            "alias_taken",
+            "The alias is already taken by another image",
+        ),
+        e => {
+            warn!("Error trying to save new image to database: {e:?}");
+            ApiError::internal()
         }
     }
 }
@@ -98,18 +969,81 @@ struct ImageSettings {
     pub width: Option<u32>,
     #[serde(default, deserialize_with = "empty_string_as_none_u32")]
     pub height: Option<u32>,
+    #[serde(default, deserialize_with = "empty_string_as_none_aspect")]
+    pub aspect: Option<f32>,
+    #[serde(default, deserialize_with = "empty_string_as_none_f32")]
+    pub scale: Option<f32>,
+    #[serde(default, deserialize_with = "empty_string_as_none_u8")]
+    pub quality: Option<u8>,
+    #[serde(default, deserialize_with = "empty_string_as_none_u8")]
+    pub speed: Option<u8>,
+    #[serde(default)]
+    pub colorspace: Option<ColorSpace>,
+    #[serde(default)]
+    pub subsampling: Option<ChromaSubsampling>,
+    #[serde(default, deserialize_with = "empty_string_as_none_u8")]
+    pub bitdepth: Option<u8>,
+    #[serde(default, deserialize_with = "empty_string_as_none_f32")]
+    pub sharpen: Option<f32>,
+    #[serde(default, deserialize_with = "empty_string_as_none_u8")]
+    pub sharpen_threshold: Option<u8>,
+    #[serde(default, deserialize_with = "empty_string_as_none_uuid")]
+    pub watermark: Option<Uuid>,
+    #[serde(default)]
+    pub watermark_position: WatermarkPosition,
+    #[serde(default, deserialize_with = "empty_string_as_none_f32")]
+    pub watermark_opacity: Option<f32>,
+    #[serde(default, deserialize_with = "empty_string_as_none_pipeline")]
+    pub pipeline: Option<Pipeline>,
+    #[serde(default, deserialize_with = "empty_string_as_none_u16")]
+    pub dpi: Option<u16>,
+    #[serde(default)]
+    pub encode: Option<ResponseEncoding>,
+    #[serde(default)]
+    pub original: bool,
+    #[serde(default)]
+    pub no_store: bool,
 }
 
-impl From<ImageSettings> for TranscodeTarget {
-    fn from(val: ImageSettings) -> Self {
-        TranscodeTarget {
-            image_format: val.format,
-            image_width: val.width,
-            image_height: val.height,
-        }
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ResponseEncoding {
+    Base64,
+}
+
+impl TryFrom<ImageSettings> for TranscodeTarget {
+    type Error = TranscodeTargetError;
+
+    fn try_from(val: ImageSettings) -> Result<Self, Self::Error> {
+        TranscodeTarget::builder()
+            .with_format(val.format)
+            .with_size(val.width, val.height)
+            .with_crop(val.aspect)
+            .with_scale(val.scale)
+            .with_quality(val.quality)
+            .with_speed(val.speed)
+            .with_colorspace(val.colorspace)
+            .with_subsampling(val.subsampling)
+            .with_bit_depth(val.bitdepth)
+            .with_sharpen(val.sharpen.map(|amount| Sharpen {
+                amount,
+                threshold: val.sharpen_threshold.unwrap_or(0),
+            }))
+            .with_watermark(val.watermark.map(|image_id| Watermark {
+                image_id,
+                position: val.watermark_position,
+                opacity: val.watermark_opacity.unwrap_or(1.0),
+            }))
+            .with_pipeline(val.pipeline)
+            .build()
     }
 }
 
+fn transcode_target(query: ImageSettings) -> Result<TranscodeTarget, ApiError> {
+    TranscodeTarget::try_from(query)
+        .map_err(|e| ApiError::bad_request("invalid_transform", e.to_string()))
+}
+
 fn empty_string_as_none_image_format<'de, D>(de: D) -> Result<Option<ImageFormat>, D::Error>
 where
     D: Deserializer<'de>,
@@ -140,53 +1074,1268 @@ where
     }
 }
 
-#[debug_handler]
-async fn serve_image(
-    State(state): State<Arc<ApiState>>,
-    Path(image_identifier): Path<String>,
-    Query(query): Query<ImageSettings>,
-) -> impl IntoResponse {
-    let uuid = match Uuid::from_str(&image_identifier) {
-        Ok(uuid) => uuid,
-        Err(_) => return build_response(StatusCode::BAD_REQUEST, "Invalid image id".into()),
-    };
+fn empty_string_as_none_u16<'de, D>(de: D) -> Result<Option<u16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => u16::from_str(s).map_err(de::Error::custom).map(Some),
+    }
+}
 
-    let image = match transcode::get_image(uuid, query.into(), &state.database, None).await {
-        Ok(image) => image,
-        Err(TranscoderError::ImageError(e)) => {
-            warn!("Image could not be computed: {e:?}");
-            return build_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "ERROR TRANSCODING IMAGES".into(),
-            );
-        }
-        Err(TranscoderError::NotComputed) => {
-            return build_response(StatusCode::NOT_FOUND, "Image not yet computed".into());
-        }
-        Err(TranscoderError::NotFound) => {
-            return build_response(StatusCode::NOT_FOUND, "Image not found".into());
-        }
-        Err(TranscoderError::InternalServerError(e)) => {
-            warn!("Something went wrong trying to get an image: {e:?}");
-            return build_response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "INTERNAL SERVER ERROR".into(),
-            );
-        }
-    };
-    let mime_format = query.format.unwrap_or(ImageFormat::PNG);
+fn empty_string_as_none_u8<'de, D>(de: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => u8::from_str(s).map_err(de::Error::custom).map(Some),
+    }
+}
 
-    let bytes = Bytes::from(image);
-    let body = axum::body::Body::from(bytes);
+fn empty_string_as_none_f32<'de, D>(de: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => f32::from_str(s).map_err(de::Error::custom).map(Some),
+    }
+}
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", mime_format.to_mime_type())
-        .body(body)
-        .unwrap()
+//Either `width:height`, e.g. `16:9`, or the ratio itself, e.g. `1.777`.
+fn empty_string_as_none_aspect<'de, D>(de: D) -> Result<Option<f32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => match s.split_once(':') {
+            Some((width, height)) => {
+                let width = f32::from_str(width).map_err(de::Error::custom)?;
+                let height = f32::from_str(height).map_err(de::Error::custom)?;
+                Ok(Some(width / height))
+            }
+            None => f32::from_str(s).map_err(de::Error::custom).map(Some),
+        },
+    }
 }
 
-fn build_response(status: StatusCode, message: String) -> Response<axum::body::Body> {
-    let body = axum::body::Body::from(Bytes::from(message));
-    Response::builder().status(status).body(body).unwrap()
+fn empty_string_as_none_pipeline<'de, D>(de: D) -> Result<Option<Pipeline>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => Pipeline::from_str(s).map_err(de::Error::custom).map(Some),
+    }
+}
+
+fn empty_string_as_none_uuid<'de, D>(de: D) -> Result<Option<Uuid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref() {
+        None | Some("") => Ok(None),
+        Some(s) => Uuid::from_str(s).map_err(de::Error::custom).map(Some),
+    }
+}
+
+#[debug_handler]
+async fn serve_image(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    query: Result<Query<ImageSettings>, QueryRejection>,
+    formats_query: Result<Query<FormatsQuery>, QueryRejection>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let Query(mut query) = query?;
+    let Query(formats_query) = formats_query?;
+    let (image_identifier, path_format) = split_extension(&image_identifier)?;
+    if let Some(path_format) = path_format {
+        if query.format.is_some_and(|format| format != path_format) {
+            return Err(ApiError::bad_request(
+                "conflicting_format",
+                "format query parameter conflicts with the path extension",
+            ));
+        }
+        query.format = Some(path_format);
+    }
+    if let Some(pipeline_format) = query.pipeline.and_then(|pipeline| pipeline.format()) {
+        if query.format.is_some_and(|format| format != pipeline_format) {
+            return Err(ApiError::bad_request(
+                "conflicting_format",
+                "format of the pipeline conflicts with format or the path extension",
+            ));
+        }
+        query.format = Some(pipeline_format);
+    }
+    let uuid = parse_image_id(image_identifier)?;
+    if let Some(format) = query.format.filter(|_| !query.original) {
+        ensure_enabled(format)?;
+    }
+    if query.dpi == Some(0) {
+        return Err(ApiError::bad_request("invalid_transform", "dpi must be at least 1"));
+    }
+
+    //The response depends on Accept whenever the format is left to negotiation, even if nothing matched.
+    let negotiated = state.config.negotiate_format
+        && query.format.is_none()
+        && !query.original
+        && formats_query.formats.as_ref().is_none_or(|formats| formats.is_empty());
+    if negotiated {
+        query.format = negotiate_format(&headers);
+    }
+
+    //Everything is validated before a conditional request is answered, a 304 vouches for the request.
+    let formats = formats_query
+        .formats
+        .filter(|formats| !formats.is_empty() && !query.original);
+    let format_targets = match formats {
+        Some(formats) => {
+            if query.format.is_some() {
+                return Err(ApiError::bad_request(
+                    "conflicting_format",
+                    "formats can not be combined with format or a path extension",
+                ));
+            }
+            if query.encode.is_some() {
+                return Err(ApiError::bad_request(
+                    "invalid_transform",
+                    "formats can not be combined with encode",
+                ));
+            }
+            let formats: Vec<String> = formats
+                .split(',')
+                .map(|format| format.trim().to_string())
+                .collect();
+            let targets = parse_formats(&formats)?
+                .into_iter()
+                .map(|format| {
+                    transcode_target(ImageSettings {
+                        format: Some(format),
+                        ..query
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(targets)
+        }
+        None => None,
+    };
+    //The kept upload or the stored bytes are returned verbatim, every transform and format parameter is ignored.
+    let target = if query.original || format_targets.is_some() {
+        None
+    } else {
+        Some(transcode_target(query)?)
+    };
+
+    let cache_info = match state.database.cache_info(&tenant, &uuid).await {
+        Ok(cache_info) => cache_info,
+        Err(e) => {
+            warn!("Something went wrong trying to get the modification date of an image: {e:?}");
+            return Err(ApiError::internal());
+        }
+    };
+    let last_modified = cache_info.as_ref().map(|cache_info| cache_info.last_modified);
+    let cache_control = cache_info
+        .as_ref()
+        .and_then(|cache_info| cache_control(cache_info, state.config.cache_max_age));
+    //If-None-Match takes precedence, it is checked once the body and with it the ETag is known.
+    //Images still being computed have nothing to compare against yet.
+    let if_modified_since = if_modified_since(&headers)
+        .filter(|_| !headers.contains_key(header::IF_NONE_MATCH))
+        .filter(|_| cache_info.as_ref().is_some_and(|cache_info| cache_info.computed));
+    if let (Some(last_modified), Some(if_modified_since)) = (last_modified, if_modified_since) {
+        if last_modified.timestamp() <= if_modified_since.timestamp() {
+            let mut response = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::LAST_MODIFIED, http_date(&last_modified));
+            response = response.header(header::VARY, vary(negotiated));
+            if let Some(cache_control) = &cache_control {
+                response = response.header(header::CACHE_CONTROL, cache_control);
+            }
+            return Ok(response.body(axum::body::Body::empty()).unwrap());
+        }
+    }
+
+    if let Some(targets) = format_targets {
+        return serve_formats(&state, &tenant, uuid, targets, !query.no_store, cache_info.as_ref(), &uri)
+            .await;
+    }
+
+    let image = match target {
+        None => transcode::get_original(&tenant, uuid, &state.database).await,
+        Some(target) => {
+            transcode::get_image(&tenant, uuid, target, &state.database, None, !query.no_store).await
+        }
+    };
+
+    let image = match image {
+        Ok(image) => image,
+        Err(e) => return not_served(&state, e, &uri),
+    };
+    //Density is only metadata, it is written into the served bytes rather than being a transform of its own.
+    let image = match query.dpi.filter(|_| !query.original) {
+        Some(dpi) => match transcode::with_density(image, dpi).await {
+            Ok(image) => image,
+            Err(e) => return not_served(&state, e, &uri),
+        },
+        None => image,
+    };
+    let image_content_type = image.content_type().to_string();
+    let kept_upload = image.content_type.is_some();
+    let (content_type, data) = match query.encode {
+        Some(ResponseEncoding::Base64) => {
+            let data = match image.data.into_bytes().await {
+                Ok(data) => data,
+                Err(e) => return not_served(&state, e, &uri),
+            };
+            (
+                "text/plain".to_string(),
+                ImageData::Buffered(
+                    format!("data:{image_content_type};base64,{}", BASE64_STANDARD.encode(&data))
+                        .into_bytes(),
+                ),
+            )
+        }
+        None => (image_content_type, image.data),
+    };
+    let (etag, size) = match &data {
+        ImageData::Buffered(data) => (etag(data, image.cache_hit), data.len() as u64),
+        ImageData::Streamed { checksum, len, .. } => (stored_etag(checksum), *len),
+    };
+    //Enough to tell from the logs exactly what was asked for and what it cost.
+    match &target {
+        Some(target) => debug!(
+            "Serving image {uuid} as {target:?}, cache {}, {size} bytes",
+            if image.cache_hit { "hit" } else { "miss" }
+        ),
+        None => debug!("Serving original of image {uuid}, {size} bytes"),
+    }
+
+    if etag_matches(&headers, &etag) {
+        let mut response = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &etag);
+        response = response.header(header::VARY, vary(negotiated));
+        if let Some(last_modified) = &last_modified {
+            response = response.header(header::LAST_MODIFIED, http_date(last_modified));
+        }
+        if let Some(cache_control) = &cache_control {
+            response = response.header(header::CACHE_CONTROL, cache_control);
+        }
+        return Ok(response.body(axum::body::Body::empty()).unwrap());
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header(header::ETAG, &etag)
+        .header(CACHE_HEADER, if image.cache_hit { "HIT" } else { "MISS" });
+    response = response.header(header::VARY, vary(negotiated));
+    if let Some(last_modified) = &last_modified {
+        response = response.header(header::LAST_MODIFIED, http_date(last_modified));
+    }
+    if let Some(cache_control) = &cache_control {
+        response = response.header(header::CACHE_CONTROL, cache_control);
+    }
+    if let Some((width, height)) = image.dimensions {
+        response = response
+            .header(IMAGE_WIDTH_HEADER, width)
+            .header(IMAGE_HEIGHT_HEADER, height);
+    }
+    //Kept uploads are whatever was sent, SVGs with scripts included, so they are never rendered in our origin.
+    if kept_upload {
+        response = response
+            .header(header::CONTENT_DISPOSITION, "attachment")
+            .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+            .header(header::CONTENT_SECURITY_POLICY, KEPT_UPLOAD_CSP);
+    }
+    if let Some(available_formats) = &image.available_formats {
+        let available_formats: Vec<&str> = available_formats
+            .iter()
+            .map(|format| format.to_str())
+            .collect();
+        response = response.header(AVAILABLE_FORMATS_HEADER, available_formats.join(", "));
+    }
+    if let Some(caption) = cache_info.as_ref().and_then(|cache_info| cache_info.caption.as_deref()) {
+        response = response.header(IMAGE_CAPTION_HEADER, encode_caption(caption));
+    }
+
+    //Stored files are streamed so memory doesn't grow with their size.
+    let body = match data {
+        ImageData::Buffered(data) => axum::body::Body::from(Bytes::from(data)),
+        ImageData::Streamed { file, len, .. } => {
+            response = response.header(header::CONTENT_LENGTH, len);
+            axum::body::Body::from_stream(ReaderStream::new(file))
+        }
+    };
+
+    Ok(response.body(body).unwrap())
+}
+
+#[derive(Deserialize)]
+struct AliasParams {
+    alias: String,
+}
+
+//Serves the image an alias points at exactly like its id would, path extension included.
+#[debug_handler]
+async fn serve_alias(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(AliasParams { alias }): Path<AliasParams>,
+    query: Result<Query<ImageSettings>, QueryRejection>,
+    formats_query: Result<Query<FormatsQuery>, QueryRejection>,
+    original_uri: OriginalUri,
+    headers: HeaderMap,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let (alias, path_format) = split_extension(&alias)?;
+    let uuid = match state.database.resolve_alias(&tenant, alias).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return Err(ApiError::not_found("Image not found")),
+        Err(e) => {
+            warn!("Something went wrong trying to resolve an alias: {e:?}");
+            return Err(ApiError::internal());
+        }
+    };
+    let image_id = match path_format {
+        Some(format) => format!("{uuid}.{}", format.extension()),
+        None => uuid.to_string(),
+    };
+    serve_image(
+        State(state),
+        tenant,
+        Path(ImageParams { image_id }),
+        query,
+        formats_query,
+        original_uri,
+        headers,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct FormatsQuery {
+    formats: Option<String>,
+}
+
+//Every requested format as one part of a multipart/mixed body, e.g. for building <picture> elements.
+async fn serve_formats(
+    state: &ApiState,
+    tenant: &Tenant,
+    uuid: Uuid,
+    targets: Vec<TranscodeTarget>,
+    store: bool,
+    cache_info: Option<&CacheInfo>,
+    uri: &Uri,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let images = futures::future::join_all(
+        targets
+            .iter()
+            .map(|&target| transcode::get_image(tenant, uuid, target, &state.database, None, store)),
+    )
+    .await;
+
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut body: Vec<u8> = Vec::new();
+    for (target, image) in targets.iter().zip(images) {
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => return not_served(state, e, uri),
+        };
+        let data = match image.data.into_bytes().await {
+            Ok(data) => data,
+            Err(e) => return not_served(state, e, uri),
+        };
+        debug!(
+            "Serving image {uuid} as {target:?}, cache {}, {} bytes",
+            if image.cache_hit { "hit" } else { "miss" },
+            data.len()
+        );
+        let mut part_headers = format!(
+            "--{boundary}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{CACHE_HEADER}: {}\r\n",
+            image.image_format.to_mime_type(),
+            data.len(),
+            if image.cache_hit { "HIT" } else { "MISS" },
+        );
+        if let Some((width, height)) = image.dimensions {
+            part_headers.push_str(&format!(
+                "{IMAGE_WIDTH_HEADER}: {width}\r\n{IMAGE_HEIGHT_HEADER}: {height}\r\n"
+            ));
+        }
+        part_headers.push_str("\r\n");
+        body.extend_from_slice(part_headers.as_bytes());
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format!("multipart/mixed; boundary={boundary}"));
+    response = response.header(header::VARY, vary(false));
+    if let Some(cache_info) = cache_info {
+        response = response.header(header::LAST_MODIFIED, http_date(&cache_info.last_modified));
+        if let Some(cache_control) = cache_control(cache_info, state.config.cache_max_age) {
+            response = response.header(header::CACHE_CONTROL, cache_control);
+        }
+    }
+    Ok(response.body(axum::body::Body::from(body)).unwrap())
+}
+
+//Maps a failed lookup to its error, unknown images get the fallback image when one is configured.
+//`uri` is where the client should poll again for images that are still being computed.
+fn not_served(
+    state: &ApiState,
+    e: TranscoderError,
+    uri: &Uri,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    match e {
+        //Sources stored before the limits were lowered.
+        TranscoderError::ImageError(ImageError::Limits(e)) => {
+            info!("Image exceeds the decode limits: {e}");
+            Err(ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "limits_exceeded", e.to_string()))
+        }
+        TranscoderError::ImageError(e) => {
+            warn!("Image could not be computed: {e:?}");
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "transcode_failed",
+                "Error transcoding image",
+            ))
+        }
+        TranscoderError::NotComputed => Err(not_computed(state, uri)),
+        TranscoderError::NotFound => match &state.fallback_image {
+            Some(fallback_image) => Ok(fallback_image.response()),
+            None => Err(ApiError::not_found("Image not found")),
+        },
+        TranscoderError::WatermarkNotFound => Err(ApiError::bad_request(
+            "invalid_watermark",
+            "Watermark image not found",
+        )),
+        TranscoderError::InvalidTarget(e) => {
+            Err(ApiError::bad_request("invalid_transform", e.to_string()))
+        }
+        TranscoderError::TooManyTransforms => Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_transforms",
+            "Too many distinct transforms requested for this image, try again later",
+        )),
+        TranscoderError::InternalServerError(e) => {
+            warn!("Something went wrong trying to get an image: {e:?}");
+            Err(ApiError::internal())
+        }
+    }
+}
+
+fn not_computed(state: &ApiState, uri: &Uri) -> ApiError {
+    let mut error = ApiError::new(
+        state.not_computed_status,
+        "not_computed",
+        "Image not yet computed",
+    );
+    //A 404 tells clients the image isn't there, polling hints would contradict it.
+    if state.not_computed_status.is_client_error() {
+        return error;
+    }
+    let estimate = transcode::estimated_transcode_time();
+    let retry_after = estimate.map_or(1, |estimate| estimate.as_secs_f64().ceil().max(1.0) as u64);
+    error = error.with_header(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    if let Ok(location) = HeaderValue::from_str(&uri.to_string()) {
+        error = error.with_header(header::LOCATION, location);
+    }
+    if let Some(estimate) = estimate {
+        error = error.with_header(
+            HeaderName::from_static(ESTIMATED_WAIT_HEADER),
+            HeaderValue::from(estimate.as_millis() as u64),
+        );
+    }
+    error
+}
+
+fn if_modified_since(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    let value = headers.get(header::IF_MODIFIED_SINCE)?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+//Most preferred first, breaks ties between types the client weighs equally.
+const NEGOTIABLE_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::AVIF,
+    ImageFormat::WEBP,
+    ImageFormat::JPG,
+    ImageFormat::PNG,
+];
+
+//Only explicitly listed types count, wildcards like image/* keep the stored format.
+fn negotiate_format(headers: &HeaderMap) -> Option<ImageFormat> {
+    let accept = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    let mut best: Option<(f32, usize)> = None;
+    for media_range in accept {
+        let mut parts = media_range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let quality = parts
+            .filter_map(|parameter| parameter.strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let Some(rank) = NEGOTIABLE_FORMATS.iter().position(|format| {
+            format.to_mime_type().eq_ignore_ascii_case(media_type) && transcode::format_enabled(*format)
+        }) else {
+            continue;
+        };
+        let better = match best {
+            None => quality > 0.0,
+            Some((best_quality, best_rank)) => {
+                quality > best_quality || (quality == best_quality && rank < best_rank)
+            }
+        };
+        if better {
+            best = Some((quality, rank));
+        }
+    }
+    best.map(|(_, rank)| NEGOTIABLE_FORMATS[rank])
+}
+
+//Strong tags promise byte-identical bodies, which only stored files served verbatim can keep.
+//Anything encoded for the request is weak, encoders aren't guaranteed to be deterministic.
+fn etag(body: &[u8], stored: bool) -> String {
+    let digest = Sha256::digest(body);
+    let hash: String = digest[..16].iter().map(|byte| format!("{byte:02x}")).collect();
+    if stored {
+        format!("\"{hash}\"")
+    } else {
+        format!("W/\"{hash}\"")
+    }
+}
+
+//The same tag `etag` gives the stored bytes, taken from the checksum recorded when they were written.
+fn stored_etag(checksum: &str) -> String {
+    format!("\"{}\"", &checksum[..32])
+}
+
+//If-None-Match uses the weak comparison, so only the opaque parts have to match.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+//The customary max-age for content that never changes.
+const IMMUTABLE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+//The same url serves every tenant picked by header, so shared caches have to keep them apart.
+fn vary(negotiated: bool) -> String {
+    if negotiated {
+        format!("{}, {TENANT_HEADER}", header::ACCEPT)
+    } else {
+        TENANT_HEADER.to_string()
+    }
+}
+
+//Fresh until the image expires, but never longer than CACHE_MAX_AGE_SECS so browsers still revalidate.
+//Images without a TTL only get a header when a max age is configured, or when they are immutable.
+fn cache_control(cache_info: &CacheInfo, cache_max_age: Option<u64>) -> Option<String> {
+    let remaining = cache_info
+        .expires_at
+        .map(|expires_at| (expires_at - Utc::now()).num_seconds().max(0) as u64);
+    let max_age = match (remaining, cache_max_age) {
+        (Some(remaining), Some(cache_max_age)) => Some(remaining.min(cache_max_age)),
+        (remaining, cache_max_age) => remaining.or(cache_max_age),
+    };
+    if cache_info.immutable {
+        let max_age = max_age.unwrap_or(IMMUTABLE_MAX_AGE);
+        Some(format!("public, max-age={max_age}, immutable"))
+    } else {
+        max_age.map(|max_age| format!("public, max-age={max_age}"))
+    }
+}
+
+#[debug_handler]
+async fn delete_image(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+) -> Result<StatusCode, ApiError> {
+    state.ensure_writable()?;
+    let uuid = parse_image_id(&image_identifier)?;
+
+    match state.database.delete_image(&tenant, &uuid).await {
+        Ok(_source_format) => {
+            #[cfg(feature = "webhooks")]
+            if let Some(webhooks) = state.database.webhooks() {
+                webhooks.send(WebhookEvent::Deleted, &tenant, uuid, _source_format);
+            }
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(DeleteImageError::NotFound) => Err(ApiError::not_found("Image not found")),
+        Err(DeleteImageError::Immutable) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "immutable",
+            "Image is immutable",
+        )),
+        Err(DeleteImageError::InternalServerError(e)) => {
+            warn!("Something went wrong trying to delete an image: {e:?}");
+            Err(ApiError::internal())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FormatsResponse {
+    formats: Vec<&'static str>,
+}
+
+#[derive(Deserialize)]
+struct FormatsRequest {
+    formats: Vec<String>,
+}
+
+async fn available_formats(
+    database: &Database,
+    tenant: &Tenant,
+    uuid: &Uuid,
+) -> Result<Vec<ImageFormat>, ApiError> {
+    match database.available_formats(tenant, uuid).await {
+        Ok(Some(formats)) => Ok(formats),
+        Ok(None) => Err(ApiError::not_found("Image not found")),
+        Err(e) => {
+            warn!("Something went wrong trying to get the formats of an image: {e:?}");
+            Err(ApiError::internal())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExistsResponse {
+    exists: bool,
+}
+
+//Header values are limited to visible ASCII, anything else in the UTF-8 caption is percent-encoded.
+fn encode_caption(caption: &str) -> String {
+    let mut encoded = String::with_capacity(caption.len());
+    for byte in caption.bytes() {
+        match byte {
+            b'%' => encoded.push_str("%25"),
+            b' '..=b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[debug_handler]
+async fn get_meta(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+) -> Result<Json<ImageMeta>, ApiError> {
+    let uuid = parse_image_id(&image_identifier)?;
+    match state.database.image_meta(&tenant, &uuid).await {
+        Ok(Some(meta)) => Ok(Json(meta)),
+        Ok(None) => Err(ApiError::not_found("Image not found")),
+        Err(e) => {
+            warn!("Something went wrong getting the metadata of image: {uuid}: {e:?}");
+            Err(ApiError::internal())
+        }
+    }
+}
+
+//Answers 200 either way, for clients that only want a yes or no.
+#[debug_handler]
+async fn image_exists(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+) -> Result<Json<ExistsResponse>, ApiError> {
+    let uuid = parse_image_id(&image_identifier)?;
+    let exists = state
+        .database
+        .image_exists(&tenant, &uuid)
+        .await
+        .map_err(|e| {
+            warn!("Something went wrong checking whether image: {uuid} exists: {e:?}");
+            ApiError::internal()
+        })?;
+
+    Ok(Json(ExistsResponse { exists }))
+}
+
+#[debug_handler]
+async fn get_formats(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+) -> Result<Json<FormatsResponse>, ApiError> {
+    let uuid = parse_image_id(&image_identifier)?;
+    let formats = available_formats(&state.database, &tenant, &uuid).await?;
+
+    Ok(Json(FormatsResponse {
+        formats: formats.into_iter().map(ImageFormat::to_str).collect(),
+    }))
+}
+
+//Transcodes run in the background, clients poll GET .../formats to see when they are ready.
+#[debug_handler]
+async fn warm_formats(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    request: Result<Json<FormatsRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<FormatsResponse>), ApiError> {
+    state.ensure_writable()?;
+    let Json(request) = request?;
+    let uuid = parse_image_id(&image_identifier)?;
+    let requested = parse_formats(&request.formats)?;
+
+    let available = available_formats(&state.database, &tenant, &uuid).await?;
+    let missing = spawn_missing_formats(&state, &tenant, uuid, requested, &available);
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(FormatsResponse {
+            formats: missing.into_iter().map(ImageFormat::to_str).collect(),
+        }),
+    ))
+}
+
+#[derive(Deserialize, Default)]
+struct RegenerateRequest {
+    #[serde(default)]
+    formats: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RegenerateResponse {
+    deleted: Vec<&'static str>,
+    scheduled: Vec<&'static str>,
+}
+
+//Drops cached formats so they are re-encoded with the current settings, the body is optional.
+#[debug_handler]
+async fn regenerate(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<RegenerateResponse>), ApiError> {
+    state.ensure_writable()?;
+    let request = if body.is_empty() {
+        RegenerateRequest::default()
+    } else {
+        Json::<RegenerateRequest>::from_bytes(&body)?.0
+    };
+    let uuid = parse_image_id(&image_identifier)?;
+    let requested = parse_formats(&request.formats)?;
+
+    let deleted = match state.database.delete_variants(&tenant, &uuid).await {
+        Ok(Some(deleted)) => deleted,
+        Ok(None) => return Err(ApiError::not_found("Image not found")),
+        Err(e) => {
+            warn!("Something went wrong trying to regenerate an image: {e:?}");
+            return Err(ApiError::internal());
+        }
+    };
+
+    let available = available_formats(&state.database, &tenant, &uuid).await?;
+    let scheduled = spawn_missing_formats(&state, &tenant, uuid, requested, &available);
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(RegenerateResponse {
+            deleted: deleted.into_iter().map(ImageFormat::to_str).collect(),
+            scheduled: scheduled.into_iter().map(ImageFormat::to_str).collect(),
+        }),
+    ))
+}
+
+fn parse_formats(formats: &[String]) -> Result<Vec<ImageFormat>, ApiError> {
+    let mut requested: Vec<ImageFormat> = Vec::new();
+    for format in formats {
+        match ImageFormat::from_str(format) {
+            Some(format) if !requested.contains(&format) => requested.push(ensure_enabled(format)?),
+            Some(_) => {}
+            None => {
+                return Err(ApiError::bad_request(
+                    "unsupported_format",
+                    format!("unsupported image format: {format}"),
+                ))
+            }
+        }
+    }
+    Ok(requested)
+}
+
+//Transcodes run in the background, returns the formats that were scheduled.
+fn spawn_missing_formats(
+    state: &Arc<ApiState>,
+    tenant: &Tenant,
+    uuid: Uuid,
+    requested: Vec<ImageFormat>,
+    available: &[ImageFormat],
+) -> Vec<ImageFormat> {
+    let missing: Vec<ImageFormat> = requested
+        .into_iter()
+        .filter(|format| !available.contains(format))
+        .collect();
+
+    for format in missing.iter().copied() {
+        let state = state.clone();
+        let tenant = tenant.clone();
+        tokio::spawn(async move {
+            let target = TranscodeTarget {
+                image_format: Some(format),
+                ..Default::default()
+            };
+            if let Err(e) = transcode::get_image(&tenant, uuid, target, &state.database, None, true).await {
+                warn!("Could not pre-warm {} for image {uuid}: {e:?}", format.to_str());
+            }
+        });
+    }
+
+    missing
+}
+
+const MAX_ARCHIVE_IDS: usize = 100;
+const ARCHIVE_MISSING_ENTRY: &str = "missing.txt";
+
+#[derive(Deserialize)]
+struct ArchiveQuery {
+    ids: String,
+}
+
+struct ArchiveStream {
+    state: Arc<ApiState>,
+    tenant: Tenant,
+    ids: std::vec::IntoIter<Uuid>,
+    writer: ZipWriter,
+    missing: Vec<String>,
+    finished: bool,
+}
+
+impl ArchiveStream {
+    //One image is read at a time, so memory stays bounded by the largest image rather than the archive.
+    async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        if self.finished {
+            return None;
+        }
+        for uuid in self.ids.by_ref() {
+            match transcode::get_original(&self.tenant, uuid, &self.state.database).await {
+                Ok(image) => {
+                    let extension = match &image.content_type {
+                        Some(content_type) => kept_upload_extension(content_type),
+                        None => image.image_format.extension(),
+                    };
+                    let name = format!("{uuid}.{extension}");
+                    match image.data.into_bytes().await {
+                        Ok(data) => return Some(self.writer.entry(&name, &data)),
+                        Err(e) => {
+                            warn!("Could not add image: {uuid} to an archive because: {e:?}");
+                            self.missing.push(format!("{uuid}: could not be read"));
+                        }
+                    }
+                }
+                Err(TranscoderError::NotFound) => self.missing.push(format!("{uuid}: not found")),
+                Err(TranscoderError::NotComputed) => {
+                    self.missing.push(format!("{uuid}: not computed yet"))
+                }
+                Err(e) => {
+                    warn!("Could not add image: {uuid} to an archive because: {e:?}");
+                    self.missing.push(format!("{uuid}: could not be read"));
+                }
+            }
+        }
+
+        self.finished = true;
+        let mut bytes = Vec::new();
+        if !self.missing.is_empty() {
+            let manifest = self.missing.join("\n") + "\n";
+            match self.writer.entry(ARCHIVE_MISSING_ENTRY, manifest.as_bytes()) {
+                Ok(entry) => bytes = entry,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(std::mem::take(&mut self.writer).finish().map(|directory| {
+            bytes.extend(directory);
+            bytes
+        }))
+    }
+}
+
+//The extensions stored files get, e.g. `jpg` rather than a guess like `jfif`.
+fn kept_upload_extension(content_type: &str) -> &'static str {
+    if content_type == SVG_MIME_TYPE {
+        return "svg";
+    }
+    let Some(format) = image::ImageFormat::from_mime_type(content_type) else {
+        return "bin";
+    };
+    ImageFormat::from_image_format(format)
+        .map(ImageFormat::extension)
+        .or_else(|| format.extensions_str().first().copied())
+        .unwrap_or("bin")
+}
+
+//Stored originals of every id as one zip, ids that can't be served are listed in missing.txt instead.
+#[debug_handler]
+async fn archive(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    query: Result<Query<ArchiveQuery>, QueryRejection>,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let Query(query) = query?;
+    let mut ids = Vec::new();
+    for image_identifier in query.ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        let uuid = parse_image_id(image_identifier)?;
+        if !ids.contains(&uuid) {
+            ids.push(uuid);
+        }
+    }
+    if ids.is_empty() || ids.len() > MAX_ARCHIVE_IDS {
+        return Err(ApiError::bad_request(
+            "invalid_query",
+            format!("ids must list between 1 and {MAX_ARCHIVE_IDS} images"),
+        ));
+    }
+
+    let stream = ArchiveStream {
+        state,
+        tenant,
+        ids: ids.into_iter(),
+        writer: ZipWriter::default(),
+        missing: Vec::new(),
+        finished: false,
+    };
+    let body = futures::stream::unfold(stream, |mut stream| async move {
+        let chunk = stream.next_chunk().await?;
+        Some((chunk, stream))
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"images.zip\"",
+        )
+        .body(axum::body::Body::from_stream(body))
+        .unwrap())
+}
+
+const DEFAULT_LIST_LIMIT: u32 = 100;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+#[derive(Deserialize)]
+struct ListQuery {
+    after: Option<String>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    images: Vec<ListEntry>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    id: String,
+    format: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+//Cursors are opaque to clients, inside they are the creation time in microseconds and id of the last image.
+fn encode_cursor(created_at: DateTime<Utc>, image_identifier: Uuid) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(format!("{}:{image_identifier}", created_at.timestamp_micros()))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(DateTime<Utc>, Uuid)> {
+    let decoded = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (micros, image_identifier) = decoded.split_once(':')?;
+    Some((
+        DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+        Uuid::from_str(image_identifier).ok()?,
+    ))
+}
+
+//Pages through the tenant's images by cursor, `next_cursor` is null on the last page.
+#[debug_handler]
+async fn list_images(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    query: Result<Query<ListQuery>, QueryRejection>,
+) -> Result<Json<ListResponse>, ApiError> {
+    let Query(query) = query?;
+    let after = match query.after.as_deref().filter(|after| !after.is_empty()) {
+        Some(after) => Some(
+            decode_cursor(after)
+                .ok_or_else(|| ApiError::bad_request("invalid_cursor", "invalid cursor"))?,
+        ),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIST_LIMIT);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        return Err(ApiError::bad_request(
+            "invalid_query",
+            format!("limit must be between 1 and {MAX_LIST_LIMIT}"),
+        ));
+    }
+
+    //One more than asked for tells whether another page follows.
+    let mut images = state
+        .database
+        .list_images(&tenant, after, i64::from(limit) + 1)
+        .await
+        .map_err(|e| {
+            warn!("Something went wrong listing images: {e:?}");
+            ApiError::internal()
+        })?;
+    let more = images.len() > limit as usize;
+    images.truncate(limit as usize);
+    let next_cursor = images
+        .last()
+        .filter(|_| more)
+        .map(|image| encode_cursor(image.created_at, image.image_identifier));
+    let images = images
+        .into_iter()
+        .map(|image| ListEntry {
+            id: if state.config.short_ids {
+                short_id::encode(image.image_identifier)
+            } else {
+                image.image_identifier.to_string()
+            },
+            format: image.format,
+            created_at: image.created_at,
+            expires_at: image.expires_at,
+        })
+        .collect();
+    Ok(Json(ListResponse {
+        images,
+        next_cursor,
+    }))
+}
+
+const MAX_CONTACT_SHEET_IDS: usize = 100;
+const MAX_CONTACT_SHEET_THUMB: u32 = 256;
+const DEFAULT_CONTACT_SHEET_THUMB: u32 = 128;
+
+#[derive(Deserialize)]
+struct ContactSheetQuery {
+    ids: String,
+    cols: Option<u32>,
+    thumb: Option<u32>,
+}
+
+//Thumbnails of every id on one PNG grid, ids that can't be served leave their cell blank.
+#[debug_handler]
+async fn serve_contact_sheet(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    query: Result<Query<ContactSheetQuery>, QueryRejection>,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let Query(query) = query?;
+    let ids = query
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(parse_image_id)
+        .collect::<Result<Vec<Uuid>, ApiError>>()?;
+    if ids.is_empty() || ids.len() > MAX_CONTACT_SHEET_IDS {
+        return Err(ApiError::bad_request(
+            "invalid_query",
+            format!("ids must list between 1 and {MAX_CONTACT_SHEET_IDS} images"),
+        ));
+    }
+    //Roughly square unless asked otherwise.
+    let columns = query
+        .cols
+        .unwrap_or_else(|| (ids.len() as f64).sqrt().ceil() as u32);
+    if columns == 0 || columns as usize > MAX_CONTACT_SHEET_IDS {
+        return Err(ApiError::bad_request(
+            "invalid_query",
+            format!("cols must be between 1 and {MAX_CONTACT_SHEET_IDS}"),
+        ));
+    }
+    let cell = query.thumb.unwrap_or(DEFAULT_CONTACT_SHEET_THUMB);
+    if cell == 0 || cell > MAX_CONTACT_SHEET_THUMB {
+        return Err(ApiError::bad_request(
+            "invalid_query",
+            format!("thumb must be between 1 and {MAX_CONTACT_SHEET_THUMB}"),
+        ));
+    }
+
+    //Sources are decoded one at a time and only their thumbnails kept.
+    let mut thumbnails = Vec::with_capacity(ids.len());
+    for uuid in ids {
+        let source = transcode::decode_for_thumbnail(&tenant, uuid, cell, &state.database).await;
+        let thumbnail = match source {
+            Ok(image) => transcode::run_blocking(move || contact_sheet::thumbnail(&image, cell))
+                .await
+                .ok(),
+            Err(TranscoderError::NotFound | TranscoderError::NotComputed) => None,
+            Err(TranscoderError::TooManyTransforms) => {
+                info!("Leaving image: {uuid} off a contact sheet, it has too many transforms");
+                None
+            }
+            Err(TranscoderError::ImageError(ImageError::Limits(e))) => {
+                info!("Leaving image: {uuid} off a contact sheet, it exceeds the decode limits: {e}");
+                None
+            }
+            Err(e) => {
+                warn!("Could not add image: {uuid} to a contact sheet because: {e:?}");
+                None
+            }
+        };
+        thumbnails.push(thumbnail);
+    }
+
+    let encoded = transcode::run_blocking(move || {
+        let sheet = contact_sheet::contact_sheet(&thumbnails, columns, cell);
+        let mut data = Cursor::new(Vec::new());
+        sheet
+            .write_to(&mut data, image::ImageFormat::Png)
+            .map(|_| data.into_inner())
+    })
+    .await
+    .map_err(|_| ApiError::internal())?
+    .map_err(|e| {
+        warn!("Could not encode a contact sheet because: {e:?}");
+        ApiError::internal()
+    })?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", ImageFormat::PNG.format().to_mime_type())
+        .body(axum::body::Body::from(encoded))
+        .unwrap())
+}
+
+//Output dimensions and a rough size and time for a transform, without running it.
+#[debug_handler]
+async fn estimate_transform(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    query: Result<Query<ImageSettings>, QueryRejection>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Json<TranscodeEstimate>, ApiError> {
+    let Query(query) = query?;
+    let uuid = parse_image_id(&image_identifier)?;
+    let target = transcode_target(query)?;
+
+    match transcode::estimate(&tenant, uuid, target, &state.database).await {
+        Ok(estimate) => Ok(Json(estimate)),
+        Err(TranscoderError::NotFound) => Err(ApiError::not_found("Image not found")),
+        Err(TranscoderError::NotComputed) => Err(not_computed(&state, &uri)),
+        Err(TranscoderError::InvalidTarget(e)) => {
+            Err(ApiError::bad_request("invalid_transform", e.to_string()))
+        }
+        Err(e) => {
+            warn!("Something went wrong trying to estimate a transform: {e:?}");
+            Err(ApiError::internal())
+        }
+    }
+}
+
+const RAW_CHANNELS: u32 = 4;
+
+//Unencoded RGBA8 pixels, row by row, e.g. for GPU uploads. Format and encoder parameters are ignored.
+#[debug_handler]
+async fn serve_raw(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    query: Result<Query<ImageSettings>, QueryRejection>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Response<axum::body::Body>, ApiError> {
+    let Query(query) = query?;
+    let uuid = parse_image_id(&image_identifier)?;
+    let target = transcode_target(query)?;
+
+    let (pixels, (width, height)) =
+        match transcode::raw_pixels(&tenant, uuid, target, &state.database).await {
+            Ok(raw) => raw,
+            //The fallback image is encoded, it can't stand in for raw pixels.
+            Err(TranscoderError::NotFound) => return Err(ApiError::not_found("Image not found")),
+            Err(e) => return not_served(&state, e, &uri),
+        };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/octet-stream")
+        .header(IMAGE_WIDTH_HEADER, width)
+        .header(IMAGE_HEIGHT_HEADER, height)
+        .header(IMAGE_CHANNELS_HEADER, RAW_CHANNELS)
+        .body(axum::body::Body::from(pixels))
+        .unwrap())
+}
+
+//Diagnostic only, computed from the original on every request and never cached.
+#[debug_handler]
+async fn get_histogram(
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Json<Histogram>, ApiError> {
+    let uuid = parse_image_id(&image_identifier)?;
+
+    let image = match transcode::decode_source(&tenant, uuid, &state.database).await {
+        Ok(image) => image,
+        Err(TranscoderError::NotFound) => return Err(ApiError::not_found("Image not found")),
+        Err(TranscoderError::NotComputed) => return Err(not_computed(&state, &uri)),
+        Err(e) => {
+            warn!("Something went wrong trying to decode an image: {e:?}");
+            return Err(ApiError::internal());
+        }
+    };
+
+    transcode::run_blocking(move || histogram::histogram(&image))
+        .await
+        .map(Json)
+        .map_err(|_| ApiError::internal())
 }
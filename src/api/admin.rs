@@ -0,0 +1,227 @@
+use std::sync::{atomic::Ordering, Arc};
+
+use chrono::{DateTime, Duration, Utc};
+
+use axum::{
+    async_trait, debug_handler,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRequestParts, Path, Query, State,
+    },
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{parse_image_id, ApiError, ApiState, ImageParams};
+use crate::{database::Tenant, image_format::ImageFormat, transcode, Config};
+
+pub fn router() -> Router<Arc<ApiState>> {
+    Router::new()
+        .route("/read-only", get(get_read_only).put(set_read_only))
+        .route("/stuck", get(get_stuck))
+        .route("/requeue", post(requeue))
+        .route("/restore/:image_id", post(restore))
+        .route("/config", get(get_config))
+}
+
+//Admin routes are only mounted when ADMIN_TOKEN is set and expect it as a bearer token.
+struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<Arc<ApiState>> for AdminAuth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<ApiState>,
+    ) -> Result<Self, Self::Rejection> {
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match (provided, &state.config.admin_token) {
+            (Some(provided), Some(token)) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => {
+                Ok(AdminAuth)
+            }
+            _ => Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Missing or invalid admin token",
+            )),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadOnly {
+    read_only: bool,
+}
+
+#[debug_handler(state = Arc<ApiState>)]
+async fn get_read_only(_auth: AdminAuth, State(state): State<Arc<ApiState>>) -> Json<ReadOnly> {
+    Json(ReadOnly {
+        read_only: state.read_only.load(Ordering::Relaxed),
+    })
+}
+
+#[debug_handler(state = Arc<ApiState>)]
+async fn set_read_only(
+    _auth: AdminAuth,
+    State(state): State<Arc<ApiState>>,
+    request: Result<Json<ReadOnly>, JsonRejection>,
+) -> Result<Json<ReadOnly>, ApiError> {
+    let Json(request) = request?;
+    state.read_only.store(request.read_only, Ordering::Relaxed);
+    info!("Read-only mode set to {}", request.read_only);
+
+    Ok(Json(request))
+}
+
+//Uncomputed rows younger than this are most likely still being written.
+const DEFAULT_STUCK_AFTER_SECS: u32 = 600;
+
+#[derive(Deserialize)]
+struct StuckQuery {
+    older_than_secs: Option<u32>,
+}
+
+impl StuckQuery {
+    fn created_before(&self) -> DateTime<Utc> {
+        let older_than = self.older_than_secs.unwrap_or(DEFAULT_STUCK_AFTER_SECS);
+        Utc::now() - Duration::seconds(older_than.into())
+    }
+}
+
+#[derive(Serialize)]
+struct StuckImage {
+    tenant: String,
+    image_id: String,
+    format: &'static str,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Requeued {
+    computed: usize,
+    discarded: usize,
+}
+
+#[debug_handler(state = Arc<ApiState>)]
+async fn get_stuck(
+    _auth: AdminAuth,
+    State(state): State<Arc<ApiState>>,
+    query: Result<Query<StuckQuery>, QueryRejection>,
+) -> Result<Json<Vec<StuckImage>>, ApiError> {
+    let Query(query) = query?;
+    let stuck = state
+        .database
+        .stuck_images(query.created_before())
+        .await
+        .map_err(|e| {
+            warn!("Could not list stuck images: {e:?}");
+            ApiError::internal()
+        })?;
+
+    Ok(Json(
+        stuck
+            .into_iter()
+            .map(|image| StuckImage {
+                tenant: image.tenant.as_str().to_string(),
+                image_id: image.image_identifier.to_string(),
+                format: image.image_format.to_str(),
+                created_at: image.created_at,
+            })
+            .collect(),
+    ))
+}
+
+#[debug_handler(state = Arc<ApiState>)]
+async fn requeue(
+    _auth: AdminAuth,
+    State(state): State<Arc<ApiState>>,
+    query: Result<Query<StuckQuery>, QueryRejection>,
+) -> Result<Json<Requeued>, ApiError> {
+    let Query(query) = query?;
+    let summary = state
+        .database
+        .requeue_stuck(query.created_before())
+        .await
+        .map_err(|e| {
+            warn!("Could not requeue stuck images: {e:?}");
+            ApiError::internal()
+        })?;
+    info!(
+        computed = summary.computed,
+        discarded = summary.discarded,
+        "Requeued stuck images"
+    );
+
+    Ok(Json(Requeued {
+        computed: summary.computed,
+        discarded: summary.discarded,
+    }))
+}
+
+//Only soft deleted images within the SOFT_DELETE_SECS grace period can be restored.
+#[debug_handler(state = Arc<ApiState>)]
+async fn restore(
+    _auth: AdminAuth,
+    State(state): State<Arc<ApiState>>,
+    tenant: Tenant,
+    Path(ImageParams {
+        image_id: image_identifier,
+    }): Path<ImageParams>,
+) -> Result<StatusCode, ApiError> {
+    state.ensure_writable()?;
+    let uuid = parse_image_id(&image_identifier)?;
+    let restored = state
+        .database
+        .restore_image(&tenant, &uuid)
+        .await
+        .map_err(|e| {
+            warn!("Could not restore image: {uuid} because: {e:?}");
+            ApiError::internal()
+        })?;
+    if !restored {
+        return Err(ApiError::not_found("No deleted image to restore"));
+    }
+    info!("Restored image: {uuid}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    #[serde(flatten)]
+    config: &'a Config,
+    default_format: &'static str,
+    enabled_formats: Vec<&'static str>,
+}
+
+#[debug_handler(state = Arc<ApiState>)]
+async fn get_config(_auth: AdminAuth, State(state): State<Arc<ApiState>>) -> Response {
+    //Read-only mode can be switched at runtime, the rest is fixed at startup.
+    let config = Config {
+        read_only: state.read_only.load(Ordering::Relaxed),
+        ..state.config.clone()
+    };
+    Json(EffectiveConfig {
+        config: &config,
+        default_format: ImageFormat::default().to_str(),
+        enabled_formats: ImageFormat::ALL
+            .into_iter()
+            .filter(|format| transcode::format_enabled(*format))
+            .map(ImageFormat::to_str)
+            .collect(),
+    })
+    .into_response()
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use super::error::ApiError;
+use crate::client_ip::ClientIp;
+
+//Like the transform limit, clients whose window ran out are only dropped once this many are tracked.
+const SWEEP_THRESHOLD: usize = 10_000;
+//Beyond this the client whose window started first is forgotten to make room.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+//Requests per client address within a fixed window that starts with the client's first request.
+pub struct RateLimiter {
+    max_requests: u32,
+    window: Duration,
+    max_clients: usize,
+    //Doubles with what is left after a sweep, so clients that are all still active aren't swept on every check.
+    next_sweep: usize,
+    clients: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            max_requests,
+            window,
+            max_clients: MAX_TRACKED_CLIENTS,
+            next_sweep: SWEEP_THRESHOLD,
+            clients: HashMap::new(),
+        }
+    }
+
+    //How long until the client may send again when it is over its limit.
+    fn check(&mut self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        if self.clients.len() >= self.next_sweep {
+            self.sweep(now);
+        }
+        if self.clients.len() >= self.max_clients && !self.clients.contains_key(&client) {
+            self.forget_oldest();
+        }
+
+        let (started, requests) = self.clients.entry(client).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *requests = 0;
+        }
+        if *requests >= self.max_requests {
+            return Err(self.window - now.duration_since(*started));
+        }
+        *requests += 1;
+        Ok(())
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let window = self.window;
+        self.clients
+            .retain(|_, (started, _)| now.duration_since(*started) < window);
+        self.next_sweep = SWEEP_THRESHOLD.max(self.clients.len() * 2);
+    }
+
+    fn forget_oldest(&mut self) {
+        let oldest = self
+            .clients
+            .iter()
+            .min_by_key(|(_, (started, _))| *started)
+            .map(|(client, _)| *client);
+        if let Some(client) = oldest {
+            self.clients.remove(&client);
+        }
+    }
+}
+
+//Keyed on the address `extract_client_ip` settled on, so clients behind a trusted proxy are told apart.
+pub async fn limit(
+    State(limiter): State<Arc<Mutex<RateLimiter>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(ClientIp(client)) = request.extensions().get::<ClientIp>().copied() else {
+        return next.run(request).await;
+    };
+    let checked = limiter.lock().unwrap().check(client);
+    match checked {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests, try again later",
+        )
+        .with_header(
+            header::RETRY_AFTER,
+            HeaderValue::from(retry_after.as_secs_f64().ceil().max(1.0) as u64),
+        )
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn address(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn requests_stop_at_the_limit() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check(address(1)).is_ok());
+        assert!(limiter.check(address(1)).is_ok());
+        assert!(limiter.check(address(1)).is_err());
+        assert!(limiter.check(address(2)).is_ok());
+    }
+
+    #[test]
+    fn tracked_clients_are_capped() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(60));
+        limiter.max_clients = 2;
+        for last in 1..=3 {
+            assert!(limiter.check(address(last)).is_ok());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(limiter.clients.len(), 2);
+        assert!(!limiter.clients.contains_key(&address(1)));
+        assert!(limiter.check(address(3)).is_err());
+    }
+
+    #[test]
+    fn active_clients_are_not_swept_on_every_check() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(60));
+        limiter.clients = (0..SWEEP_THRESHOLD as u32)
+            .map(|n| (IpAddr::V4(Ipv4Addr::from(n)), (Instant::now(), 1)))
+            .collect();
+        assert!(limiter.check(address(1)).is_ok());
+        assert_eq!(limiter.next_sweep, SWEEP_THRESHOLD * 2);
+    }
+}
@@ -0,0 +1,249 @@
+use chrono::Duration;
+use derive_more::derive::Display;
+use reqwest::{multipart, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    image_format::ImageFormat,
+    short_id,
+    transcode::{ChromaSubsampling, ColorSpace, TranscodeTarget, WatermarkPosition},
+};
+
+const TENANT_HEADER: &str = "X-Tenant";
+
+#[derive(Debug, Display)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    #[display("{status} {code}: {message}")]
+    Api {
+        status: StatusCode,
+        code: String,
+        message: String,
+    },
+    InvalidResponse(String),
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetails,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetails {
+    code: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct UploadBody {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct FormatsBody {
+    formats: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct FormatsRequest<'a> {
+    formats: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct GetQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aspect: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    colorspace: Option<ColorSpace>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subsampling: Option<ChromaSubsampling>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bitdepth: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sharpen: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sharpen_threshold: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark_position: Option<WatermarkPosition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watermark_opacity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline: Option<String>,
+}
+
+//Typed access to the HTTP API, base_url is the server root without the /api prefix.
+#[derive(Clone)]
+pub struct ImageClient {
+    base_url: String,
+    http: reqwest::Client,
+    tenant: Option<String>,
+}
+
+impl ImageClient {
+    pub fn new(base_url: impl Into<String>) -> ImageClient {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> ImageClient {
+        ImageClient {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http,
+            tenant: None,
+        }
+    }
+
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> ImageClient {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    pub async fn upload(
+        &self,
+        bytes: Vec<u8>,
+        format: ImageFormat,
+        ttl: Option<Duration>,
+    ) -> Result<Uuid, ClientError> {
+        let part = multipart::Part::bytes(bytes)
+            .file_name(format!("upload.{}", format.to_str()))
+            .mime_str(format.to_mime_type())?;
+        let form = multipart::Form::new().part("file", part);
+
+        let mut request = self.request(reqwest::Method::POST, "upload").multipart(form);
+        if let Some(ttl) = ttl {
+            request = request.query(&[("ttl_secs", ttl.num_seconds())]);
+        }
+        let body: UploadBody = Self::check(request.send().await?).await?.json().await?;
+
+        Uuid::parse_str(&body.id)
+            .ok()
+            .or_else(|| short_id::decode(&body.id))
+            .ok_or_else(|| ClientError::InvalidResponse(format!("invalid image id: {}", body.id)))
+    }
+
+    pub async fn get(&self, id: Uuid, target: TranscodeTarget) -> Result<Vec<u8>, ClientError> {
+        let query = GetQuery {
+            format: target.image_format.map(ImageFormat::to_str),
+            width: target.image_width,
+            height: target.image_height,
+            aspect: target.aspect,
+            scale: target.scale,
+            quality: target.quality,
+            speed: target.speed,
+            colorspace: target.colorspace,
+            subsampling: target.subsampling,
+            bitdepth: target.bit_depth,
+            sharpen: target.sharpen.map(|sharpen| sharpen.amount),
+            sharpen_threshold: target.sharpen.map(|sharpen| sharpen.threshold),
+            watermark: target.watermark.map(|watermark| watermark.image_id.to_string()),
+            watermark_position: target.watermark.map(|watermark| watermark.position),
+            watermark_opacity: target.watermark.map(|watermark| watermark.opacity),
+            pipeline: target.pipeline.map(|pipeline| pipeline.to_string()),
+        };
+
+        let request = self
+            .request(reqwest::Method::GET, &id.to_string())
+            .query(&query);
+        let response = Self::check(request.send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    //The stored file byte for byte, in the format it was stored in.
+    pub async fn get_original(&self, id: Uuid) -> Result<Vec<u8>, ClientError> {
+        let request = self
+            .request(reqwest::Method::GET, &id.to_string())
+            .query(&[("original", true)]);
+        let response = Self::check(request.send().await?).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub async fn delete(&self, id: Uuid) -> Result<(), ClientError> {
+        let request = self.request(reqwest::Method::DELETE, &id.to_string());
+        Self::check(request.send().await?).await?;
+        Ok(())
+    }
+
+    pub async fn formats(&self, id: Uuid) -> Result<Vec<ImageFormat>, ClientError> {
+        let request = self.request(reqwest::Method::GET, &format!("{id}/formats"));
+        let body: FormatsBody = Self::check(request.send().await?).await?.json().await?;
+
+        body.formats
+            .iter()
+            .map(|format| {
+                ImageFormat::from_str(format)
+                    .ok_or_else(|| ClientError::InvalidResponse(format!("unknown format: {format}")))
+            })
+            .collect()
+    }
+
+    pub async fn warm_formats(&self, id: Uuid, formats: &[ImageFormat]) -> Result<(), ClientError> {
+        let formats: Vec<&str> = formats.iter().map(|format| format.to_str()).collect();
+        let request = self
+            .request(reqwest::Method::POST, &format!("{id}/formats"))
+            .json(&FormatsRequest { formats });
+        Self::check(request.send().await?).await?;
+        Ok(())
+    }
+
+    pub async fn regenerate(&self, id: Uuid, formats: &[ImageFormat]) -> Result<(), ClientError> {
+        let formats: Vec<&str> = formats.iter().map(|format| format.to_str()).collect();
+        let request = self
+            .request(reqwest::Method::POST, &format!("{id}/regenerate"))
+            .json(&FormatsRequest { formats });
+        Self::check(request.send().await?).await?;
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}/api/{path}", self.base_url));
+        match &self.tenant {
+            Some(tenant) => request.header(TENANT_HEADER, tenant),
+            None => request,
+        }
+    }
+
+    async fn check(response: Response) -> Result<Response, ClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let body = response.text().await?;
+        match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(ErrorBody { error }) => Err(ClientError::Api {
+                status,
+                code: error.code,
+                message: error.message,
+            }),
+            Err(_) => Err(ClientError::Api {
+                status,
+                code: String::new(),
+                message: body,
+            }),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+
+const REAL_IP_HEADER: &str = "X-Real-IP";
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+//The address a request came from, as seen by the server or reported by a trusted proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+//Proxy headers are only honored with `trust_proxy`, otherwise any client could claim any address.
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, trust_proxy: bool) -> Option<IpAddr> {
+    if !trust_proxy {
+        return peer;
+    }
+
+    let real_ip = headers
+        .get(REAL_IP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    //The proxy appends the peer it saw, the entries before it come from the client and can be forged.
+    let forwarded_for = || {
+        headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|value| value.trim().parse().ok())
+    };
+    real_ip.or_else(forwarded_for).or(peer)
+}
+
+//Runs outside the trace layer so the request span can carry the address.
+pub async fn extract_client_ip(trust_proxy: bool, mut request: Request, next: Next) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    if let Some(ip) = client_ip(peer, request.headers(), trust_proxy) {
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
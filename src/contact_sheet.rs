@@ -0,0 +1,22 @@
+use image::{imageops, DynamicImage, RgbaImage};
+
+//Fits the image into a square cell, keeping its aspect ratio.
+pub fn thumbnail(image: &DynamicImage, cell: u32) -> RgbaImage {
+    image.thumbnail(cell, cell).into_rgba8()
+}
+
+//Cells are filled row by row, thumbnails are centered in theirs and missing ones stay transparent.
+pub fn contact_sheet(thumbnails: &[Option<RgbaImage>], columns: u32, cell: u32) -> RgbaImage {
+    let rows = (thumbnails.len() as u32).div_ceil(columns);
+    let mut sheet = RgbaImage::new(columns * cell, rows * cell);
+    for (index, thumbnail) in thumbnails.iter().enumerate() {
+        let Some(thumbnail) = thumbnail else {
+            continue;
+        };
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let x = column * cell + (cell - thumbnail.width()) / 2;
+        let y = row * cell + (cell - thumbnail.height()) / 2;
+        imageops::overlay(&mut sheet, thumbnail, x.into(), y.into());
+    }
+    sheet
+}
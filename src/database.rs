@@ -1,32 +1,135 @@
 use std::{
-    error::Error,
+    future::Future,
     fmt::Write, // Add this line to bring the Write trait into scope
-    io::{BufRead, Read, Seek},
+    hash::{Hash, Hasher},
+    io::{BufRead, Cursor, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
 };
 
 use chrono::{DateTime, Duration, Utc};
 use derive_more::derive::Display;
+use either::Either;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tracing::{debug, warn};
 
-use crate::image_format::ImageFormat;
-use image::ImageReader;
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use tokio::sync::mpsc::{Receiver, Sender};
+use crate::{
+    image_format::ImageFormat,
+    transcode::{self, AnimationPolicy},
+};
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use sqlx::{
+    postgres::{PgConnectOptions, PgConnection, PgListener, PgPoolOptions},
+    Connection, PgPool, Postgres,
+};
+use tokio::sync::{
+    broadcast,
+    mpsc::{Receiver, Sender},
+    oneshot, Semaphore,
+};
 use uuid::Uuid;
 
+#[cfg(feature = "webhooks")]
+use crate::webhook::{WebhookEvent, Webhooks};
 use crate::Config;
 
 #[derive(Debug)]
 pub enum GetImageError {
     NotComputed,
+    //The format was claimed by a transcode that hasn't finished writing it, with the source like below.
+    BeingWritten(ImagePath, Vec<ImageFormat>),
+    NotFound,
+    FoundButNotInFormat(ImagePath, Vec<ImageFormat>),
+    InternalServerError(sqlx::Error),
+}
+
+#[derive(Debug)]
+pub enum DeleteImageError {
     NotFound,
-    FoundButNotInFormat(ImagePath),
+    Immutable,
     InternalServerError(sqlx::Error),
 }
 
+//Connection failures classified into the common misconfigurations of DATABASE_URL.
+#[derive(Debug, Display)]
+pub enum ConnectError {
+    #[display("authentication failed for user '{user}', check the credentials in 'DATABASE_URL'")]
+    AuthFailed { user: String },
+    #[display("database '{database}' does not exist, create it or fix the name in 'DATABASE_URL'")]
+    DatabaseMissing { database: String },
+    #[display("connection to {host}:{port} was refused, is PostgreSQL running at the address in 'DATABASE_URL'?")]
+    ConnectionRefused { host: String, port: u16 },
+    #[display("could not connect to the database: {_0}")]
+    Other(sqlx::Error),
+}
+
+impl std::error::Error for ConnectError {}
+
+//Everything that keeps the database from starting up.
+#[derive(Debug, Display)]
+pub enum DatabaseInitError {
+    #[display("could not create the image folder: {_0}")]
+    ImageFolder(std::io::Error),
+    Connect(ConnectError),
+    #[cfg(feature = "webhooks")]
+    #[display("could not set up webhooks: {_0}")]
+    Webhooks(reqwest::Error),
+}
+
+impl std::error::Error for DatabaseInitError {}
+
+impl From<ConnectError> for DatabaseInitError {
+    fn from(error: ConnectError) -> Self {
+        DatabaseInitError::Connect(error)
+    }
+}
+
+impl ConnectError {
+    const INVALID_PASSWORD: &'static str = "28P01";
+    const INVALID_AUTHORIZATION: &'static str = "28000";
+    const INVALID_CATALOG_NAME: &'static str = "3D000";
+
+    pub fn classify(error: sqlx::Error, options: &PgConnectOptions) -> ConnectError {
+        let code = match &error {
+            sqlx::Error::Database(database_error) => database_error.code().map(|code| code.into_owned()),
+            sqlx::Error::Io(io_error) if io_error.kind() == std::io::ErrorKind::ConnectionRefused => {
+                return ConnectError::ConnectionRefused {
+                    host: options.get_host().to_string(),
+                    port: options.get_port(),
+                }
+            }
+            _ => None,
+        };
+        match code.as_deref() {
+            Some(Self::INVALID_PASSWORD | Self::INVALID_AUTHORIZATION) => ConnectError::AuthFailed {
+                user: options.get_username().to_string(),
+            },
+            Some(Self::INVALID_CATALOG_NAME) => ConnectError::DatabaseMissing {
+                database: options.get_database().unwrap_or_default().to_string(),
+            },
+            _ => ConnectError::Other(error),
+        }
+    }
+}
+
 #[derive(Debug, Display)]
 pub enum SaveImageError {
+    #[display("invalid image dimensions {_0}x{_1}")]
+    InvalidDimensions(u32, u32),
+    #[display("image of {_0}x{_1} exceeds the pixel limit")]
+    TooManyPixels(u32, u32),
+    LimitsExceeded(image::ImageError),
+    InvalidImage(image::ImageError),
+    #[display("image could not be stored")]
+    NotStored,
+    #[display("alias is taken by another image")]
+    AliasTaken,
+    UploadNotKept(std::io::Error),
     InternalServerError(sqlx::Error),
 }
 
@@ -37,49 +140,419 @@ pub struct Database {
     image_location: PathBuf,
     transmitter: Sender<DatabaseMessage>,
     image_ttl_allowed : Option<Duration>,
+    validate_raw: bool,
+    max_stored_edge: Option<u32>,
+    max_pixels: Option<u64>,
+    animation_policy: AnimationPolicy,
+    max_image_count: Option<u64>,
+    eviction_pending: Arc<AtomicBool>,
+    auto_store_format: bool,
+    soft_delete: Option<Duration>,
+    computed_events: broadcast::Sender<String>,
+    #[cfg(feature = "webhooks")]
+    webhooks: Option<Webhooks>,
+}
+
+//Cheap handle for inspecting queued work after the Database itself has been handed to the router.
+#[derive(Clone)]
+pub struct PendingWork {
+    pool: PgPool,
+    transmitter: Sender<DatabaseMessage>,
+}
+
+impl PendingWork {
+    pub fn queued_messages(&self) -> usize {
+        self.transmitter.max_capacity() - self.transmitter.capacity()
+    }
+
+    pub async fn uncomputed_images(&self) -> Result<i64, sqlx::Error> {
+        let count = sqlx::query_scalar!("SELECT COUNT(*) FROM images WHERE computed = false")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count.unwrap_or(0))
+    }
+}
+
+//What HTTP caching needs to know about an image, taken from all of its unexpired rows.
+pub struct CacheInfo {
+    pub last_modified: DateTime<Utc>,
+    //The source's expiry, None for images without a TTL.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub immutable: bool,
+    //Not about caching, but served along with every image so it's read in the same query.
+    pub caption: Option<String>,
+    pub computed: bool,
+}
+
+//What is known about an image beyond its pixels, from its unexpired source row.
+#[derive(Serialize)]
+pub struct ImageMeta {
+    pub format: String,
+    pub caption: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub immutable: bool,
+}
+
+pub struct ListedImage {
+    pub image_identifier: Uuid,
+    pub format: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+//A row that is still uncomputed, usually because the task writing its file died.
+pub struct StuckImage {
+    pub tenant: Tenant,
+    pub image_identifier: Uuid,
+    pub image_format: ImageFormat,
+    pub created_at: DateTime<Utc>,
+    pub generation: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct RequeueSummary {
+    pub computed: usize,
+    pub discarded: usize,
 }
 
 enum DatabaseMessage {
-    Computed(Uuid, ImageFormat),
-    CleanExpired,
+    Computed(ComputedImage),
+    Discard(Tenant, Uuid, ImageFormat),
+    //Removes the rows that expired at or before the instant, along with their files.
+    CleanExpired(DateTime<Utc>),
+    //The flag is cleared once the sweep starts, uploads in the meantime don't queue another one.
+    EnforceImageCount(u64, Arc<AtomicBool>),
+}
+
+//A row whose file is done, the notifier, if any, is told once the row has been flipped to computed.
+//The generation is the row's when the file was started. A row deleted and inserted again in the
+//meantime has a newer one, so a stale write never replaces the file of the newer row.
+struct ComputedImage {
+    tenant: Tenant,
+    image_id: Uuid,
+    image_format: ImageFormat,
+    generation: i64,
+    //The written file waits next to its target until its row is marked computed.
+    temp_file: Option<TempFile>,
+    checksum: Option<String>,
+    notifier: Option<oneshot::Sender<()>>,
+}
+
+//Recorded for every written file, so reads can tell when the disk returned something else.
+pub fn checksum(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+//What the row of a new upload is created with.
+#[derive(Debug, Clone, Default)]
+pub struct NewSource {
+    pub api_ttl: Option<Duration>,
+    pub immutable: bool,
+    //Unique per tenant, AliasTaken when another image already has it.
+    pub alias: Option<String>,
+}
+
+//The upload byte for byte, written next to the source before its row records it.
+#[derive(Debug, Clone)]
+pub struct KeptUpload {
+    pub data: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+//Where `store_image` records a source, a reserved row keeps what it was reserved with.
+enum SourceRow {
+    New(NewSource),
+    Reserved(Uuid),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    const MAX_LEN: usize = 64;
+
+    pub fn new(name: &str) -> Option<Tenant> {
+        let valid = !name.is_empty()
+            && name.len() <= Self::MAX_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        valid.then(|| Tenant(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 impl Database {
-    pub async fn new(config: &Config) -> Result<Database, Box<dyn Error>> {
+    const COMPUTED_EVENTS_CAPACITY: usize = 1024;
+    const LISTENER_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+    const STALE_CLAIM_AGE: Duration = Duration::minutes(5);
+    const STALE_TEMP_FILE_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+    const ALIAS_INDEX: &'static str = "images_alias";
+    const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+    pub async fn new(config: &Config) -> Result<Database, DatabaseInitError> {
+        tokio::fs::create_dir_all(&config.image_path)
+            .await
+            .map_err(DatabaseInitError::ImageFolder)?;
+        Self::sweep_temp_files(&config.image_path, Self::STALE_TEMP_FILE_AGE).await;
+
         let (tx, rx) = tokio::sync::mpsc::channel(1024);
-        let pool = PgPoolOptions::new().connect(&config.database_url).await?;
+        let options = config
+            .database_url
+            .parse::<PgConnectOptions>()
+            .map_err(ConnectError::Other)?;
+        //The pool retries refused connections until it times out, a single connection fails fast with the real cause.
+        PgConnection::connect_with(&options)
+            .await
+            .map_err(|e| ConnectError::classify(e, &options))?
+            .close()
+            .await
+            .map_err(ConnectError::Other)?;
+        let pool = PgPoolOptions::new()
+            .connect_with(options.clone())
+            .await
+            .map_err(|e| ConnectError::classify(e, &options))?;
+        //A lowered MAX_IMAGE_COUNT applies right away rather than on the next upload.
+        let eviction_pending = Arc::new(AtomicBool::new(config.max_image_count.is_some()));
+        if let Some(max_image_count) = config.max_image_count {
+            tx.send(DatabaseMessage::EnforceImageCount(max_image_count, eviction_pending.clone()))
+                .await
+                .expect("Receiver is spawned below and the channel is empty");
+        }
+        #[cfg(feature = "webhooks")]
+        let webhooks = match (&config.webhook_url, &config.webhook_secret) {
+            (Some(url), Some(secret)) => Some(Webhooks::new(url, secret).map_err(DatabaseInitError::Webhooks)?),
+            _ => None,
+        };
+        let (computed_events, _) = broadcast::channel(Self::COMPUTED_EVENTS_CAPACITY);
+        tokio::spawn(Self::forward_computed_events(pool.clone(), computed_events.clone()));
         let receiver_pool = pool.clone();
         let image_path = config.image_path.clone();
         tokio::spawn(DatabaseReceiver::compute_message(
             rx,
             receiver_pool,
             image_path,
+            config
+                .max_background_db_tasks
+                .unwrap_or(DatabaseReceiver::DEFAULT_MAX_TASKS),
+            #[cfg(feature = "webhooks")]
+            webhooks.clone(),
         ));
 
+        //Nothing reads soft deleted images once they are hidden, so their files are purged on a timer.
+        if let Some(soft_delete) = config.soft_delete {
+            let transmitter = tx.clone();
+            let period = soft_delete
+                .to_std()
+                .unwrap_or_default()
+                .clamp(std::time::Duration::from_secs(1), Self::PURGE_INTERVAL);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    let message = DatabaseMessage::CleanExpired(Utc::now() - soft_delete);
+                    if transmitter.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
         Ok(Database {
             pool,
             image_location: config.image_path.clone(),
             transmitter: tx,
-            image_ttl_allowed: config.image_ttl
+            image_ttl_allowed: config.image_ttl,
+            validate_raw: config.validate_raw,
+            max_stored_edge: config.max_stored_edge,
+            max_pixels: config
+                .max_megapixels
+                .map(|megapixels| (megapixels * 1_000_000.0) as u64),
+            animation_policy: config.animation_policy,
+            max_image_count: config.max_image_count,
+            eviction_pending,
+            auto_store_format: config.auto_store_format,
+            soft_delete: config.soft_delete,
+            computed_events,
+            #[cfg(feature = "webhooks")]
+            webhooks,
+        })
+    }
+
+    //Relays the rows every instance marks computed or discards, so waiting requests wake up right away.
+    //The listener keeps one connection of the pool and reconnects on its own when it is lost.
+    async fn forward_computed_events(pool: PgPool, events: broadcast::Sender<String>) {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Could not listen for computed images, waiting requests poll instead: {e:?}");
+                return;
+            }
+        };
+        if let Err(e) = listener.listen(COMPUTED_CHANNEL).await {
+            warn!("Could not listen for computed images, waiting requests poll instead: {e:?}");
+            return;
+        }
+        loop {
+            match listener.recv().await {
+                Ok(notification) => {
+                    let _ = events.send(notification.payload().to_string());
+                }
+                Err(e) => {
+                    warn!("Lost the computed images listener: {e:?}");
+                    tokio::time::sleep(Self::LISTENER_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    //Subscribe before looking the row up, so an event sent in between isn't missed.
+    pub fn computed_events(&self) -> broadcast::Receiver<String> {
+        self.computed_events.subscribe()
+    }
+
+    #[cfg(feature = "webhooks")]
+    pub fn webhooks(&self) -> Option<&Webhooks> {
+        self.webhooks.as_ref()
+    }
+
+    //Runs in the background, uploads never wait for older images to be evicted.
+    //Uploads arriving while a sweep is queued are covered by it, so a burst of them sweeps once.
+    async fn enforce_image_count(&self) {
+        if let Some(max_image_count) = self.max_image_count {
+            if self.eviction_pending.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            let pending = self.eviction_pending.clone();
+            if let Err(e) = self
+                .transmitter
+                .send(DatabaseMessage::EnforceImageCount(max_image_count, pending))
+                .await
+            {
+                warn!("Could not send to transmitter: {e:?}");
+            }
+        }
+    }
+
+    pub fn animation_policy(&self) -> AnimationPolicy {
+        self.animation_policy
+    }
+
+    //The format to pass to `save_image`, None leaves it to the content when AUTO_STORE_FORMAT is enabled.
+    //Formats that can be decoded but not served are normalized to the default.
+    pub fn store_format(
+        &self,
+        requested: Option<ImageFormat>,
+        source_format: image::ImageFormat,
+    ) -> Option<ImageFormat> {
+        requested.or_else(|| {
+            (!self.auto_store_format)
+                .then(|| ImageFormat::from_image_format(source_format).unwrap_or_default())
         })
     }
 
     pub async fn save_image<R>(
         &self,
+        tenant: &Tenant,
         imagereader: ImageReader<R>,
+        image_format: Option<ImageFormat>,
+        source: NewSource,
+        kept_upload: Option<KeptUpload>,
+        sync: bool,
+    ) -> Result<Uuid, SaveImageError>
+    where
+        R: Read + Seek + Send + BufRead + 'static,
+    {
+        let row = SourceRow::New(source);
+        self.store_image(tenant, imagereader, image_format, row, kept_upload, sync)
+            .await
+    }
+
+    //Creates the uncomputed source row of an image whose data is still on its way, it is served as
+    //not computed until `save_reserved_image` stores it. The format is provisional.
+    pub async fn reserve_image(
+        &self,
+        tenant: &Tenant,
         image_format: ImageFormat,
-        api_ttl: Option<Duration>,
+        source: &NewSource,
+    ) -> Result<Uuid, SaveImageError> {
+        self.insert_source(tenant, image_format, source, None)
+            .await
+            .map(|(file_identifier, _)| file_identifier)
+    }
+
+    //Stores a reserved image, NotStored when its row was deleted or expired in the meantime.
+    pub async fn save_reserved_image<R>(
+        &self,
+        tenant: &Tenant,
+        image_identifier: Uuid,
+        imagereader: ImageReader<R>,
+        image_format: Option<ImageFormat>,
+        kept_upload: Option<KeptUpload>,
     ) -> Result<Uuid, SaveImageError>
     where
         R: Read + Seek + Send + BufRead + 'static,
     {
-        let image_eol = Self::determine_eol(api_ttl, self.image_ttl_allowed);
+        let row = SourceRow::Reserved(image_identifier);
+        self.store_image(tenant, imagereader, image_format, row, kept_upload, false)
+            .await
+    }
+
+    //Drops a reservation whose data never arrived.
+    pub async fn discard_reserved_image(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let discarded = sqlx::query!(
+            "DELETE FROM images WHERE tenant=$1 AND image_identifier=$2 AND source AND NOT computed RETURNING uploaded_type",
+            tenant.as_str(),
+            image_identifier
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        if discarded.is_some_and(|image| image.uploaded_type.is_some()) {
+            Self::remove_upload(&self.image_location, tenant, image_identifier).await;
+        }
+        Ok(())
+    }
 
+    async fn insert_source(
+        &self,
+        tenant: &Tenant,
+        image_format: ImageFormat,
+        source: &NewSource,
+        kept_upload: Option<&KeptUpload>,
+    ) -> Result<(Uuid, i64), SaveImageError> {
+        let image_eol = if source.immutable {
+            None
+        } else {
+            Self::determine_eol(source.api_ttl, self.image_ttl_allowed)
+        };
+        //Expired images keep their rows until the next cleanup, their aliases are free already.
+        //Deleted images keep theirs while they can still be restored, so restoring brings them back whole.
+        if let Some(alias) = &source.alias {
+            sqlx::query!(
+                "UPDATE images SET alias=NULL WHERE tenant=$1 AND alias=$2 AND expires_at <= $3
+                AND (deleted_at IS NULL OR deleted_at <= $4)",
+                tenant.as_str(),
+                alias,
+                Utc::now(),
+                self.purge_before()
+            )
+            .execute(&self.pool)
+            .await
+            .map_err(SaveImageError::InternalServerError)?;
+        }
         let file_identifier = loop {
             let uid = uuid::Uuid::new_v4();
 
             if !self
-                .file_exists(&uid)
+                .file_exists(tenant, &uid)
                 .await
                 .map_err(SaveImageError::InternalServerError)?
             {
@@ -87,82 +560,427 @@ impl Database {
             }
         };
 
-        let file_path = ImagePath::new(&self.image_location, &file_identifier, image_format);
-
-        sqlx::query!(
-            "INSERT INTO images (image_identifier, image_format, expires_at) VALUES ($1, $2, $3)",
+        if let Some(kept_upload) = kept_upload {
+            self.write_upload(tenant, &file_identifier, &kept_upload.data)
+                .await
+                .map_err(SaveImageError::UploadNotKept)?;
+        }
+        let inserted = sqlx::query_scalar!(
+            "INSERT INTO images (tenant, image_identifier, image_format, expires_at, immutable, source, alias, uploaded_type) VALUES ($1, $2, $3, $4, $5, true, $6, $7) RETURNING generation",
+            tenant.as_str(),
             file_identifier,
             image_format.to_str(),
-            image_eol.expect("TODO: OPTIONAL TTLS NOT YET IMPLEMENTED")
+            image_eol,
+            source.immutable,
+            source.alias.as_deref(),
+            kept_upload.map(|kept_upload| kept_upload.content_type)
         )
-        .execute(&self.pool)
-        .await
-        .map_err(SaveImageError::InternalServerError)?;
+        .fetch_one(&self.pool)
+        .await;
+        let generation = match inserted {
+            Ok(generation) => generation,
+            Err(e) => {
+                if kept_upload.is_some() {
+                    Self::remove_upload(&self.image_location, tenant, &file_identifier).await;
+                }
+                return Err(match &e {
+                    sqlx::Error::Database(error) if error.constraint() == Some(Self::ALIAS_INDEX) => {
+                        SaveImageError::AliasTaken
+                    }
+                    _ => SaveImageError::InternalServerError(e),
+                });
+            }
+        };
+        self.enforce_image_count().await;
+        Ok((file_identifier, generation))
+    }
+
+    async fn store_image<R>(
+        &self,
+        tenant: &Tenant,
+        imagereader: ImageReader<R>,
+        image_format: Option<ImageFormat>,
+        row: SourceRow,
+        kept_upload: Option<KeptUpload>,
+        sync: bool,
+    ) -> Result<Uuid, SaveImageError>
+    where
+        R: Read + Seek + Send + BufRead + 'static,
+    {
+        let (imagereader, icc_profile) = Self::check_dimensions(imagereader, self.max_pixels)?;
+        let animation_policy = self.animation_policy;
+        //Choosing by content needs the pixels before the row is created, the decode isn't repeated later.
+        let (image_format, source) = match image_format {
+            Some(image_format) => (image_format, Either::Left(imagereader)),
+            None => {
+                let image = transcode::run_blocking(move || {
+                    transcode::decode_still(imagereader, animation_policy)
+                })
+                .await
+                .map_err(|_| SaveImageError::NotStored)?
+                .map_err(Self::decode_error)?;
+                (transcode::content_store_format(&image), Either::Right(image))
+            }
+        };
 
+        let (file_identifier, generation) = match row {
+            //The reserved row only records the kept upload once its file is in place.
+            SourceRow::Reserved(file_identifier) => {
+                if let Some(kept_upload) = &kept_upload {
+                    self.write_upload(tenant, &file_identifier, &kept_upload.data)
+                        .await
+                        .map_err(SaveImageError::UploadNotKept)?;
+                }
+                let updated = sqlx::query_scalar!(
+                    "UPDATE images SET image_format=$3, uploaded_type=$4 WHERE tenant=$1 AND image_identifier=$2 AND source AND NOT computed RETURNING generation",
+                    tenant.as_str(),
+                    file_identifier,
+                    image_format.to_str(),
+                    kept_upload.as_ref().map(|kept_upload| kept_upload.content_type)
+                )
+                .fetch_optional(&self.pool)
+                .await;
+                let generation = match updated {
+                    Ok(Some(generation)) => generation,
+                    updated => {
+                        if kept_upload.is_some() {
+                            Self::remove_upload(&self.image_location, tenant, &file_identifier).await;
+                        }
+                        return Err(match updated {
+                            Err(e) => SaveImageError::InternalServerError(e),
+                            _ => SaveImageError::NotStored,
+                        });
+                    }
+                };
+                (file_identifier, generation)
+            }
+            SourceRow::New(source) => {
+                self.insert_source(tenant, image_format, &source, kept_upload.as_ref())
+                    .await?
+            }
+        };
+
+        let file_path = ImagePath::new(&self.image_location, tenant, &file_identifier, image_format);
+
+        let (computed_notifier, computed) = if sync {
+            let (tx, rx) = oneshot::channel();
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
         let transmitter = self.transmitter.clone();
-        tokio::task::spawn_blocking(move || {
-            let image = match imagereader.decode() {
-                Ok(image) => image,
+        let tenant = tenant.clone();
+        let max_stored_edge = self.max_stored_edge;
+        transcode::spawn(move || {
+            let stored = match source {
+                Either::Left(imagereader) => Self::read_upload(imagereader).and_then(|(data, format)| {
+                    let image = transcode::decode_still(
+                        Self::upload_reader(&data, format),
+                        animation_policy,
+                    )?;
+                    let verbatim = Some(image_format.format()) == format
+                        && icc_profile.is_some() == transcode::preserve_icc()
+                        && !transcode::is_animated(&data);
+                    let upload = verbatim.then_some(data.as_slice());
+                    Self::write_stored(image, image_format, icc_profile, max_stored_edge, upload, &file_path)
+                }),
+                Either::Right(image) => {
+                    Self::write_stored(image, image_format, icc_profile, max_stored_edge, None, &file_path)
+                }
+            };
+            let message = match stored {
+                Ok((temp_file, checksum)) => DatabaseMessage::Computed(ComputedImage {
+                    tenant,
+                    image_id: file_identifier,
+                    image_format,
+                    generation,
+                    temp_file: Some(temp_file),
+                    checksum: Some(checksum),
+                    notifier: computed_notifier,
+                }),
+                //The row goes and the notifier is dropped unsent, a waiter answers that nothing was stored.
                 Err(e) => {
-                    warn!("Could not decode image with ID: {file_identifier} because: {e:?}");
-                    panic!("Error decoding image");
+                    warn!("Could not store image with ID: {file_identifier} because: {e:?}");
+                    DatabaseMessage::Discard(tenant, file_identifier, image_format)
                 }
             };
-            if let Err(e) = image.save_with_format(file_path, image_format.format()) {
-                warn!("Could not save image with ID: {file_identifier} because: {e:?}");
-            }
-            tokio::runtime::Handle::current().block_on(async {
-                transmitter
-                    .send(DatabaseMessage::Computed(file_identifier, image_format))
-                    .await
-                    .expect("Could not send message on channel")
-            });
+            transmitter
+                .blocking_send(message)
+                .expect("Could not send message on channel");
         });
 
+        if let Some(computed) = computed {
+            computed.await.map_err(|_| SaveImageError::NotStored)?;
+        }
         Ok(file_identifier)
     }
 
-    pub async fn save_raw_image(
+    //The whole upload, which is decoded from memory and may be stored as it is.
+    fn read_upload<R>(
+        imagereader: ImageReader<R>,
+    ) -> Result<(Vec<u8>, Option<image::ImageFormat>), image::ImageError>
+    where
+        R: Read + Seek + BufRead,
+    {
+        let format = imagereader.format();
+        let mut data = Vec::new();
+        imagereader
+            .into_inner()
+            .read_to_end(&mut data)
+            .map_err(image::ImageError::IoError)?;
+        Ok((data, format))
+    }
+
+    fn upload_reader(data: &[u8], format: Option<image::ImageFormat>) -> ImageReader<Cursor<&[u8]>> {
+        let mut imagereader = ImageReader::new(Cursor::new(data));
+        if let Some(format) = format {
+            imagereader.set_format(format);
+        }
+        imagereader.limits(transcode::decode_limits());
+        imagereader
+    }
+
+    //Runs on the transcode pool, the file is written next to its target and only moved in place once marked computed.
+    //`upload` is written as it is unless the image has to be shrunk, it is only passed when it is already in the
+    //stored format, a still image and has a profile exactly when profiles are preserved.
+    fn write_stored(
+        image: DynamicImage,
+        image_format: ImageFormat,
+        icc_profile: Option<Vec<u8>>,
+        max_stored_edge: Option<u32>,
+        upload: Option<&[u8]>,
+        file_path: &ImagePath,
+    ) -> Result<(TempFile, String), image::ImageError> {
+        let (image, upload) = match max_stored_edge {
+            Some(max_edge) if image.width().max(image.height()) > max_edge => (
+                image.resize(max_edge, max_edge, image::imageops::FilterType::Lanczos3),
+                None,
+            ),
+            _ => (image, upload),
+        };
+        let encoded;
+        let data = match upload {
+            Some(upload) => upload,
+            None => {
+                let image = transcode::fit_to_format(image, image_format);
+                encoded = Self::encode_stored(&image, image_format, icc_profile)?;
+                encoded.as_slice()
+            }
+        };
+        file_path.create_parent_dir().map_err(image::ImageError::IoError)?;
+        let temp_file = TempFile::for_target(file_path);
+        std::fs::write(temp_file.path(), data).map_err(image::ImageError::IoError)?;
+        Ok((temp_file, checksum(data)))
+    }
+
+    //Encoded in memory so the checksum is of exactly the bytes that are written.
+    fn encode_stored(
+        image: &DynamicImage,
+        image_format: ImageFormat,
+        icc_profile: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, image::ImageError> {
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, image_format.format())?;
+        if !transcode::preserve_icc() {
+            return Ok(data.into_inner());
+        }
+        Ok(transcode::embed_icc_profile(
+            data.into_inner(),
+            image_format,
+            image.color(),
+            icc_profile.as_deref(),
+        ))
+    }
+
+    //The checks save_image runs before creating a row, for callers that only want to validate.
+    pub fn check_upload<R>(&self, imagereader: ImageReader<R>) -> Result<ImageReader<R>, SaveImageError>
+    where
+        R: Read + Seek + BufRead,
+    {
+        Self::check_dimensions(imagereader, self.max_pixels).map(|(imagereader, _)| imagereader)
+    }
+
+    //Only reads the header, so images that would decode to nothing or too much are refused before a row is created.
+    //The ICC profile is read along the way, whether it is kept depends on PRESERVE_ICC.
+    fn check_dimensions<R>(
+        imagereader: ImageReader<R>,
+        max_pixels: Option<u64>,
+    ) -> Result<(ImageReader<R>, Option<Vec<u8>>), SaveImageError>
+    where
+        R: Read + Seek + BufRead,
+    {
+        let format = imagereader.format();
+        let mut data = imagereader.into_inner();
+        let start = data
+            .stream_position()
+            .map_err(|e| SaveImageError::InvalidImage(image::ImageError::IoError(e)))?;
+
+        let limits = transcode::decode_limits();
+        let mut probe = ImageReader::new(&mut data);
+        if let Some(format) = format {
+            probe.set_format(format);
+        }
+        //Creating the decoder already checks the dimension limits.
+        probe.limits(limits.clone());
+        let mut decoder = probe.into_decoder().map_err(Self::decode_error)?;
+        let (width, height) = decoder.dimensions();
+        if width == 0 || height == 0 {
+            return Err(SaveImageError::InvalidDimensions(width, height));
+        }
+        if max_pixels.is_some_and(|max_pixels| width as u64 * height as u64 > max_pixels) {
+            return Err(SaveImageError::TooManyPixels(width, height));
+        }
+        limits
+            .clone()
+            .reserve(decoder.total_bytes())
+            .map_err(Self::decode_error)?;
+        let icc_profile = decoder.icc_profile().ok().flatten();
+        drop(decoder);
+
+        data.seek(SeekFrom::Start(start))
+            .map_err(|e| SaveImageError::InvalidImage(image::ImageError::IoError(e)))?;
+        let mut imagereader = ImageReader::new(data);
+        if let Some(format) = format {
+            imagereader.set_format(format);
+        }
+        imagereader.limits(limits);
+        Ok((imagereader, icc_profile))
+    }
+
+    fn decode_error(error: image::ImageError) -> SaveImageError {
+        match error {
+            image::ImageError::Limits(_) => SaveImageError::LimitsExceeded(error),
+            _ => SaveImageError::InvalidImage(error),
+        }
+    }
+
+    //Claims a missing format for the caller to transcode, None when another request got to it first.
+    //The advisory lock only lives as long as the insert, so losers return at once instead of queueing
+    //behind the winner's row lock. The uncomputed row then tells every instance to wait for the file.
+    pub async fn claim_variant(
         &self,
-        data: Vec<u8>,
-        image_identifier: Uuid,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
         image_format: ImageFormat,
         api_ttl: Option<Duration>,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<Option<VariantClaim>, sqlx::Error> {
         let image_eol = Self::determine_eol(api_ttl, self.image_ttl_allowed);
-
-        let file_path = ImagePath::new(&self.image_location, &image_identifier, image_format);
-        let _result = sqlx::query!(
-            "INSERT INTO images (image_identifier, image_format, expires_at) VALUES ($1, $2, $3)",
+        let mut transaction = self.pool.begin().await?;
+        let locked = sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_xact_lock(hashtextextended($1, 0)) AS "locked!""#,
+            variant_key(tenant, image_identifier, image_format)
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if !locked {
+            return Ok(None);
+        }
+        //Nothing is claimed once expiry removed every row of the image, the file would be orphaned.
+        //A claim left behind by an instance that died is taken over with a new generation once it is stale.
+        let generation = sqlx::query_scalar!(
+            "INSERT INTO images (tenant, image_identifier, image_format, expires_at)
+            SELECT $1::TEXT, $2::UUID, $3::TEXT, $4::TIMESTAMPTZ WHERE EXISTS (SELECT 1 FROM images WHERE tenant=$1 AND image_identifier=$2)
+            ON CONFLICT (tenant, image_identifier, image_format) DO UPDATE
+            SET generation=nextval('image_generation'), created_at=now(), expires_at=EXCLUDED.expires_at
+            WHERE NOT images.computed AND NOT images.source AND images.created_at < $5
+            RETURNING generation",
+            tenant.as_str(),
             image_identifier,
             image_format.to_str(),
-            image_eol.expect("TODO: OPTIONAL TTL NOT YET IMPLEMENTED")
+            image_eol,
+            Utc::now() - Self::STALE_CLAIM_AGE
         )
-        .execute(&self.pool)
+        .fetch_optional(&mut *transaction)
         .await?;
+        transaction.commit().await?;
+        Ok(generation.map(|generation| VariantClaim {
+            tenant: tenant.clone(),
+            image_identifier: *image_identifier,
+            image_format,
+            generation,
+        }))
+    }
 
+    //Writes the claimed format in the background, the row is discarded when the file can't be written.
+    pub fn save_raw_image(
+        &self,
+        claim: VariantClaim,
+        data: Vec<u8>,
+    ) {
+        let VariantClaim {
+            tenant,
+            image_identifier,
+            image_format,
+            generation,
+        } = claim;
+        let file_path = ImagePath::new(&self.image_location, &tenant, &image_identifier, image_format);
         let transmitter = self.transmitter.clone();
+        let validate_raw = self.validate_raw;
         tokio::spawn(async move {
-            if let Err(e) = tokio::fs::write(file_path, data.as_slice()).await {
-                warn!("Could not save raw image: {image_identifier} because : {e:?}")
+            if let Some(parent) = file_path.as_ref().parent() {
+                if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                    warn!("Could not create image directory for: {image_identifier} because : {e:?}")
+                }
             }
+            let temp_file = TempFile::for_target(&file_path);
+            let checksum = match tokio::fs::write(temp_file.path(), data.as_slice()).await {
+                Ok(()) => Some(checksum(&data)),
+                Err(e) => {
+                    warn!("Could not save raw image: {image_identifier} because : {e:?}");
+                    None
+                }
+            };
+            let message = match checksum {
+                None => DatabaseMessage::Discard(tenant, image_identifier, image_format),
+                Some(_) if validate_raw && !Self::is_valid_raw(data, image_format).await => {
+                    warn!(
+                        "Raw image: {image_identifier} is not a valid {} image, discarding it",
+                        image_format.to_str()
+                    );
+                    DatabaseMessage::Discard(tenant, image_identifier, image_format)
+                }
+                Some(checksum) => DatabaseMessage::Computed(ComputedImage {
+                    tenant,
+                    image_id: image_identifier,
+                    image_format,
+                    generation,
+                    temp_file: Some(temp_file),
+                    checksum: Some(checksum),
+                    notifier: None,
+                }),
+            };
             transmitter
-                .send(DatabaseMessage::Computed(image_identifier, image_format))
+                .send(message)
                 .await
                 .expect("Could not send image on channel");
         });
+    }
 
-        Ok(())
+    //Gives up a claim whose transcode failed, so the next request tries again.
+    pub async fn release_claim(&self, claim: VariantClaim) {
+        self.discard_variant(&claim.tenant, &claim.image_identifier, claim.image_format)
+            .await;
+    }
+
+    async fn is_valid_raw(data: Vec<u8>, image_format: ImageFormat) -> bool {
+        transcode::run_blocking(move || {
+            image::guess_format(&data).is_ok_and(|format| format == image_format.format())
+                && image::load_from_memory_with_format(&data, image_format.format()).is_ok()
+        })
+        .await
+        .unwrap_or(false)
     }
 
     pub async fn get_image_location(
         &self,
+        tenant: &Tenant,
         file_identifier: &Uuid,
         image_format: ImageFormat,
         max_time: &DateTime<Utc>,
     ) -> Result<ImagePath, GetImageError> {
         let result = sqlx::query!(
-            "SELECT computed, image_format, expires_at FROM images WHERE image_identifier=$1",
+            "SELECT computed, image_format, expires_at, source FROM images WHERE tenant=$1 AND image_identifier=$2 ORDER BY source DESC",
+            tenant.as_str(),
             file_identifier,
         )
         .fetch_all(&self.pool)
@@ -170,24 +988,37 @@ impl Database {
 
         match result {
             Ok(record) => {
-                if record.iter().any(|image| &image.expires_at < max_time) {
-                    println!("owo");
-                    if let Err(e) = self.transmitter.send(DatabaseMessage::CleanExpired).await {
+                let purge_before = self.purge_before();
+                if record
+                    .iter()
+                    .any(|image| Self::is_expired(image.expires_at, &purge_before))
+                {
+                    if let Err(e) = self
+                        .transmitter
+                        .send(DatabaseMessage::CleanExpired(purge_before))
+                        .await
+                    {
                         warn!("Could not send to transmitter: {e:?}");
                     }
                 }
+                //An expired original takes its variants with it, even those that would still be valid.
+                if record
+                    .iter()
+                    .any(|image| image.source && Self::is_expired(image.expires_at, max_time))
+                {
+                    return Err(GetImageError::NotFound);
+                }
 
-                let active: Vec<(bool, DateTime<Utc>, ImageFormat)> = record
+                let active: Vec<(bool, Option<DateTime<Utc>>, ImageFormat)> = record
                     .into_iter()
-                    .map(|image| {
-                        (
+                    .filter_map(|image| {
+                        Some((
                             image.computed,
                             image.expires_at,
-                            ImageFormat::from_str(&image.image_format)
-                                .expect("invalid image format in db"),
-                        )
+                            Self::stored_format(file_identifier, &image.image_format)?,
+                        ))
                     })
-                    .filter(|(_, expires_at, _)| expires_at > max_time)
+                    .filter(|(_, expires_at, _)| !Self::is_expired(*expires_at, max_time))
                     .collect();
                 if active.is_empty() {
                     Err(GetImageError::NotFound)
@@ -195,38 +1026,637 @@ impl Database {
                     active.iter().find(|(_, _, format)| &image_format == format)
                 {
                     if *computed {
-                        Ok(ImagePath::new(
+                        return Ok(ImagePath::new(
                             &self.image_location,
+                            tenant,
                             file_identifier,
                             image_format,
-                        ))
-                    } else {
-                        Err(GetImageError::NotComputed)
+                        ));
+                    }
+                    match self.computed_source(tenant, file_identifier, &active) {
+                        Some((source, available)) => Err(GetImageError::BeingWritten(source, available)),
+                        None => Err(GetImageError::NotComputed),
                     }
                 } else {
-                    Err(GetImageError::FoundButNotInFormat(ImagePath::new(
-                        &self.image_location,
-                        file_identifier,
-                        active.first().unwrap().2,
-                    )))
+                    match self.computed_source(tenant, file_identifier, &active) {
+                        Some((source, available)) => {
+                            Err(GetImageError::FoundButNotInFormat(source, available))
+                        }
+                        None => Err(GetImageError::NotComputed),
+                    }
                 }
             }
             Err(e) => Err(GetImageError::InternalServerError(e)),
         }
     }
 
-    async fn file_exists(&self, image_identifier: &Uuid) -> Result<bool, sqlx::Error> {
-        Ok(sqlx::query!(
-            "SELECT * FROM images WHERE image_identifier=$1",
-            image_identifier
+    //The formats that can be served and the path to transcode others from, None until the source is computed.
+    //Rows are ordered source first, so other formats are transcoded from the original.
+    fn computed_source(
+        &self,
+        tenant: &Tenant,
+        file_identifier: &Uuid,
+        active: &[(bool, Option<DateTime<Utc>>, ImageFormat)],
+    ) -> Option<(ImagePath, Vec<ImageFormat>)> {
+        let available: Vec<ImageFormat> = active
+            .iter()
+            .filter(|(computed, _, _)| *computed)
+            .map(|(_, _, format)| *format)
+            .collect();
+        let source_format = *available.first()?;
+        Some((
+            ImagePath::new(&self.image_location, tenant, file_identifier, source_format),
+            available,
+        ))
+    }
+
+    //The uploaded original, stored in whatever format the upload asked for.
+    pub async fn get_source_location(
+        &self,
+        tenant: &Tenant,
+        file_identifier: &Uuid,
+        max_time: &DateTime<Utc>,
+    ) -> Result<ImagePath, GetImageError> {
+        let record = sqlx::query!(
+            "SELECT computed, image_format, expires_at FROM images WHERE tenant=$1 AND image_identifier=$2 AND source",
+            tenant.as_str(),
+            file_identifier,
         )
         .fetch_optional(&self.pool)
-        .await?
-        .is_some())
+        .await
+        .map_err(GetImageError::InternalServerError)?;
+
+        match record {
+            Some(record) if !Self::is_expired(record.expires_at, max_time) => {
+                if !record.computed {
+                    return Err(GetImageError::NotComputed);
+                }
+                let Some(image_format) = Self::stored_format(file_identifier, &record.image_format)
+                else {
+                    return Err(GetImageError::NotFound);
+                };
+                Ok(ImagePath::new(
+                    &self.image_location,
+                    tenant,
+                    file_identifier,
+                    image_format,
+                ))
+            }
+            _ => Err(GetImageError::NotFound),
+        }
     }
 
-    fn determine_eol(requested : Option<Duration>, max : Option<Duration>) -> Option<DateTime<Utc>>{
-        if requested.is_none(){
+    //Stores the upload byte for byte next to its normalized source, see `KeptUpload`.
+    async fn write_upload(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let upload_path = ImagePath::upload(&self.image_location, tenant, image_identifier);
+        if let Some(parent) = upload_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let temp_file = TempFile::for_target(&upload_path);
+        tokio::fs::write(temp_file.path(), data).await?;
+        temp_file.persist_async(&upload_path).await
+    }
+
+    //None when the upload wasn't kept, the bytes are there as soon as the upload is answered.
+    pub async fn get_upload_location(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        max_time: &DateTime<Utc>,
+    ) -> Result<Option<(PathBuf, String)>, GetImageError> {
+        let record = sqlx::query!(
+            "SELECT expires_at, uploaded_type FROM images WHERE tenant=$1 AND image_identifier=$2 AND source",
+            tenant.as_str(),
+            image_identifier,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(GetImageError::InternalServerError)?;
+
+        match record {
+            Some(record) if !Self::is_expired(record.expires_at, max_time) => {
+                Ok(record.uploaded_type.map(|content_type| {
+                    (
+                        ImagePath::upload(&self.image_location, tenant, image_identifier),
+                        content_type,
+                    )
+                }))
+            }
+            _ => Err(GetImageError::NotFound),
+        }
+    }
+
+    //Deleted images expire right away and keep their files, the expiry they had is put back on restore.
+    async fn soft_delete_image(
+        mut transaction: sqlx::Transaction<'_, Postgres>,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<ImageFormat>, DeleteImageError> {
+        let deleted = sqlx::query!(
+            "UPDATE images SET deleted_at=$3, restore_expires_at=expires_at, expires_at=$3
+            WHERE tenant=$1 AND image_identifier=$2 AND deleted_at IS NULL RETURNING image_format, source",
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(DeleteImageError::InternalServerError)?;
+        if deleted.is_empty() {
+            return Err(DeleteImageError::NotFound);
+        }
+        transaction
+            .commit()
+            .await
+            .map_err(DeleteImageError::InternalServerError)?;
+        transcode::forget_decoded(tenant, *image_identifier);
+
+        Ok(deleted
+            .into_iter()
+            .find(|image| image.source)
+            .and_then(|image| ImageFormat::from_str(&image.image_format)))
+    }
+
+    //Undoes a soft delete while the image is still within the grace period, false when there is nothing to restore.
+    pub async fn restore_image(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let restored = sqlx::query!(
+            "UPDATE images SET expires_at=restore_expires_at, restore_expires_at=NULL, deleted_at=NULL
+            WHERE tenant=$1 AND image_identifier=$2 AND deleted_at > $3",
+            tenant.as_str(),
+            image_identifier,
+            self.purge_before()
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(restored.rows_affected() > 0)
+    }
+
+    async fn remove_upload(image_folder: &Path, tenant: &Tenant, image_identifier: &Uuid) {
+        let upload_path = ImagePath::upload(image_folder, tenant, image_identifier);
+        if let Err(e) = tokio::fs::remove_file(upload_path).await {
+            warn!("Something went wrong deleting the upload of image: {image_identifier} because: {e:?}");
+        }
+    }
+
+    //The format of the deleted original.
+    pub async fn delete_image(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<ImageFormat>, DeleteImageError> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .map_err(DeleteImageError::InternalServerError)?;
+
+        let rows = sqlx::query!(
+            "SELECT immutable FROM images WHERE tenant=$1 AND image_identifier=$2 FOR UPDATE",
+            tenant.as_str(),
+            image_identifier
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(DeleteImageError::InternalServerError)?;
+
+        if rows.is_empty() {
+            return Err(DeleteImageError::NotFound);
+        }
+        if rows.iter().any(|row| row.immutable) {
+            return Err(DeleteImageError::Immutable);
+        }
+        if self.soft_delete.is_some() {
+            return Self::soft_delete_image(transaction, tenant, image_identifier).await;
+        }
+
+        let deleted = sqlx::query!(
+            "DELETE FROM images WHERE tenant=$1 AND image_identifier=$2 RETURNING image_format, uploaded_type, source",
+            tenant.as_str(),
+            image_identifier
+        )
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(DeleteImageError::InternalServerError)?;
+
+        transaction
+            .commit()
+            .await
+            .map_err(DeleteImageError::InternalServerError)?;
+        transcode::forget_decoded(tenant, *image_identifier);
+
+        let mut source_format = None;
+        for image in deleted {
+            if image.uploaded_type.is_some() {
+                Self::remove_upload(&self.image_location, tenant, image_identifier).await;
+            }
+            let Some(format) = ImageFormat::from_str(&image.image_format) else {
+                continue;
+            };
+            if image.source {
+                source_format = Some(format);
+            }
+            let file_path = ImagePath::new(&self.image_location, tenant, image_identifier, format);
+            if let Err(e) = tokio::fs::remove_file(file_path).await {
+                warn!("Something went wrong deleting image: {image_identifier} because: {e:?}");
+            }
+        }
+
+        Ok(source_format)
+    }
+
+    //Drops every finished non-source format, None when the image doesn't exist.
+    //Rows still being computed are left alone so their files aren't orphaned mid-write.
+    pub async fn delete_variants(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<Vec<ImageFormat>>, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+
+        let exists = sqlx::query!(
+            "SELECT image_format FROM images WHERE tenant=$1 AND image_identifier=$2 AND (expires_at IS NULL OR expires_at > $3) FOR UPDATE",
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+        if exists.is_empty() {
+            return Ok(None);
+        }
+
+        let deleted = sqlx::query!(
+            "DELETE FROM images WHERE tenant=$1 AND image_identifier=$2 AND NOT source AND computed RETURNING image_format",
+            tenant.as_str(),
+            image_identifier
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        let deleted: Vec<ImageFormat> = deleted
+            .into_iter()
+            .filter_map(|image| ImageFormat::from_str(&image.image_format))
+            .collect();
+        for format in deleted.iter().copied() {
+            let file_path = ImagePath::new(&self.image_location, tenant, image_identifier, format);
+            if let Err(e) = tokio::fs::remove_file(file_path).await {
+                warn!("Something went wrong deleting variant of image: {image_identifier} because: {e:?}");
+            }
+        }
+
+        Ok(Some(deleted))
+    }
+
+    pub async fn stuck_images(
+        &self,
+        created_before: DateTime<Utc>,
+    ) -> Result<Vec<StuckImage>, sqlx::Error> {
+        let records = sqlx::query!(
+            "SELECT tenant, image_identifier, image_format, created_at, generation FROM images WHERE NOT computed AND created_at <= $1 ORDER BY created_at",
+            created_before
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records
+            .into_iter()
+            .filter_map(|record| {
+                Some(StuckImage {
+                    tenant: Tenant(record.tenant),
+                    image_identifier: record.image_identifier,
+                    image_format: ImageFormat::from_str(&record.image_format)?,
+                    created_at: record.created_at,
+                    generation: record.generation,
+                })
+            })
+            .collect())
+    }
+
+    //Files are only moved in place once fully written, so a stuck row with its file is just missing
+    //the update to computed. Rows without one are discarded, variants are transcoded again on request.
+    pub async fn requeue_stuck(
+        &self,
+        created_before: DateTime<Utc>,
+    ) -> Result<RequeueSummary, sqlx::Error> {
+        let mut summary = RequeueSummary::default();
+        for image in self.stuck_images(created_before).await? {
+            let file_path = ImagePath::new(
+                &self.image_location,
+                &image.tenant,
+                &image.image_identifier,
+                image.image_format,
+            );
+            let message = if tokio::fs::try_exists(&file_path).await.unwrap_or(false) {
+                summary.computed += 1;
+                DatabaseMessage::Computed(ComputedImage {
+                    tenant: image.tenant,
+                    image_id: image.image_identifier,
+                    image_format: image.image_format,
+                    generation: image.generation,
+                    temp_file: None,
+                    checksum: None,
+                    notifier: None,
+                })
+            } else {
+                warn!(
+                    "Stuck image: {} has no {} file, discarding it",
+                    image.image_identifier,
+                    image.image_format.to_str()
+                );
+                summary.discarded += 1;
+                DatabaseMessage::Discard(image.tenant, image.image_identifier, image.image_format)
+            };
+            if let Err(e) = self.transmitter.send(message).await {
+                warn!("Could not send to transmitter: {e:?}");
+            }
+        }
+        Ok(summary)
+    }
+
+    pub fn pending_work(&self) -> PendingWork {
+        PendingWork {
+            pool: self.pool.clone(),
+            transmitter: self.transmitter.clone(),
+        }
+    }
+
+    //None when the image doesn't exist, otherwise the formats that are ready to be served.
+    pub async fn available_formats(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<Vec<ImageFormat>>, sqlx::Error> {
+        let records = sqlx::query!(
+            "SELECT image_format, computed FROM images WHERE tenant=$1 AND image_identifier=$2 AND (expires_at IS NULL OR expires_at > $3)",
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        if records.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            records
+                .into_iter()
+                .filter(|record| record.computed)
+                .filter_map(|record| ImageFormat::from_str(&record.image_format))
+                .collect(),
+        ))
+    }
+
+    //Rows with a format this build doesn't know, like the "unkw" of `to_str`'s fallback, are treated as missing.
+    fn stored_format(image_identifier: &Uuid, image_format: &str) -> Option<ImageFormat> {
+        let parsed = ImageFormat::from_str(image_format);
+        if parsed.is_none() {
+            warn!("Ignoring row of image: {image_identifier} with unknown format {image_format:?}");
+        }
+        parsed
+    }
+
+    //Expired and deleted images are kept for the SOFT_DELETE_SECS grace period before their rows and files go.
+    fn purge_before(&self) -> DateTime<Utc> {
+        Utc::now() - self.soft_delete.unwrap_or_else(Duration::zero)
+    }
+
+    //Expiry is half-open: an image is gone from the instant expires_at is reached, matching the `expires_at > now` SQL filters.
+    fn is_expired(expires_at: Option<DateTime<Utc>>, now: &DateTime<Utc>) -> bool {
+        expires_at.is_some_and(|expires_at| &expires_at <= now)
+    }
+
+    //The image an alias points at, None for unknown aliases and expired images.
+    pub async fn resolve_alias(&self, tenant: &Tenant, alias: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "SELECT image_identifier FROM images WHERE tenant=$1 AND alias=$2 AND source AND (expires_at IS NULL OR expires_at > $3)",
+            tenant.as_str(),
+            alias,
+            Utc::now()
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn cache_info(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<CacheInfo>, sqlx::Error> {
+        let record = sqlx::query!(
+            "SELECT MIN(created_at) AS created_at, MAX(expires_at) FILTER (WHERE source) AS expires_at, BOOL_OR(immutable) AS immutable,
+            MAX(caption) FILTER (WHERE source) AS caption, BOOL_OR(computed) FILTER (WHERE source) AS computed
+            FROM images WHERE tenant=$1 AND image_identifier=$2 AND (expires_at IS NULL OR expires_at > $3)",
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(record.created_at.map(|last_modified| CacheInfo {
+            last_modified,
+            expires_at: record.expires_at,
+            immutable: record.immutable.unwrap_or(false),
+            caption: record.caption,
+            computed: record.computed.unwrap_or(false),
+        }))
+    }
+
+    pub async fn image_meta(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+    ) -> Result<Option<ImageMeta>, sqlx::Error> {
+        sqlx::query_as!(
+            ImageMeta,
+            "SELECT image_format AS format, caption, created_at, expires_at, immutable FROM images
+            WHERE tenant=$1 AND image_identifier=$2 AND source AND (expires_at IS NULL OR expires_at > $3)",
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    //Oldest first, continuing after the `(created_at, image_identifier)` of the last image seen.
+    //Seeking instead of skipping keeps later pages as cheap as the first, and images uploaded
+    //in between sort after the ones already seen.
+    pub async fn list_images(
+        &self,
+        tenant: &Tenant,
+        after: Option<(DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> Result<Vec<ListedImage>, sqlx::Error> {
+        sqlx::query_as!(
+            ListedImage,
+            "SELECT image_identifier, image_format AS format, created_at, expires_at FROM images
+            WHERE tenant=$1 AND source AND (expires_at IS NULL OR expires_at > $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR (created_at, image_identifier) > ($3, $4::UUID))
+            ORDER BY created_at, image_identifier LIMIT $5",
+            tenant.as_str(),
+            Utc::now(),
+            after.map(|(created_at, _)| created_at),
+            after.map(|(_, image_identifier)| image_identifier),
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    //The checksum recorded for a file with whether it is the source, None for files written before
+    //checksums were recorded.
+    pub async fn stored_checksum(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        image_format: ImageFormat,
+    ) -> Result<Option<(String, bool)>, sqlx::Error> {
+        let record = sqlx::query!(
+            "SELECT checksum, source FROM images WHERE tenant=$1 AND image_identifier=$2 AND image_format=$3",
+            tenant.as_str(),
+            image_identifier,
+            image_format.to_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(record.and_then(|record| Some((record.checksum?, record.source))))
+    }
+
+    //Removes a variant in the background, it is transcoded again on the next request.
+    pub async fn discard_variant(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        image_format: ImageFormat,
+    ) {
+        let message = DatabaseMessage::Discard(tenant.clone(), *image_identifier, image_format);
+        if let Err(e) = self.transmitter.send(message).await {
+            warn!("Could not send to transmitter: {e:?}");
+        }
+    }
+
+    pub async fn set_caption(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        caption: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE images SET caption=$3 WHERE tenant=$1 AND image_identifier=$2 AND source",
+            tenant.as_str(),
+            image_identifier,
+            caption
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    //Removes temp files left behind by writes that never finished, e.g. because the server crashed.
+    async fn sweep_temp_files(image_folder: &Path, max_age: std::time::Duration) {
+        let mut folders = vec![image_folder.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            let mut entries = match tokio::fs::read_dir(&folder).await {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Could not read image folder {} because: {e:?}", folder.display());
+                    continue;
+                }
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                let Ok(metadata) = entry.metadata().await else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    folders.push(path);
+                    continue;
+                }
+                let is_stale = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age > max_age);
+                if TempFile::is_temp_file(&path) && is_stale {
+                    debug!("Removing stale temp file {}", path.display());
+                    if let Err(e) = tokio::fs::remove_file(&path).await {
+                        warn!("Could not remove stale temp file {} because: {e:?}", path.display());
+                    }
+                }
+            }
+        }
+    }
+
+    //Only imported images have a content hash, uploads are never deduplicated.
+    pub async fn find_by_content_hash(
+        &self,
+        tenant: &Tenant,
+        content_hash: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar!(
+            "SELECT image_identifier FROM images WHERE tenant=$1 AND content_hash=$2 LIMIT 1",
+            tenant.as_str(),
+            content_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn set_content_hash(
+        &self,
+        tenant: &Tenant,
+        image_identifier: &Uuid,
+        content_hash: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE images SET content_hash=$3 WHERE tenant=$1 AND image_identifier=$2 AND source",
+            tenant.as_str(),
+            image_identifier,
+            content_hash
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    //Whether the image can still be served, unlike `file_exists` expired rows don't count.
+    pub async fn image_exists(&self, tenant: &Tenant, image_identifier: &Uuid) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM images WHERE tenant=$1 AND image_identifier=$2 AND source AND (expires_at IS NULL OR expires_at > $3)) AS "exists!""#,
+            tenant.as_str(),
+            image_identifier,
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .exists)
+    }
+
+    async fn file_exists(&self, tenant: &Tenant, image_identifier: &Uuid) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query!(
+            "SELECT * FROM images WHERE tenant=$1 AND image_identifier=$2",
+            tenant.as_str(),
+            image_identifier
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .is_some())
+    }
+
+    fn determine_eol(requested : Option<Duration>, max : Option<Duration>) -> Option<DateTime<Utc>>{
+        if requested.is_none(){
             max.map(|x| Utc::now() + x)
         }else if let (Some(requested), Some(max)) = (requested, max){
             if requested > max{
@@ -243,60 +1673,351 @@ impl Database {
 struct DatabaseReceiver();
 
 impl DatabaseReceiver {
+    const MAX_ATTEMPTS: u32 = 5;
+    const DEFAULT_MAX_TASKS: usize = 16;
+    const RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+
+    //Background queries retry transient errors (e.g. postgres restarting) instead of panicking the task.
+    async fn with_retries<T, F, Fut>(description: &str, mut operation: F) -> Option<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        let mut delay = Self::RETRY_DELAY;
+        for attempt in 1..=Self::MAX_ATTEMPTS {
+            match operation().await {
+                Ok(value) => return Some(value),
+                Err(e) if attempt < Self::MAX_ATTEMPTS && Self::is_transient(&e) => {
+                    warn!("{description} failed (attempt {attempt}), retrying in {delay:?}: {e:?}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    warn!("{description} failed, giving up: {e:?}");
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    fn is_transient(error: &sqlx::Error) -> bool {
+        match error {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::WorkerCrashed => true,
+            //Class 08 is connection exceptions, class 57 covers the server shutting down.
+            sqlx::Error::Database(e) => e
+                .code()
+                .is_some_and(|code| code.starts_with("08") || code.starts_with("57")),
+            _ => false,
+        }
+    }
+
+    //Messages are handled concurrently but at most `max_tasks` at a time, while they are all busy
+    //the channel fills up and pushes back on the senders.
     async fn compute_message(
         mut rx: Receiver<DatabaseMessage>,
         pool: PgPool,
         image_folder: PathBuf,
+        max_tasks: usize,
+        #[cfg(feature = "webhooks")] webhooks: Option<Webhooks>,
     ) {
+        let permits = Arc::new(Semaphore::new(max_tasks));
         while let Some(message) = rx.recv().await {
-            match message {
-                DatabaseMessage::Computed(image, image_format) => {
-                    tokio::spawn(Self::image_computed(image, image_format, pool.clone()));
-                }
-                DatabaseMessage::CleanExpired => {
-                    tokio::spawn(Self::clean_expired(pool.clone(), image_folder.clone()));
+            let permit = permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Background task semaphore is never closed");
+            let pool = pool.clone();
+            let image_folder = image_folder.clone();
+            #[cfg(feature = "webhooks")]
+            let webhooks = webhooks.clone();
+            tokio::spawn(async move {
+                match message {
+                    DatabaseMessage::Computed(computed) => {
+                        Self::image_computed(
+                            computed,
+                            pool,
+                            image_folder,
+                            #[cfg(feature = "webhooks")]
+                            webhooks,
+                        )
+                        .await
+                    }
+                    DatabaseMessage::Discard(tenant, image, image_format) => {
+                        Self::discard_image(tenant, image, image_format, pool, image_folder).await
+                    }
+                    DatabaseMessage::CleanExpired(expired_before) => {
+                        Self::clean_expired(expired_before, pool, image_folder).await
+                    }
+                    DatabaseMessage::EnforceImageCount(max_image_count, pending) => {
+                        pending.store(false, Ordering::Release);
+                        Self::evict_oldest(max_image_count, pool, image_folder).await
+                    }
                 }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn image_computed(
+        mut computed: ComputedImage,
+        pool: PgPool,
+        image_folder: PathBuf,
+        #[cfg(feature = "webhooks")] webhooks: Option<Webhooks>,
+    ) {
+        let file_path = ImagePath::new(&image_folder, &computed.tenant, &computed.image_id, computed.image_format);
+        let notifier = computed.notifier.take();
+        let updated = Self::move_in_place(&mut computed, &file_path, &pool, &image_folder).await;
+        //A source written again under the same id must not be served from an old decode.
+        if updated == Some(true) {
+            transcode::forget_decoded(&computed.tenant, computed.image_id);
+        }
+        //Only uploads are announced, not the variants transcoded from them.
+        #[cfg(feature = "webhooks")]
+        if let (Some(webhooks), Some(true)) = (webhooks, updated) {
+            webhooks.send(
+                WebhookEvent::Computed,
+                &computed.tenant,
+                computed.image_id,
+                Some(computed.image_format),
+            );
+        }
+        //Dropping the notifier without sending tells the waiter the image never became servable.
+        if let (Some(_), Some(notifier)) = (updated, notifier) {
+            let _ = notifier.send(());
+        }
+    }
+
+    //Whether the row is a source, None when the file was dropped instead: its row was deleted, expired or
+    //replaced by a newer generation, or the file couldn't be moved in place, which discards the row.
+    //No row is locked while the file is moved. Writes of the same file in this instance take turns instead,
+    //so a write that passed the generation check is in place before a newer one is checked.
+    async fn move_in_place(
+        computed: &mut ComputedImage,
+        file_path: &ImagePath,
+        pool: &PgPool,
+        image_folder: &Path,
+    ) -> Option<bool> {
+        let image_id = computed.image_id;
+        let image_format = computed.image_format;
+        let _turn = file_write_turn(file_path).lock().await;
+        let current = Self::with_retries("Checking the generation of an image", || {
+            sqlx::query_scalar!(
+                "SELECT source FROM images WHERE tenant=$1 AND image_identifier=$2 AND image_format=$3 AND generation=$4",
+                computed.tenant.as_str(),
+                image_id,
+                image_format.to_str(),
+                computed.generation
+            )
+            .fetch_optional(pool)
+        })
+        .await
+        .flatten();
+        if current.is_none() {
+            debug!("Dropping stale {} file of image: {image_id}", image_format.to_str());
+            return None;
+        }
+
+        if let Some(temp_file) = computed.temp_file.take() {
+            if let Err(e) = temp_file.persist_async(file_path).await {
+                warn!("Could not move image: {image_id} in place because: {e:?}");
+                Self::discard_image(computed.tenant.clone(), image_id, image_format, pool.clone(), image_folder.to_path_buf())
+                    .await;
+                return None;
             }
         }
+        let marked = Self::with_retries("Marking image as computed", || Self::mark_computed(pool, computed))
+            .await
+            .flatten();
+        if marked.is_some() {
+            return marked;
+        }
+        //The row was deleted or replaced while the file was moved, the file is orphaned or, written by
+        //another instance, may have replaced the newer one. Unless the newer row is an upload, whose own
+        //write decides its file, the format is discarded to be transcoded again.
+        let replaced_by_upload = Self::with_retries("Checking a replaced image", || {
+            sqlx::query_scalar!(
+                "SELECT source FROM images WHERE tenant=$1 AND image_identifier=$2 AND image_format=$3",
+                computed.tenant.as_str(),
+                image_id,
+                image_format.to_str()
+            )
+            .fetch_optional(pool)
+        })
+        .await
+        .flatten();
+        if replaced_by_upload != Some(true) {
+            debug!("Discarding {} file of image: {image_id} that turned stale", image_format.to_str());
+            Self::discard_image(computed.tenant.clone(), image_id, image_format, pool.clone(), image_folder.to_path_buf())
+                .await;
+        }
+        None
     }
 
-    async fn image_computed(image_id: Uuid, file_format: ImageFormat, pool: PgPool) {
-        let _ = sqlx::query!(
-            "UPDATE images SET computed=true WHERE image_identifier=$1 AND image_format=$2",
-            image_id,
-            file_format.to_str()
+    //Whether the row is a source, None when it no longer has the generation the file was written for.
+    async fn mark_computed(pool: &PgPool, computed: &ComputedImage) -> Result<Option<bool>, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+        let source = sqlx::query_scalar!(
+            "UPDATE images SET computed=true, checksum=COALESCE($4, checksum) WHERE tenant=$1 AND image_identifier=$2 AND image_format=$3 AND generation=$5 RETURNING source",
+            computed.tenant.as_str(),
+            computed.image_id,
+            computed.image_format.to_str(),
+            computed.checksum.as_deref(),
+            computed.generation
         )
-        .execute(&pool)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if source.is_none() {
+            return Ok(None);
+        }
+        //Delivered to every instance once the transaction commits.
+        sqlx::query!(
+            "SELECT pg_notify($1, $2)",
+            COMPUTED_CHANNEL,
+            variant_key(&computed.tenant, &computed.image_id, computed.image_format)
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(source)
+    }
+
+    async fn discard_image(
+        tenant: Tenant,
+        image_id: Uuid,
+        file_format: ImageFormat,
+        pool: PgPool,
+        image_folder: PathBuf,
+    ) {
+        let discarded = Self::with_retries("Deleting discarded image", || {
+            sqlx::query!(
+                "DELETE FROM images WHERE tenant=$1 AND image_identifier=$2 AND image_format=$3 RETURNING uploaded_type",
+                tenant.as_str(),
+                image_id,
+                file_format.to_str()
+            )
+            .fetch_all(&pool)
+        })
+        .await;
+        //Only a discarded source has a kept upload.
+        if discarded.is_some_and(|rows| rows.iter().any(|row| row.uploaded_type.is_some())) {
+            Database::remove_upload(&image_folder, &tenant, &image_id).await;
+        }
+        transcode::forget_decoded(&tenant, image_id);
+        //Requests waiting for the format claim it themselves instead of waiting out their timeout.
+        Self::with_retries("Announcing discarded image", || {
+            sqlx::query!(
+                "SELECT pg_notify($1, $2)",
+                COMPUTED_CHANNEL,
+                variant_key(&tenant, &image_id, file_format)
+            )
+            .execute(&pool)
+        })
+        .await;
+
+        //Images that failed to be written never had a file.
+        let file_path = ImagePath::new(&image_folder, &tenant, &image_id, file_format);
+        match tokio::fs::remove_file(file_path).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not remove discarded image: {image_id} because: {e:?}"),
+            Ok(()) => {}
+        }
+    }
+
+    //Immutable images count towards the cap but are never evicted, like those whose source is still
+    //being written. Soft deleted images don't count and are left to the purge, so they stay restorable
+    //for the whole window. An evicted image takes every row along, variants still being computed included,
+    //their files are dropped by `move_in_place` once it finds the row gone.
+    async fn evict_oldest(max_image_count: u64, pool: PgPool, image_folder: PathBuf) {
+        let keep = i64::try_from(max_image_count).unwrap_or(i64::MAX);
+        let Some(evicted) = Self::with_retries("Evicting the oldest images", || {
+            sqlx::query!(
+                "WITH oldest AS (SELECT tenant, image_identifier, immutable, computed FROM images WHERE source AND deleted_at IS NULL ORDER BY created_at DESC, image_identifier OFFSET $1)
+                DELETE FROM images USING oldest WHERE images.tenant = oldest.tenant AND images.image_identifier = oldest.image_identifier AND oldest.computed AND NOT oldest.immutable
+                RETURNING images.tenant, images.image_identifier, images.image_format, images.uploaded_type, images.computed",
+                keep
+            )
+            .fetch_all(&pool)
+        })
         .await
-        .expect("Thread could not send query to sqlx");
+        else {
+            return;
+        };
+        if !evicted.is_empty() {
+            debug!("Evicted {} rows beyond the image count limit", evicted.len());
+        }
+        for image in evicted {
+            let tenant = Tenant(image.tenant);
+            transcode::forget_decoded(&tenant, image.image_identifier);
+            if image.uploaded_type.is_some() {
+                Database::remove_upload(&image_folder, &tenant, &image.image_identifier).await;
+            }
+            let Some(format) = ImageFormat::from_str(&image.image_format) else {
+                continue;
+            };
+            let file_path = ImagePath::new(&image_folder, &tenant, &image.image_identifier, format);
+            match tokio::fs::remove_file(file_path).await {
+                //Uncomputed rows may not have a file yet.
+                Err(e) if !image.computed && e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Something went wrong deleting evicted image: {e:?}"),
+                Ok(()) => {}
+            }
+        }
     }
 
-    async fn clean_expired(pool: PgPool, image_folder: PathBuf) {
+    //Expired variants go on their own once computed, an expired source takes every row of its image along
+    //in the same statement, computed or not. Files still being written are dropped by `move_in_place`.
+    async fn clean_expired(expired_before: DateTime<Utc>, pool: PgPool, image_folder: PathBuf) {
         debug!("Deleting expired images");
-        let expired = sqlx::query!(
-            "DELETE FROM images WHERE expires_at < $1 AND computed = True RETURNING image_identifier, image_format",
-            Utc::now()
-        )
-        .fetch_all(&pool)
+        let Some(expired) = Self::with_retries("Deleting expired images", || {
+            sqlx::query!(
+                "DELETE FROM images WHERE NOT immutable AND (
+                    (expires_at <= $1 AND computed)
+                    OR (tenant, image_identifier) IN (SELECT tenant, image_identifier FROM images WHERE source AND expires_at <= $1 AND NOT immutable)
+                ) RETURNING tenant, image_identifier, image_format, uploaded_type, computed",
+                expired_before
+            )
+            .fetch_all(&pool)
+        })
         .await
-        .unwrap();
+        else {
+            return;
+        };
         for image in expired {
-            let format = ImageFormat::from_str(&image.image_format)
-                .expect("INVALID IMAGE FORMAT IN DATABASE");
-            let file_path = ImagePath::new(&image_folder, &image.image_identifier, format);
-            if let Err(e) = tokio::fs::remove_file(file_path).await {
-                warn!("Something went wrong deleting expired image: {e:?}");
+            let tenant = Tenant(image.tenant);
+            transcode::forget_decoded(&tenant, image.image_identifier);
+            if image.uploaded_type.is_some() {
+                Database::remove_upload(&image_folder, &tenant, &image.image_identifier).await;
+            }
+            let Some(format) = Database::stored_format(&image.image_identifier, &image.image_format)
+            else {
+                continue;
+            };
+            let file_path = ImagePath::new(&image_folder, &tenant, &image.image_identifier, format);
+            match tokio::fs::remove_file(file_path).await {
+                //Uncomputed rows may not have a file yet.
+                Err(e) if !image.computed && e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Something went wrong deleting expired image: {e:?}"),
+                Ok(()) => {}
             }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ImagePath(PathBuf);
 
 impl ImagePath {
+    const UPLOAD_EXTENSION: &'static str = "upload";
+
     pub fn new(
         image_folder: &Path,
+        tenant: &Tenant,
         image_identifier: &Uuid,
         image_format: ImageFormat,
     ) -> ImagePath {
@@ -306,10 +2027,102 @@ impl ImagePath {
         }
         ImagePath(
             image_folder
+                .join(tenant.as_str())
                 .join(location)
                 .with_extension(image_format.extension()),
         )
     }
+
+    //The exact uploaded bytes kept next to the source, see `KeptUpload`.
+    fn upload(image_folder: &Path, tenant: &Tenant, image_identifier: &Uuid) -> PathBuf {
+        ImagePath::new(image_folder, tenant, image_identifier, ImageFormat::default())
+            .0
+            .with_extension(Self::UPLOAD_EXTENSION)
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        self.0
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ImageFormat::from_str)
+            .expect("image paths are always created with a known extension")
+    }
+
+    fn create_parent_dir(&self) -> std::io::Result<()> {
+        match self.0.parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+    }
+}
+
+//A missing format reserved by `claim_variant`, handed to `save_raw_image` or `release_claim`.
+pub struct VariantClaim {
+    tenant: Tenant,
+    image_identifier: Uuid,
+    image_format: ImageFormat,
+    generation: i64,
+}
+
+const COMPUTED_CHANNEL: &str = "image_computed";
+const FILE_WRITE_TURNS: usize = 64;
+
+//Files share a fixed set of turns by the hash of their path, unrelated files rarely wait on each other.
+static FILE_WRITES: [tokio::sync::Mutex<()>; FILE_WRITE_TURNS] =
+    [const { tokio::sync::Mutex::const_new(()) }; FILE_WRITE_TURNS];
+
+fn file_write_turn(file_path: &ImagePath) -> &'static tokio::sync::Mutex<()> {
+    let mut hasher = std::hash::DefaultHasher::new();
+    file_path.as_ref().hash(&mut hasher);
+    &FILE_WRITES[hasher.finish() as usize % FILE_WRITE_TURNS]
+}
+
+//Names a format of an image in advisory locks and computed events.
+pub fn variant_key(tenant: &Tenant, image_identifier: &Uuid, image_format: ImageFormat) -> String {
+    format!("{}/{image_identifier}.{}", tenant.as_str(), image_format.to_str())
+}
+
+//Files are written to a temp file next to their target and renamed into place, so a half written
+//file is never served. The temp file is removed on drop unless it was persisted.
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    const EXTENSION: &'static str = "tmp";
+
+    fn for_target(target: impl AsRef<Path>) -> TempFile {
+        let target = target.as_ref();
+        let mut file_name = target.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!(".{}.{}", Uuid::new_v4().simple(), Self::EXTENSION));
+        TempFile {
+            path: target.with_file_name(file_name),
+            persisted: false,
+        }
+    }
+
+    fn is_temp_file(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| extension == Self::EXTENSION)
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn persist_async(mut self, target: impl AsRef<Path>) -> std::io::Result<()> {
+        tokio::fs::rename(&self.path, target.as_ref()).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
 }
 
 impl AsRef<Path> for ImagePath {
@@ -317,3 +2130,184 @@ impl AsRef<Path> for ImagePath {
         self.0.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    //Runs against the database in DATABASE_URL like the integration tests, skipped without it.
+    async fn pool() -> Option<PgPool> {
+        let database_url = std::env::var("DATABASE_URL").ok()?;
+        Some(PgPool::connect(&database_url).await.unwrap())
+    }
+
+    fn tenant() -> Tenant {
+        Tenant::new(&format!("test-{}", Uuid::new_v4().simple())).unwrap()
+    }
+
+    async fn insert_variant(pool: &PgPool, tenant: &Tenant, image_id: Uuid) -> i64 {
+        sqlx::query_scalar!(
+            "INSERT INTO images (tenant, image_identifier, image_format) VALUES ($1, $2, $3) RETURNING generation",
+            tenant.as_str(),
+            image_id,
+            ImageFormat::WEBP.to_str()
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn row(pool: &PgPool, tenant: &Tenant, image_id: Uuid) -> Option<(bool, Option<String>)> {
+        sqlx::query!(
+            "SELECT computed, checksum FROM images WHERE tenant=$1 AND image_identifier=$2",
+            tenant.as_str(),
+            image_id
+        )
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .map(|row| (row.computed, row.checksum))
+    }
+
+    //A file written for `generation` of the row, waiting next to its target to be moved in place.
+    fn written(tenant: &Tenant, image_id: Uuid, file_path: &ImagePath, generation: i64, data: &[u8]) -> ComputedImage {
+        std::fs::create_dir_all(file_path.as_ref().parent().unwrap()).unwrap();
+        let temp_file = TempFile::for_target(file_path);
+        std::fs::write(temp_file.path(), data).unwrap();
+        ComputedImage {
+            tenant: tenant.clone(),
+            image_id,
+            image_format: ImageFormat::WEBP,
+            generation,
+            temp_file: Some(temp_file),
+            checksum: Some(checksum(data)),
+            notifier: None,
+        }
+    }
+
+    async fn computed(pool: &PgPool, folder: &TempDir, image: ComputedImage) {
+        DatabaseReceiver::image_computed(
+            image,
+            pool.clone(),
+            folder.path().to_path_buf(),
+            #[cfg(feature = "webhooks")]
+            None,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stale_write_finishing_after_a_newer_one_is_dropped() {
+        let Some(pool) = pool().await else {
+            return;
+        };
+        let folder = TempDir::new().unwrap();
+        let (tenant, image_id) = (tenant(), Uuid::new_v4());
+        let file_path = ImagePath::new(folder.path(), &tenant, &image_id, ImageFormat::WEBP);
+
+        //The old transcode is still writing when its row is discarded and claimed again.
+        let old_generation = insert_variant(&pool, &tenant, image_id).await;
+        let old = written(&tenant, image_id, &file_path, old_generation, b"old");
+        let old_temp_file = old.temp_file.as_ref().unwrap().path().to_path_buf();
+        sqlx::query!("DELETE FROM images WHERE tenant=$1", tenant.as_str())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let new_generation = insert_variant(&pool, &tenant, image_id).await;
+        let new = written(&tenant, image_id, &file_path, new_generation, b"new");
+
+        computed(&pool, &folder, new).await;
+        computed(&pool, &folder, old).await;
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"new");
+        assert!(!old_temp_file.exists());
+        assert_eq!(row(&pool, &tenant, image_id).await, Some((true, Some(checksum(b"new")))));
+    }
+
+    #[tokio::test]
+    async fn files_that_cant_be_moved_discard_their_row() {
+        let Some(pool) = pool().await else {
+            return;
+        };
+        let folder = TempDir::new().unwrap();
+        let (tenant, image_id) = (tenant(), Uuid::new_v4());
+        let file_path = ImagePath::new(folder.path(), &tenant, &image_id, ImageFormat::WEBP);
+
+        let generation = insert_variant(&pool, &tenant, image_id).await;
+        let image = written(&tenant, image_id, &file_path, generation, b"data");
+        //A file can't be renamed over a directory.
+        std::fs::create_dir_all(file_path.as_ref().join("blocking")).unwrap();
+        computed(&pool, &folder, image).await;
+
+        assert_eq!(row(&pool, &tenant, image_id).await, None);
+    }
+
+    //The image count cap spans every tenant, so eviction runs on a copy of the table that no other test sees.
+    async fn isolated_pool(pool: &PgPool) -> (PgPool, String) {
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+        for statement in [
+            format!("CREATE SCHEMA {schema}"),
+            format!("CREATE TABLE {schema}.images (LIKE public.images INCLUDING ALL)"),
+        ] {
+            sqlx::query(&statement).execute(pool).await.unwrap();
+        }
+        let options = pool
+            .connect_options()
+            .as_ref()
+            .clone()
+            .options([("search_path", schema.as_str())]);
+        (PgPool::connect_with(options).await.unwrap(), schema)
+    }
+
+    async fn insert_source(pool: &PgPool, tenant: &Tenant, age_secs: i64, deleted: bool) -> Uuid {
+        let image_id = Uuid::new_v4();
+        let now = Utc::now();
+        sqlx::query(
+            "INSERT INTO images (tenant, image_identifier, image_format, source, computed, created_at, deleted_at) VALUES ($1, $2, $3, true, true, $4, $5)",
+        )
+        .bind(tenant.as_str())
+        .bind(image_id)
+        .bind(ImageFormat::PNG.to_str())
+        .bind(now - Duration::seconds(age_secs))
+        .bind(deleted.then_some(now))
+        .execute(pool)
+        .await
+        .unwrap();
+        image_id
+    }
+
+    async fn sources(pool: &PgPool) -> Vec<Uuid> {
+        sqlx::query_scalar("SELECT image_identifier FROM images WHERE source ORDER BY created_at")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn soft_deleted_images_are_neither_counted_nor_evicted() {
+        let Some(pool) = pool().await else {
+            return;
+        };
+        let (isolated, schema) = isolated_pool(&pool).await;
+        let folder = TempDir::new().unwrap();
+        let tenant = tenant();
+        let deleted = insert_source(&isolated, &tenant, 30, true).await;
+        let older = insert_source(&isolated, &tenant, 20, false).await;
+        let newer = insert_source(&isolated, &tenant, 10, false).await;
+
+        DatabaseReceiver::evict_oldest(2, isolated.clone(), folder.path().to_path_buf()).await;
+        let under_cap = sources(&isolated).await;
+        DatabaseReceiver::evict_oldest(1, isolated.clone(), folder.path().to_path_buf()).await;
+        let over_cap = sources(&isolated).await;
+
+        isolated.close().await;
+        sqlx::query(&format!("DROP SCHEMA {schema} CASCADE"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(under_cap, [deleted, older, newer]);
+        assert_eq!(over_cap, [deleted, newer]);
+    }
+}
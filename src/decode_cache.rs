@@ -0,0 +1,125 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use image::DynamicImage;
+use uuid::Uuid;
+
+use crate::database::Tenant;
+
+struct Entry {
+    image: Arc<DynamicImage>,
+    bytes: usize,
+    last_used: u64,
+}
+
+//Least recently used decoded sources, bounded by the size of their pixel buffers.
+pub struct DecodeCache {
+    max_bytes: usize,
+    used_bytes: usize,
+    clock: u64,
+    entries: HashMap<(Tenant, Uuid), Entry>,
+    //The keys by when they were last used, so the oldest is found without a scan.
+    recency: BTreeMap<u64, (Tenant, Uuid)>,
+}
+
+impl DecodeCache {
+    pub fn new(max_bytes: usize) -> DecodeCache {
+        DecodeCache {
+            max_bytes,
+            used_bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, tenant: &Tenant, image_id: Uuid) -> Option<Arc<DynamicImage>> {
+        self.clock += 1;
+        let key = (tenant.clone(), image_id);
+        let entry = self.entries.get_mut(&key)?;
+        self.recency.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(entry.image.clone())
+    }
+
+    //Drops the decode of a source that was deleted or replaced.
+    pub fn remove(&mut self, tenant: &Tenant, image_id: Uuid) {
+        if let Some(entry) = self.entries.remove(&(tenant.clone(), image_id)) {
+            self.recency.remove(&entry.last_used);
+            self.used_bytes -= entry.bytes;
+        }
+    }
+
+    //Images larger than the whole cache are not kept.
+    pub fn insert(&mut self, tenant: Tenant, image_id: Uuid, image: Arc<DynamicImage>) {
+        let bytes = image.as_bytes().len();
+        if bytes > self.max_bytes {
+            return;
+        }
+
+        self.clock += 1;
+        let entry = Entry {
+            image,
+            bytes,
+            last_used: self.clock,
+        };
+        self.recency.insert(self.clock, (tenant.clone(), image_id));
+        if let Some(previous) = self.entries.insert((tenant, image_id), entry) {
+            self.recency.remove(&previous.last_used);
+            self.used_bytes -= previous.bytes;
+        }
+        self.used_bytes += bytes;
+
+        while self.used_bytes > self.max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.used_bytes -= evicted.bytes;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    fn image() -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::ImageRgb8(RgbImage::new(10, 10)))
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let tenant = Tenant::default();
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut cache = DecodeCache::new(2 * 300);
+        cache.insert(tenant.clone(), first, image());
+        cache.insert(tenant.clone(), second, image());
+        cache.get(&tenant, first);
+        cache.insert(tenant.clone(), third, image());
+
+        assert!(cache.get(&tenant, first).is_some());
+        assert!(cache.get(&tenant, second).is_none());
+        assert!(cache.get(&tenant, third).is_some());
+    }
+
+    #[test]
+    fn removed_images_free_their_bytes() {
+        let tenant = Tenant::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut cache = DecodeCache::new(300);
+        cache.insert(tenant.clone(), first, image());
+        cache.remove(&tenant, first);
+        cache.insert(tenant.clone(), second, image());
+
+        assert!(cache.get(&tenant, first).is_none());
+        assert!(cache.get(&tenant, second).is_some());
+        assert_eq!(cache.used_bytes, 300);
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{icc::png_chunk, image_format::ImageFormat};
+
+const PNG_SIGNATURE_LENGTH: usize = 8;
+const METERS_PER_INCH: f64 = 0.0254;
+const JFIF_IDENTIFIER: &[u8] = b"JFIF\0";
+const JFIF_DOTS_PER_INCH: u8 = 1;
+const PNG_UNIT_METER: u8 = 1;
+
+//Records `dpi` in the header of encoded output, the pixels are left alone so nothing is decoded or re-encoded.
+//Formats without a density field of their own are returned unchanged.
+pub fn set_density(data: Vec<u8>, image_format: ImageFormat, dpi: u16) -> Vec<u8> {
+    let updated = match image_format.format() {
+        image::ImageFormat::Jpeg => set_jpeg_density(&data, dpi),
+        image::ImageFormat::Png => set_png_density(&data, dpi),
+        _ => None,
+    };
+    updated.unwrap_or(data)
+}
+
+//The JFIF segment an encoder writes right after SOI is updated in place, files without one get it inserted.
+fn set_jpeg_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut updated = data.to_vec();
+    if data.get(2..4) == Some(&[0xFF, 0xE0]) && data.get(6..11) == Some(JFIF_IDENTIFIER) && data.len() >= 18 {
+        updated[13] = JFIF_DOTS_PER_INCH;
+        updated[14..16].copy_from_slice(&dpi.to_be_bytes());
+        updated[16..18].copy_from_slice(&dpi.to_be_bytes());
+        return Some(updated);
+    }
+    let mut segment = vec![0xFF, 0xE0, 0, 16];
+    segment.extend_from_slice(JFIF_IDENTIFIER);
+    //Version 1.1, then the units.
+    segment.extend_from_slice(&[1, 1, JFIF_DOTS_PER_INCH]);
+    segment.extend_from_slice(&dpi.to_be_bytes());
+    segment.extend_from_slice(&dpi.to_be_bytes());
+    //No thumbnail.
+    segment.extend_from_slice(&[0, 0]);
+    updated.splice(2..2, segment);
+    Some(updated)
+}
+
+//A pHYs chunk after IHDR in pixels per meter, replacing any the file had.
+fn set_png_density(data: &[u8], dpi: u16) -> Option<Vec<u8>> {
+    let pixels_per_meter = (dpi as f64 / METERS_PER_INCH).round() as u32;
+    let mut chunk_data = pixels_per_meter.to_be_bytes().to_vec();
+    chunk_data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    chunk_data.push(PNG_UNIT_METER);
+
+    let mut updated = data.get(..PNG_SIGNATURE_LENGTH)?.to_vec();
+    let mut offset = PNG_SIGNATURE_LENGTH;
+    while offset < data.len() {
+        let length = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let end = offset.checked_add(12 + length).filter(|&end| end <= data.len())?;
+        let kind = &data[offset + 4..offset + 8];
+        if kind != b"pHYs" {
+            updated.extend_from_slice(&data[offset..end]);
+        }
+        if kind == b"IHDR" {
+            updated.extend(png_chunk(b"pHYs", &chunk_data));
+        }
+        offset = end;
+    }
+    Some(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::RgbImage;
+
+    use super::*;
+
+    fn encode(format: image::ImageFormat) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        RgbImage::new(8, 8).write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn jpeg_density_is_set_in_the_jfif_segment() {
+        let jpeg = encode(image::ImageFormat::Jpeg);
+        let updated = set_density(jpeg.clone(), ImageFormat::JPG, 300);
+
+        assert_eq!(updated.len(), jpeg.len());
+        assert_eq!(updated[13..18], [1, 1, 44, 1, 44]);
+        assert_eq!(updated[18..], jpeg[18..]);
+        image::load_from_memory(&updated).unwrap();
+    }
+
+    #[test]
+    fn jpeg_without_jfif_segment_gets_one() {
+        let jpeg = encode(image::ImageFormat::Jpeg);
+        let app0_end = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+        let mut stripped = jpeg[..2].to_vec();
+        stripped.extend_from_slice(&jpeg[app0_end..]);
+
+        let updated = set_density(stripped, ImageFormat::JPG, 72);
+        assert_eq!(updated[2..11], [0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0]);
+        assert_eq!(updated[13..18], [1, 0, 72, 0, 72]);
+        image::load_from_memory(&updated).unwrap();
+    }
+
+    #[test]
+    fn png_density_replaces_any_previous_one() {
+        let png = encode(image::ImageFormat::Png);
+        let once = set_density(png, ImageFormat::PNG, 150);
+        let twice = set_density(once, ImageFormat::PNG, 300);
+
+        let chunks: Vec<_> = twice.windows(4).filter(|window| *window == b"pHYs").collect();
+        assert_eq!(chunks.len(), 1);
+        let offset = twice.windows(4).position(|window| window == b"pHYs").unwrap() + 4;
+        //300 dpi is 11811 pixels per meter.
+        assert_eq!(twice[offset..offset + 9], [0, 0, 0x2E, 0x23, 0, 0, 0x2E, 0x23, 1]);
+        image::load_from_memory(&twice).unwrap();
+    }
+}
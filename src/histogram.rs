@@ -0,0 +1,33 @@
+use image::DynamicImage;
+use serde::Serialize;
+
+const BUCKETS: usize = 256;
+
+#[derive(Serialize)]
+pub struct Histogram {
+    pub red: Vec<u64>,
+    pub green: Vec<u64>,
+    pub blue: Vec<u64>,
+    pub luma: Vec<u64>,
+}
+
+//Channels are bucketed at 8 bits, luma uses the same Rec. 709 weights as the image crate.
+pub fn histogram(image: &DynamicImage) -> Histogram {
+    let mut histogram = Histogram {
+        red: vec![0; BUCKETS],
+        green: vec![0; BUCKETS],
+        blue: vec![0; BUCKETS],
+        luma: vec![0; BUCKETS],
+    };
+
+    for pixel in image.to_rgb8().pixels() {
+        let [red, green, blue] = pixel.0;
+        histogram.red[red as usize] += 1;
+        histogram.green[green as usize] += 1;
+        histogram.blue[blue as usize] += 1;
+        let luma = (2126 * red as u32 + 7152 * green as u32 + 722 * blue as u32) / 10000;
+        histogram.luma[luma as usize] += 1;
+    }
+
+    histogram
+}
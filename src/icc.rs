@@ -0,0 +1,311 @@
+use std::{
+    io::Write,
+    sync::OnceLock,
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use image::ColorType;
+
+use crate::image_format::ImageFormat;
+
+const JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+//A JPEG segment holds at most 65535 bytes including its length, the marker and the chunk numbering.
+const JPEG_MAX_ICC_CHUNK: usize = 65535 - 2 - JPEG_ICC_MARKER.len() - 2;
+const PNG_SIGNATURE_LENGTH: usize = 8;
+const PNG_PROFILE_NAME: &[u8] = b"ICC profile";
+const WEBP_ICC_FLAG: u8 = 0x20;
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+
+static SRGB_PROFILE: OnceLock<Vec<u8>> = OnceLock::new();
+
+//An RGB profile only describes color images and a gray one only grayscale images.
+fn matches(profile: &[u8], color: ColorType) -> bool {
+    match profile.get(16..20) {
+        Some(b"RGB ") => color.has_color(),
+        Some(b"GRAY") => !color.has_color(),
+        _ => false,
+    }
+}
+
+//Adds `profile` to encoded output, formats and color types it can't describe are returned unchanged.
+pub fn embed(data: Vec<u8>, image_format: ImageFormat, color: ColorType, profile: &[u8]) -> Vec<u8> {
+    if !matches(profile, color) {
+        return data;
+    }
+    let embedded = match image_format.format() {
+        image::ImageFormat::Jpeg => embed_jpeg(&data, profile),
+        image::ImageFormat::Png => embed_png(&data, profile),
+        image::ImageFormat::WebP => embed_webp(&data, profile),
+        _ => None,
+    };
+    embedded.unwrap_or(data)
+}
+
+//The profile to store with an image whose source had none, if there is a sensible default.
+pub fn default_profile(color: ColorType) -> Option<&'static [u8]> {
+    color
+        .has_color()
+        .then(|| SRGB_PROFILE.get_or_init(srgb_profile).as_slice())
+}
+
+//APP2 segments after the JFIF and Exif segments, which readers expect to come first, split into numbered
+//chunks when the profile doesn't fit one.
+fn embed_jpeg(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let chunks: Vec<&[u8]> = profile.chunks(JPEG_MAX_ICC_CHUNK).collect();
+    let count = u8::try_from(chunks.len()).ok()?;
+
+    let mut offset = 2;
+    while let Some(&[0xFF, 0xE0 | 0xE1, high, low]) = data.get(offset..offset + 4) {
+        offset += 2 + u16::from_be_bytes([high, low]) as usize;
+    }
+    let offset = offset.min(data.len());
+
+    let mut embedded = Vec::with_capacity(data.len() + profile.len() + chunks.len() * 18);
+    embedded.extend_from_slice(&data[..offset]);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let length = (2 + JPEG_ICC_MARKER.len() + 2 + chunk.len()) as u16;
+        embedded.extend_from_slice(&[0xFF, 0xE2]);
+        embedded.extend_from_slice(&length.to_be_bytes());
+        embedded.extend_from_slice(JPEG_ICC_MARKER);
+        embedded.extend_from_slice(&[index as u8 + 1, count]);
+        embedded.extend_from_slice(chunk);
+    }
+    embedded.extend_from_slice(&data[offset..]);
+    Some(embedded)
+}
+
+pub fn png_chunk(kind: &[u8; 4], chunk_data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(12 + chunk_data.len());
+    chunk.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(chunk_data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(chunk_data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+//An iCCP chunk after IHDR, any sRGB chunk is dropped since the two are mutually exclusive.
+fn embed_png(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(profile).ok()?;
+    let mut chunk_data = PNG_PROFILE_NAME.to_vec();
+    //Null separator and compression method 0.
+    chunk_data.extend_from_slice(&[0, 0]);
+    chunk_data.extend(encoder.finish().ok()?);
+
+    let mut embedded = data.get(..PNG_SIGNATURE_LENGTH)?.to_vec();
+    let mut offset = PNG_SIGNATURE_LENGTH;
+    while offset < data.len() {
+        let length = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize;
+        let end = offset.checked_add(12 + length).filter(|&end| end <= data.len())?;
+        let kind = &data[offset + 4..offset + 8];
+        if kind != b"iCCP" && kind != b"sRGB" {
+            embedded.extend_from_slice(&data[offset..end]);
+        }
+        if kind == b"IHDR" {
+            embedded.extend(png_chunk(b"iCCP", &chunk_data));
+        }
+        offset = end;
+    }
+    Some(embedded)
+}
+
+fn riff_chunk(kind: &[u8], chunk_data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(8 + chunk_data.len() + 1);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(chunk_data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(chunk_data);
+    if chunk_data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+//Simple lossy and lossless files only hold their bitstream, the extended format has room for an ICCP chunk.
+fn webp_extended_header(kind: &[u8], bitstream: &[u8]) -> Option<[u8; 10]> {
+    let (width, height, alpha) = match kind {
+        b"VP8 " => {
+            let dimensions = bitstream.get(6..10)?;
+            let width = u16::from_le_bytes([dimensions[0], dimensions[1]]) & 0x3FFF;
+            let height = u16::from_le_bytes([dimensions[2], dimensions[3]]) & 0x3FFF;
+            (width as u32, height as u32, false)
+        }
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(bitstream.get(1..5)?.try_into().ok()?);
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1, bits >> 28 & 1 == 1)
+        }
+        _ => return None,
+    };
+    let mut header = [0; 10];
+    header[0] = WEBP_ICC_FLAG | if alpha { WEBP_ALPHA_FLAG } else { 0 };
+    header[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+    header[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+    Some(header)
+}
+
+fn embed_webp(data: &[u8], profile: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let kind = &data[offset..offset + 4];
+        let length = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().ok()?) as usize;
+        let end = (offset + 8).checked_add(length).filter(|&end| end <= data.len())?;
+        chunks.push((kind, &data[offset + 8..end]));
+        offset = end + length % 2;
+    }
+
+    let mut body = b"WEBP".to_vec();
+    match chunks.first() {
+        Some((b"VP8X", header)) => {
+            let mut header = header.to_vec();
+            *header.first_mut()? |= WEBP_ICC_FLAG;
+            body.extend(riff_chunk(b"VP8X", &header));
+            //ICCP has to come right after VP8X.
+            body.extend(riff_chunk(b"ICCP", profile));
+        }
+        Some((kind, bitstream)) => {
+            body.extend(riff_chunk(b"VP8X", &webp_extended_header(kind, bitstream)?));
+            body.extend(riff_chunk(b"ICCP", profile));
+            body.extend(riff_chunk(kind, bitstream));
+        }
+        None => return None,
+    }
+    for (kind, chunk_data) in chunks.iter().skip(1) {
+        if *kind != b"ICCP" {
+            body.extend(riff_chunk(kind, chunk_data));
+        }
+    }
+
+    let mut embedded = b"RIFF".to_vec();
+    embedded.extend_from_slice(&u32::try_from(body.len()).ok()?.to_le_bytes());
+    embedded.extend(body);
+    Some(embedded)
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(x: f64, y: f64, z: f64) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in [x, y, z] {
+        tag.extend_from_slice(&s15_fixed16(value));
+    }
+    tag
+}
+
+//The sRGB transfer function sampled into a curve, which every ICC version understands.
+fn srgb_curve() -> Vec<u8> {
+    const SAMPLES: u32 = 1024;
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend_from_slice(&SAMPLES.to_be_bytes());
+    for sample in 0..SAMPLES {
+        let encoded = sample as f64 / (SAMPLES - 1) as f64;
+        let linear = if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        };
+        tag.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+fn text_description(description: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(description.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(description.as_bytes());
+    tag.push(0);
+    //Empty Unicode and ScriptCode descriptions.
+    tag.extend_from_slice(&[0; 8]);
+    tag.extend_from_slice(&[0; 3]);
+    tag.extend_from_slice(&[0; 67]);
+    tag
+}
+
+//A version 2 display profile for sRGB, with the primaries adapted to the D50 connection space.
+fn srgb_profile() -> Vec<u8> {
+    let mut copyright = b"text\0\0\0\0No copyright, use freely".to_vec();
+    copyright.push(0);
+    let curve = srgb_curve();
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", text_description("sRGB")),
+        (b"cprt", copyright),
+        (b"wtpt", xyz_tag(0.9642, 1.0, 0.8249)),
+        (b"rXYZ", xyz_tag(0.4360747, 0.2225045, 0.0139322)),
+        (b"gXYZ", xyz_tag(0.3850649, 0.7168786, 0.0971045)),
+        (b"bXYZ", xyz_tag(0.1430804, 0.0606169, 0.7141733)),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut tag_data = Vec::new();
+    let data_offset = 128 + 4 + 12 * tags.len();
+    for (signature, data) in &tags {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((data_offset + tag_data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        tag_data.extend_from_slice(data);
+        tag_data.resize(tag_data.len().next_multiple_of(4), 0);
+    }
+
+    let size = (data_offset + tag_data.len()) as u32;
+    let mut profile = Vec::with_capacity(size as usize);
+    profile.extend_from_slice(&size.to_be_bytes());
+    //Preferred CMM, then version 2.1.
+    profile.extend_from_slice(&[0; 4]);
+    profile.extend_from_slice(&[2, 0x10, 0, 0]);
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    //Creation date, 2024-01-01 00:00:00.
+    for value in [2024u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&value.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acsp");
+    //Platform, flags, manufacturer, model, attributes and rendering intent.
+    profile.extend_from_slice(&[0; 28]);
+    profile.extend_from_slice(&s15_fixed16(0.9642));
+    profile.extend_from_slice(&s15_fixed16(1.0));
+    profile.extend_from_slice(&s15_fixed16(0.8249));
+    //Creator, profile ID and reserved bytes.
+    profile.resize(128, 0);
+    profile.extend(table);
+    profile.extend(tag_data);
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegDecoder, ImageDecoder, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn jpeg_profile_follows_the_jfif_segment() {
+        let mut jpeg = Cursor::new(Vec::new());
+        RgbImage::new(8, 8)
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        assert_eq!(jpeg[2..4], [0xFF, 0xE0]);
+        let profile = srgb_profile();
+
+        let embedded = embed_jpeg(&jpeg, &profile).unwrap();
+        let app0_end = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+        assert_eq!(embedded[..app0_end], jpeg[..app0_end]);
+        assert_eq!(embedded[app0_end..app0_end + 2], [0xFF, 0xE2]);
+        let mut decoder = JpegDecoder::new(Cursor::new(embedded)).unwrap();
+        assert_eq!(decoder.icc_profile().unwrap(), Some(profile));
+    }
+}
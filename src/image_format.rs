@@ -13,6 +13,15 @@ impl ImageFormat {
     pub const HDR: ImageFormat = ImageFormat(image::ImageFormat::Hdr);
     pub const AVIF: ImageFormat = ImageFormat(image::ImageFormat::Avif);
 
+    //Every format the server encodes.
+    pub const ALL: [ImageFormat; 5] = [
+        Self::PNG,
+        Self::JPG,
+        Self::WEBP,
+        Self::HDR,
+        Self::AVIF,
+    ];
+
     const PNG_EXT : &'static str = "png";
     const JPG_EXT : &'static str = "jpg";
     const JPEG_EXT : &'static str = "jpeg";
@@ -21,6 +30,7 @@ impl ImageFormat {
     const AVIF_EXT : &'static str = "avif";
     const UNWN_EXT : &'static str = "unkw";
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<ImageFormat> {
         match s {
             Self::PNG_EXT => Some(ImageFormat(InnerImageFormat::Png)),
@@ -32,6 +42,18 @@ impl ImageFormat {
         }
     }
 
+    //None for formats the server can decode but doesn't serve.
+    pub fn from_image_format(format: InnerImageFormat) -> Option<ImageFormat> {
+        match format {
+            InnerImageFormat::Png
+            | InnerImageFormat::Jpeg
+            | InnerImageFormat::WebP
+            | InnerImageFormat::Hdr
+            | InnerImageFormat::Avif => Some(ImageFormat(format)),
+            _ => None,
+        }
+    }
+
     pub fn to_str(self) -> &'static str {
         match self.0 {
             InnerImageFormat::Png => Self::PNG_EXT,
@@ -0,0 +1,84 @@
+use std::{io::Cursor, path::Path};
+
+use image::ImageReader;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    database::{Database, NewSource, Tenant},
+    transcode,
+};
+
+#[derive(Default)]
+struct ImportSummary {
+    imported: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+enum Imported {
+    New,
+    AlreadyImported,
+}
+
+//Imports every file directly inside `dir` for the default tenant, the files themselves are left in place.
+//Running it again only imports files whose content wasn't imported before.
+pub async fn import_dir(database: &Database, dir: &Path) -> Result<(), std::io::Error> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut paths = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    let tenant = Tenant::default();
+    let mut summary = ImportSummary::default();
+    for path in paths {
+        match import_file(database, &tenant, &path).await {
+            Ok(Imported::New) => summary.imported += 1,
+            Ok(Imported::AlreadyImported) => summary.skipped += 1,
+            Err(e) => {
+                warn!("Could not import {}: {e}", path.display());
+                summary.failed += 1;
+            }
+        }
+    }
+
+    info!(
+        imported = summary.imported,
+        skipped = summary.skipped,
+        failed = summary.failed,
+        "Finished importing {}",
+        dir.display()
+    );
+    Ok(())
+}
+
+async fn import_file(
+    database: &Database,
+    tenant: &Tenant,
+    path: &Path,
+) -> Result<Imported, Box<dyn std::error::Error>> {
+    let data = tokio::fs::read(path).await?;
+    let content_hash = format!("{:x}", Sha256::digest(&data));
+    if let Some(image_id) = database.find_by_content_hash(tenant, &content_hash).await? {
+        info!("{} was already imported as {image_id}", path.display());
+        return Ok(Imported::AlreadyImported);
+    }
+
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(transcode::decode_limits());
+    let format = reader.format().ok_or("unrecognized image format")?;
+    let store_as = database.store_format(None, format);
+
+    let image_id = database
+        .save_image(tenant, reader, store_as, NewSource::default(), None, true)
+        .await?;
+    database
+        .set_content_hash(tenant, &image_id, &content_hash)
+        .await?;
+    info!("Imported {} as {image_id}", path.display());
+    Ok(Imported::New)
+}
@@ -0,0 +1,66 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, GrayImage, ImageError, Limits, RgbImage,
+};
+use mozjpeg::ColorSpace;
+
+fn decoding_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Exact(image::ImageFormat::Jpeg),
+        e,
+    ))
+}
+
+//libjpeg scales by n/8, the smallest n that still leaves twice the target for the final resize.
+fn scale_numerator(width: u32, height: u32, target_width: u32, target_height: u32) -> Option<u8> {
+    let scaled = |size: u32, numerator: u32| (size * numerator).div_ceil(8);
+    (1..8u8).find(|&numerator| {
+        scaled(width, numerator.into()) >= target_width * 2
+            && scaled(height, numerator.into()) >= target_height * 2
+    })
+}
+
+//Decodes straight from the DCT coefficients at a reduced size, skipping most of the work of a full decode.
+//None when the target is too close to the source size to gain anything, or for CMYK images.
+//The source's dimensions are held to `limits` like a full decode, the allocation to the scaled buffer.
+pub fn decode_scaled(
+    data: &[u8],
+    mut limits: Limits,
+    target: impl FnOnce(u32, u32) -> (u32, u32),
+) -> Result<Option<DynamicImage>, ImageError> {
+    //mozjpeg reports its errors by panicking.
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut decompress = mozjpeg::Decompress::new_mem(data).map_err(decoding_error)?;
+        let (width, height) = (decompress.width() as u32, decompress.height() as u32);
+        limits.check_dimensions(width, height)?;
+        let (target_width, target_height) = target(width, height);
+        let Some(numerator) = scale_numerator(width, height, target_width, target_height) else {
+            return Ok(None);
+        };
+        decompress.scale(numerator);
+
+        let image = match decompress.color_space() {
+            ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK => return Ok(None),
+            ColorSpace::JCS_GRAYSCALE => {
+                let mut started = decompress.grayscale().map_err(decoding_error)?;
+                let (width, height) = (started.width() as u32, started.height() as u32);
+                limits.reserve(width as u64 * height as u64)?;
+                let pixels = started.read_scanlines::<u8>().map_err(decoding_error)?;
+                started.finish().map_err(decoding_error)?;
+                GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+            }
+            _ => {
+                let mut started = decompress.rgb().map_err(decoding_error)?;
+                let (width, height) = (started.width() as u32, started.height() as u32);
+                limits.reserve(width as u64 * height as u64 * 3)?;
+                let pixels = started.read_scanlines::<u8>().map_err(decoding_error)?;
+                started.finish().map_err(decoding_error)?;
+                RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+            }
+        };
+        Ok(image)
+    }))
+    .map_err(|_| decoding_error("mozjpeg failed"))?
+}
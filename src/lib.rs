@@ -1,52 +1,326 @@
-use axum::{extract::DefaultBodyLimit, response::Html, routing::get, Router};
+use api::FallbackImage;
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Request},
+    http::StatusCode,
+    middleware,
+    response::Html,
+    routing::get,
+    Router,
+};
+use client_ip::ClientIp;
 use chrono::Duration;
-use database::Database;
+use database::{Database, DatabaseInitError, PendingWork};
+use serde::{Serialize, Serializer};
+use tokio_rustls::TlsAcceptor;
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{debug_span, info, warn, Span};
 
 use std::{error::Error, net::SocketAddr, path::PathBuf};
 
 mod api;
+#[cfg(feature = "client")]
+pub mod client;
+mod client_ip;
+mod contact_sheet;
 pub mod database;
+mod decode_cache;
+mod density;
+mod histogram;
+mod icc;
 mod transcode;
 mod image_format;
+mod import;
+#[cfg(feature = "jpeg-scaling")]
+mod jpeg_scaling;
+#[cfg(feature = "optimize")]
+mod optimize;
+mod pipeline;
+#[cfg(feature = "remote-upload")]
+mod remote;
+mod server;
+pub mod short_id;
+mod svg;
+mod transform_limit;
+#[cfg(feature = "webhooks")]
+mod webhook;
+mod zip;
 
+pub use image_format::ImageFormat;
+pub use pipeline::{Op, Pipeline};
+pub use transcode::{
+    AnimationPolicy, ChromaSubsampling, ColorSpace, Sharpen, TranscodeTarget, TranscodeTargetBuilder,
+    TranscodeTargetError, Watermark, WatermarkPosition,
+};
+
+//Serializes to the effective configuration reported at /api/admin/config, secrets are redacted or left out.
+#[derive(Clone, Serialize)]
 pub struct Config {
     pub max_image_width: Option<u32>,
     pub max_image_height: Option<u32>,
     pub max_image_size: Option<usize>,
+    pub max_batch_size: Option<usize>,
     pub max_memory_usage: Option<u32>,
     pub backend_port: u16,
+    #[serde(serialize_with = "serialize_redacted_url")]
     pub database_url: String,
     pub image_path : PathBuf,
+    #[serde(serialize_with = "serialize_secs")]
     pub image_ttl : Option<Duration>,
+    pub max_image_count: Option<u64>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub soft_delete: Option<Duration>,
+    pub fallback_image_path: Option<PathBuf>,
+    pub fallback_image_status: u16,
+    pub index_html_path: Option<PathBuf>,
+    pub import_dir: Option<PathBuf>,
+    pub validate_raw: bool,
+    pub max_concurrent_transcodes: Option<usize>,
+    pub max_decode_cache_bytes: Option<usize>,
+    pub pre_downscale_ratio: Option<f32>,
+    pub min_resize_edge: Option<u32>,
+    pub max_transforms_per_image: Option<usize>,
+    pub transform_window_secs: u64,
+    pub max_background_db_tasks: Option<usize>,
+    pub max_stored_edge: Option<u32>,
+    pub max_megapixels: Option<f64>,
+    pub animation_policy: AnimationPolicy,
+    pub jpeg_quality: Option<u8>,
+    pub webp_quality: Option<u8>,
+    pub avif_quality: Option<u8>,
+    pub avif_speed: Option<u8>,
+    pub optimize_output: bool,
+    pub enable_http2: bool,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub read_only: bool,
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+    pub max_concurrent_uploads: Option<usize>,
+    pub short_ids: bool,
+    pub not_computed_status: u16,
+    pub trust_proxy: bool,
+    pub rate_limit: Option<u32>,
+    pub rate_limit_window_secs: u64,
+    pub negotiate_format: bool,
+    pub disable_unavailable_formats: bool,
+    pub keep_uploads: bool,
+    pub cache_max_age: Option<u64>,
+    pub preserve_icc: bool,
+    pub verify_on_read: bool,
+    pub discard_corrupted: bool,
+    pub auto_store_format: bool,
+    pub remote_uploads: bool,
+    pub remote_upload_hosts: Option<Vec<String>>,
+    pub remote_upload_allow_private: bool,
+    pub remote_upload_timeout_secs: u64,
+    #[serde(skip)]
+    pub webhook_url: Option<String>,
+    #[serde(skip)]
+    pub webhook_secret: Option<String>,
+}
+
+//Only the scheme, host and database are kept, credentials can sit in the user info as well as the query.
+fn serialize_redacted_url<S: Serializer>(url: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    let redacted = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let rest = rest.split('?').next().unwrap_or_default();
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            let host = authority.rsplit('@').next().unwrap_or_default();
+            format!("{scheme}://{host}{path}")
+        }
+        //Key-value connection strings can't be taken apart safely.
+        None => "<redacted>".to_string(),
+    };
+    serializer.serialize_str(&redacted)
+}
+
+fn serialize_secs<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    duration.map(|duration| duration.num_seconds()).serialize(serializer)
 }
 
 pub async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    if config.remote_uploads && !cfg!(feature = "remote-upload") {
+        return Err("REMOTE_UPLOADS requires building with --features remote-upload".into());
+    }
+    if config.webhook_url.is_some() && !cfg!(feature = "webhooks") {
+        return Err("WEBHOOK_URL requires building with --features webhooks".into());
+    }
+    if config.webhook_url.is_some() && config.webhook_secret.is_none() {
+        return Err("WEBHOOK_URL requires WEBHOOK_SECRET to sign the payloads".into());
+    }
+    transcode::init_pool(config.max_concurrent_transcodes)?;
+    transcode::init_decode_cache(config.max_decode_cache_bytes);
+    transcode::init_decode_limits(
+        config.max_image_width,
+        config.max_image_height,
+        config.max_memory_usage.map(u64::from),
+    );
+    transcode::init_pre_downscale(config.pre_downscale_ratio)?;
+    transcode::init_min_resize_edge(config.min_resize_edge)?;
+    transcode::init_transform_limit(config.max_transforms_per_image, config.transform_window_secs);
+    transcode::init_format_check(config.disable_unavailable_formats);
+    transcode::init_icc(config.preserve_icc);
+    transcode::init_verify_on_read(config.verify_on_read, config.discard_corrupted);
+    transcode::init_encoder_defaults(transcode::EncoderDefaults {
+        jpeg_quality: config.jpeg_quality,
+        webp_quality: config.webp_quality,
+        avif_quality: config.avif_quality,
+        avif_speed: config.avif_speed,
+        optimize_output: config.optimize_output,
+    })?;
+
     let database = get_database(&config).await?;
-    let app = get_router(&config, database);
+    if let Some(import_dir) = &config.import_dir {
+        import::import_dir(&database, import_dir).await?;
+    }
+    let pending_work = database.pending_work();
+    let fallback_image = get_fallback_image(&config).await?;
+    let index_page = get_index(&config).await?;
+    StatusCode::from_u16(config.not_computed_status)?;
+    let app = get_router(&config, database, fallback_image, index_page);
+    let tls_acceptor = get_tls_acceptor(&config)?;
     let listener = get_listener(&config).await?;
 
-    info!("Running server on: 127.0.0.1:{}", config.backend_port);
-    axum::serve(listener, app).await?;
+    let scheme = if tls_acceptor.is_some() { "https" } else { "http" };
+    info!("Running server on: {scheme}://127.0.0.1:{}", config.backend_port);
+    server::serve(
+        listener,
+        app,
+        config.enable_http2,
+        tls_acceptor,
+        server::shutdown_signal(),
+    )
+    .await;
+
+    log_pending_work(&pending_work).await;
     Ok(())
 }
 
-async fn get_database(config: &Config) -> Result<Database, Box<dyn Error>> {
+//Anything counted here may be lost when the process exits, operators use it to judge what to re-upload.
+async fn log_pending_work(pending_work: &PendingWork) {
+    let queued_messages = pending_work.queued_messages();
+    let pending_transcodes = transcode::pending_transcodes();
+    match pending_work.uncomputed_images().await {
+        Ok(uncomputed_images) => info!(
+            queued_messages,
+            uncomputed_images,
+            pending_transcodes,
+            "Shutting down with pending work"
+        ),
+        Err(e) => {
+            warn!("Could not count uncomputed images on shutdown: {e:?}");
+            info!(
+                queued_messages,
+                pending_transcodes,
+                "Shutting down with pending work"
+            );
+        }
+    }
+}
+
+fn get_tls_acceptor(config: &Config) -> Result<Option<TlsAcceptor>, Box<dyn Error>> {
+    match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Ok(Some(server::load_tls_acceptor(
+            cert_path,
+            key_path,
+            config.enable_http2,
+        )?)),
+        (None, None) => Ok(None),
+        _ => Err("both 'TLS_CERT_PATH' and 'TLS_KEY_PATH' must be set to enable TLS".into()),
+    }
+}
+
+async fn get_database(config: &Config) -> Result<Database, DatabaseInitError> {
     database::Database::new(config).await
 }
 
-fn get_router(config: &Config, database: Database) -> Router {
-    let body_limit = match config.max_image_size {
+async fn get_fallback_image(config: &Config) -> Result<Option<FallbackImage>, Box<dyn Error>> {
+    match &config.fallback_image_path {
+        Some(path) => Ok(Some(
+            FallbackImage::load(path, config.fallback_image_status).await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+//A runtime file wins over the page embedded with the `test-ui` feature, without either `/` is a 404.
+async fn get_index(config: &Config) -> Result<Option<Bytes>, Box<dyn Error>> {
+    match &config.index_html_path {
+        Some(path) => {
+            let page = tokio::fs::read(path)
+                .await
+                .map_err(|e| format!("could not read index page {}: {e}", path.display()))?;
+            Ok(Some(Bytes::from(page)))
+        }
+        None => Ok(embedded_index()),
+    }
+}
+
+#[cfg(feature = "test-ui")]
+fn embedded_index() -> Option<Bytes> {
+    Some(Bytes::from_static(
+        std::include_str!("../public/index.html").as_bytes(),
+    ))
+}
+
+#[cfg(not(feature = "test-ui"))]
+fn embedded_index() -> Option<Bytes> {
+    None
+}
+
+fn get_router(
+    config: &Config,
+    database: Database,
+    fallback_image: Option<FallbackImage>,
+    index_page: Option<Bytes>,
+) -> Router {
+    let body_limit = |limit: Option<usize>| match limit {
         Some(limit) => DefaultBodyLimit::max(limit),
         None => DefaultBodyLimit::disable(),
     };
+    let upload_limit = body_limit(config.max_image_size);
+    //Unset, a batch may be as large as a single upload.
+    let batch_limit = body_limit(config.max_batch_size.or(config.max_image_size));
+
+    let router = Router::new().nest(
+        "/api",
+        api::router(
+            config,
+            &upload_limit,
+            &batch_limit,
+            database,
+            fallback_image,
+        ),
+    );
+    let router = match index_page {
+        Some(page) => router.route("/", get(move || index(page))),
+        None => router,
+    };
+    let trust_proxy = config.trust_proxy;
+    router
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(middleware::from_fn(move |request, next| {
+            client_ip::extract_client_ip(trust_proxy, request, next)
+        }))
+}
 
-    Router::new()
-        .nest("/api", api::router(&body_limit, database))
-        .route("/", get(index))
-        .layer(TraceLayer::new_for_http())
+//TraceLayer's default span with the client address added.
+fn request_span(request: &Request) -> Span {
+    let client_ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| ip.to_string())
+        .unwrap_or_default();
+    debug_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        client_ip,
+    )
 }
+
 async fn get_listener(config: &Config) -> tokio::io::Result<tokio::net::TcpListener> {
     let address = SocketAddr::from(([127, 0, 0, 1], config.backend_port));
 
@@ -54,6 +328,6 @@ async fn get_listener(config: &Config) -> tokio::io::Result<tokio::net::TcpListe
 }
 
 //Mainly for testing usage, provides visual gui for uploading file
-async fn index() -> Html<&'static str> {
-    Html(std::include_str!("../public/index.html"))
+async fn index(page: Bytes) -> Html<Bytes> {
+    Html(page)
 }
@@ -1,7 +1,7 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{num::NonZeroUsize, path::PathBuf, str::FromStr};
 
 use chrono::Duration;
-use image_server::Config;
+use image_server::{AnimationPolicy, Config};
 use tracing::warn;
 use tracing_subscriber::FmtSubscriber;
 
@@ -20,7 +20,7 @@ async fn main() {
     let config = get_config();
 
     if let Err(e) = image_server::run(config).await {
-        warn!("Some error occured running the server: {e:?}");
+        warn!("Some error occured running the server: {e}");
     }
 }
 
@@ -48,6 +48,13 @@ fn get_config() -> Config {
                 .expect("invalid format of 'max_image_size, please provide u32'")
         })
         .ok();
+    let max_batch_size = env::var("MAX_BATCH_SIZE")
+        .map(|string| {
+            string
+                .parse::<usize>()
+                .expect("invalid format of 'MAX_BATCH_SIZE', please provide usize")
+        })
+        .ok();
     let max_memory_usage = env::var("MAX_MEMORY_USAGE")
         .map(|string| {
             string
@@ -78,14 +85,376 @@ fn get_config() -> Config {
         Duration::seconds(seconds)
     }).ok();
 
+    let soft_delete = env::var("SOFT_DELETE_SECS")
+        .map(|string| {
+            let seconds = string
+                .parse::<u32>()
+                .expect("invalid format of 'SOFT_DELETE_SECS', please provide u32");
+            Duration::seconds(seconds.into())
+        })
+        .ok();
+
+    let max_image_count = env::var("MAX_IMAGE_COUNT")
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .expect("invalid format of 'MAX_IMAGE_COUNT', please provide u64")
+        })
+        .ok();
+
+    let fallback_image_path = env::var("FALLBACK_IMAGE_PATH").map(PathBuf::from).ok();
+
+    let fallback_image_status = env::var("FALLBACK_IMAGE_STATUS")
+        .map(|string| {
+            string
+                .parse::<u16>()
+                .expect("invalid format of 'FALLBACK_IMAGE_STATUS', please provide u16")
+        })
+        .unwrap_or(200u16);
+
+    let not_computed_status = env::var("NOT_COMPUTED_STATUS")
+        .map(|string| {
+            string
+                .parse::<u16>()
+                .expect("invalid format of 'NOT_COMPUTED_STATUS', please provide u16")
+        })
+        .unwrap_or(404u16);
+
+    let index_html_path = env::var("INDEX_HTML_PATH").map(PathBuf::from).ok();
+
+    let import_dir = env::var("IMPORT_DIR").map(PathBuf::from).ok();
+
+    let validate_raw = env::var("VALIDATE_RAW")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'VALIDATE_RAW', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let max_concurrent_transcodes = env::var("MAX_CONCURRENT_TRANSCODES")
+        .map(|string| {
+            string
+                .parse::<usize>()
+                .expect("invalid format of 'MAX_CONCURRENT_TRANSCODES', please provide usize")
+        })
+        .ok();
+
+    let max_decode_cache_bytes = env::var("MAX_DECODE_CACHE_BYTES")
+        .map(|string| {
+            string
+                .parse::<usize>()
+                .expect("invalid format of 'MAX_DECODE_CACHE_BYTES', please provide usize")
+        })
+        .ok();
+
+    let pre_downscale_ratio = env::var("PRE_DOWNSCALE_RATIO")
+        .map(|string| {
+            string
+                .parse::<f32>()
+                .expect("invalid format of 'PRE_DOWNSCALE_RATIO', please provide f32")
+        })
+        .ok();
+    let min_resize_edge = env::var("MIN_RESIZE_EDGE")
+        .map(|string| {
+            string
+                .parse::<u32>()
+                .expect("invalid format of 'MIN_RESIZE_EDGE', please provide u32")
+        })
+        .ok();
+
+    let max_transforms_per_image = env::var("MAX_TRANSFORMS_PER_IMAGE")
+        .map(|string| {
+            string
+                .parse::<usize>()
+                .expect("invalid format of 'MAX_TRANSFORMS_PER_IMAGE', please provide usize")
+        })
+        .ok();
+    let transform_window_secs = env::var("TRANSFORM_WINDOW_SECS")
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .expect("invalid format of 'TRANSFORM_WINDOW_SECS', please provide u64")
+        })
+        .unwrap_or(60);
+
+    let max_background_db_tasks = env::var("MAX_BACKGROUND_DB_TASKS")
+        .map(|string| {
+            string
+                .parse::<NonZeroUsize>()
+                .expect("invalid format of 'MAX_BACKGROUND_DB_TASKS', please provide a usize above 0")
+                .get()
+        })
+        .ok();
+
+    let max_concurrent_uploads = env::var("MAX_CONCURRENT_UPLOADS")
+        .map(|string| {
+            string
+                .parse::<usize>()
+                .expect("invalid format of 'MAX_CONCURRENT_UPLOADS', please provide usize")
+        })
+        .ok();
+
+    let max_stored_edge = env::var("MAX_STORED_EDGE")
+        .map(|string| {
+            string
+                .parse::<u32>()
+                .expect("invalid format of 'MAX_STORED_EDGE', please provide u32")
+        })
+        .ok();
+
+    let max_megapixels = env::var("MAX_MEGAPIXELS")
+        .map(|string| {
+            string
+                .parse::<f64>()
+                .expect("invalid format of 'MAX_MEGAPIXELS', please provide f64")
+        })
+        .ok();
+
+    let animation_policy = env::var("ANIMATION_POLICY")
+        .map(|string| {
+            string.parse::<AnimationPolicy>().expect(
+                "invalid format of 'ANIMATION_POLICY', please provide first_frame, last_frame or error",
+            )
+        })
+        .unwrap_or_default();
+
+    let jpeg_quality = env::var("JPEG_QUALITY")
+        .map(|string| {
+            string
+                .parse::<u8>()
+                .expect("invalid format of 'JPEG_QUALITY', please provide u8")
+        })
+        .ok();
+    let webp_quality = env::var("WEBP_QUALITY")
+        .map(|string| {
+            string
+                .parse::<u8>()
+                .expect("invalid format of 'WEBP_QUALITY', please provide u8")
+        })
+        .ok();
+    let avif_quality = env::var("AVIF_QUALITY")
+        .map(|string| {
+            string
+                .parse::<u8>()
+                .expect("invalid format of 'AVIF_QUALITY', please provide u8")
+        })
+        .ok();
+    let avif_speed = env::var("AVIF_SPEED")
+        .map(|string| {
+            string
+                .parse::<u8>()
+                .expect("invalid format of 'AVIF_SPEED', please provide u8")
+        })
+        .ok();
+
+    let optimize_output = env::var("OPTIMIZE_OUTPUT")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'OPTIMIZE_OUTPUT', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let enable_http2 = env::var("ENABLE_HTTP2")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'ENABLE_HTTP2', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let tls_cert_path = env::var("TLS_CERT_PATH").map(PathBuf::from).ok();
+    let tls_key_path = env::var("TLS_KEY_PATH").map(PathBuf::from).ok();
+
+    let read_only = env::var("READ_ONLY")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'READ_ONLY', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let short_ids = env::var("SHORT_IDS")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'SHORT_IDS', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let trust_proxy = env::var("TRUST_PROXY")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'TRUST_PROXY', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let rate_limit = env::var("RATE_LIMIT")
+        .map(|string| {
+            string
+                .parse::<u32>()
+                .expect("invalid format of 'RATE_LIMIT', please provide u32")
+        })
+        .ok();
+
+    let rate_limit_window_secs = env::var("RATE_LIMIT_WINDOW_SECS")
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .expect("invalid format of 'RATE_LIMIT_WINDOW_SECS', please provide u64")
+        })
+        .unwrap_or(60);
+
+    let negotiate_format = env::var("NEGOTIATE_FORMAT")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'NEGOTIATE_FORMAT', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let disable_unavailable_formats = env::var("DISABLE_UNAVAILABLE_FORMATS")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'DISABLE_UNAVAILABLE_FORMATS', please provide true or false")
+        })
+        .unwrap_or(false);
+    let cache_max_age = env::var("CACHE_MAX_AGE_SECS")
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .expect("invalid format of 'CACHE_MAX_AGE_SECS', please provide u64")
+        })
+        .ok();
+    let preserve_icc = env::var("PRESERVE_ICC")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'PRESERVE_ICC', please provide true or false")
+        })
+        .unwrap_or(false);
+    let verify_on_read = env::var("VERIFY_ON_READ")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'VERIFY_ON_READ', please provide true or false")
+        })
+        .unwrap_or(false);
+    let discard_corrupted = env::var("DISCARD_CORRUPTED")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'DISCARD_CORRUPTED', please provide true or false")
+        })
+        .unwrap_or(false);
+    let auto_store_format = env::var("AUTO_STORE_FORMAT")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'AUTO_STORE_FORMAT', please provide true or false")
+        })
+        .unwrap_or(false);
+    let remote_uploads = env::var("REMOTE_UPLOADS")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'REMOTE_UPLOADS', please provide true or false")
+        })
+        .unwrap_or(false);
+    let remote_upload_hosts = env::var("REMOTE_UPLOAD_HOSTS")
+        .map(|string| {
+            string
+                .split(',')
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .ok();
+    let remote_upload_allow_private = env::var("REMOTE_UPLOAD_ALLOW_PRIVATE")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'REMOTE_UPLOAD_ALLOW_PRIVATE', please provide true or false")
+        })
+        .unwrap_or(false);
+    let remote_upload_timeout_secs = env::var("REMOTE_UPLOAD_TIMEOUT_SECS")
+        .map(|string| {
+            string
+                .parse::<u64>()
+                .expect("invalid format of 'REMOTE_UPLOAD_TIMEOUT_SECS', please provide u64")
+        })
+        .unwrap_or(10);
+    let keep_uploads = env::var("KEEP_UPLOADS")
+        .map(|string| {
+            string
+                .parse::<bool>()
+                .expect("invalid format of 'KEEP_UPLOADS', please provide true or false")
+        })
+        .unwrap_or(false);
+
+    let admin_token = env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    let webhook_url = env::var("WEBHOOK_URL").ok().filter(|url| !url.is_empty());
+    let webhook_secret = env::var("WEBHOOK_SECRET").ok().filter(|secret| !secret.is_empty());
+
     Config {
         max_image_width,
         max_image_height,
         max_image_size,
+        max_batch_size,
         max_memory_usage,
         backend_port,
         database_url,
         image_path,
         image_ttl,
+        max_image_count,
+        soft_delete,
+        fallback_image_path,
+        fallback_image_status,
+        index_html_path,
+        import_dir,
+        validate_raw,
+        max_concurrent_transcodes,
+        max_decode_cache_bytes,
+        pre_downscale_ratio,
+        min_resize_edge,
+        max_transforms_per_image,
+        transform_window_secs,
+        max_background_db_tasks,
+        max_stored_edge,
+        max_megapixels,
+        animation_policy,
+        jpeg_quality,
+        webp_quality,
+        avif_quality,
+        avif_speed,
+        optimize_output,
+        enable_http2,
+        tls_cert_path,
+        tls_key_path,
+        read_only,
+        admin_token,
+        max_concurrent_uploads,
+        short_ids,
+        not_computed_status,
+        trust_proxy,
+        rate_limit,
+        rate_limit_window_secs,
+        negotiate_format,
+        disable_unavailable_formats,
+        keep_uploads,
+        cache_max_age,
+        preserve_icc,
+        verify_on_read,
+        discard_corrupted,
+        auto_store_format,
+        remote_uploads,
+        remote_upload_hosts,
+        remote_upload_allow_private,
+        remote_upload_timeout_secs,
+        webhook_url,
+        webhook_secret,
     }
 }
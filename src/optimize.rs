@@ -0,0 +1,59 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use image::{
+    error::{EncodingError, ImageFormatHint},
+    DynamicImage, ImageError,
+};
+
+use crate::transcode::ChromaSubsampling;
+
+fn encoding_error(
+    image_format: image::ImageFormat,
+    e: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> ImageError {
+    ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(image_format), e))
+}
+
+//Lossless, the optimized file decodes to exactly the same pixels.
+pub fn optimize_png(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    oxipng::optimize_from_memory(data, &oxipng::Options::default())
+        .map_err(|e| encoding_error(image::ImageFormat::Png, e.to_string()))
+}
+
+//Progressive with optimized huffman tables and trellis quantization, mozjpeg's defaults.
+pub fn encode_jpeg(
+    image: &DynamicImage,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+) -> Result<Vec<u8>, ImageError> {
+    let (color_space, data) = match image {
+        DynamicImage::ImageLuma8(image) => (mozjpeg::ColorSpace::JCS_GRAYSCALE, image.as_raw()),
+        DynamicImage::ImageRgb8(image) => (mozjpeg::ColorSpace::JCS_RGB, image.as_raw()),
+        other => {
+            return Err(encoding_error(
+                image::ImageFormat::Jpeg,
+                format!("unsupported color type {:?}", other.color()),
+            ))
+        }
+    };
+    let chroma_pixel_size = match subsampling {
+        ChromaSubsampling::Full => (1, 1),
+        ChromaSubsampling::Half => (2, 1),
+        ChromaSubsampling::Quarter => (2, 2),
+    };
+
+    //mozjpeg reports its errors by panicking.
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut compress = mozjpeg::Compress::new(color_space);
+        compress.set_size(image.width() as usize, image.height() as usize);
+        compress.set_quality(quality as f32);
+        if color_space == mozjpeg::ColorSpace::JCS_RGB {
+            compress.set_chroma_sampling_pixel_sizes(chroma_pixel_size, chroma_pixel_size);
+        }
+        let mut compress = compress.start_compress(Vec::new())?;
+        compress.write_scanlines(data)?;
+        compress.finish()
+    }))
+    .map_err(|_| encoding_error(image::ImageFormat::Jpeg, "mozjpeg failed"))?
+    .map_err(|e| encoding_error(image::ImageFormat::Jpeg, e))
+}
@@ -0,0 +1,263 @@
+use std::{fmt, str::FromStr};
+
+use crate::image_format::ImageFormat;
+
+pub const MAX_PIPELINE_OPS: usize = 16;
+const MAX_BLUR_SIGMA: f32 = 50.0;
+
+//One step of a pipeline, applied to the output of the step before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    //Fits the image inside the box, keeping its aspect ratio.
+    Resize(u32, u32),
+    Scale(f32),
+    //Center crops to width over height.
+    Aspect(f32),
+    Grayscale,
+    Blur(f32),
+    Sharpen(f32, u8),
+    Rotate(u16),
+    FlipHorizontal,
+    FlipVertical,
+}
+
+//An ordered list of operations, stored inline so targets stay `Copy`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Pipeline {
+    ops: [Op; MAX_PIPELINE_OPS],
+    len: usize,
+    format: Option<ImageFormat>,
+}
+
+impl Pipeline {
+    //None for more than MAX_PIPELINE_OPS operations.
+    pub fn new(ops: &[Op], format: Option<ImageFormat>) -> Option<Pipeline> {
+        if ops.len() > MAX_PIPELINE_OPS {
+            return None;
+        }
+        let mut pipeline = Pipeline {
+            ops: [Op::Grayscale; MAX_PIPELINE_OPS],
+            len: ops.len(),
+            format,
+        };
+        pipeline.ops[..ops.len()].copy_from_slice(ops);
+        Some(pipeline)
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops[..self.len]
+    }
+
+    //Set with `format(...)`, it applies to the output rather than being a step of its own.
+    pub fn format(&self) -> Option<ImageFormat> {
+        self.format
+    }
+
+    pub fn validate(&self, max_sharpen_amount: f32) -> Result<(), String> {
+        for op in self.ops() {
+            match *op {
+                Op::Resize(width, height) if width == 0 || height == 0 => {
+                    return Err(format!("invalid resize: {width}x{height}"))
+                }
+                Op::Scale(scale) if !scale.is_finite() || scale <= 0.0 => {
+                    return Err(format!("invalid scale: {scale}"))
+                }
+                Op::Aspect(aspect) if !aspect.is_finite() || aspect <= 0.0 => {
+                    return Err(format!("invalid aspect: {aspect}"))
+                }
+                Op::Blur(sigma) if !(sigma > 0.0 && sigma <= MAX_BLUR_SIGMA) => {
+                    return Err(format!(
+                        "invalid blur: {sigma}, expected above 0 and at most {MAX_BLUR_SIGMA}"
+                    ))
+                }
+                Op::Sharpen(amount, _) if !(amount > 0.0 && amount <= max_sharpen_amount) => {
+                    return Err(format!(
+                        "invalid sharpen amount: {amount}, expected above 0 and at most {max_sharpen_amount}"
+                    ))
+                }
+                Op::Rotate(degrees) if !matches!(degrees, 90 | 180 | 270) => {
+                    return Err(format!("invalid rotation: {degrees}, expected 90, 180 or 270"))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("ops", &self.ops())
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+//The query syntax, e.g. `resize(200,200),grayscale,blur(2),format(webp)`.
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut steps: Vec<String> = self
+            .ops()
+            .iter()
+            .map(|op| match op {
+                Op::Resize(width, height) => format!("resize({width},{height})"),
+                Op::Scale(scale) => format!("scale({scale})"),
+                Op::Aspect(aspect) => format!("aspect({aspect})"),
+                Op::Grayscale => "grayscale".to_string(),
+                Op::Blur(sigma) => format!("blur({sigma})"),
+                Op::Sharpen(amount, threshold) => format!("sharpen({amount},{threshold})"),
+                Op::Rotate(degrees) => format!("rotate({degrees})"),
+                Op::FlipHorizontal => "fliph".to_string(),
+                Op::FlipVertical => "flipv".to_string(),
+            })
+            .collect();
+        if let Some(format) = self.format {
+            steps.push(format!("format({})", format.to_str()));
+        }
+        write!(f, "{}", steps.join(","))
+    }
+}
+
+//Splits on the commas between steps, leaving those between arguments alone.
+fn split_steps(s: &str) -> Result<Vec<&str>, String> {
+    let mut steps = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, character) in s.char_indices() {
+        match character {
+            '(' if depth == 0 => depth += 1,
+            '(' => return Err("nested parentheses are not allowed".to_string()),
+            ')' if depth == 1 => depth -= 1,
+            ')' => return Err("unbalanced parentheses".to_string()),
+            ',' if depth == 0 => {
+                steps.push(&s[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_string());
+    }
+    steps.push(&s[start..]);
+    Ok(steps)
+}
+
+fn number<T: FromStr>(step: &str, argument: &str) -> Result<T, String> {
+    argument
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid argument to {step}: {argument:?}"))
+}
+
+fn aspect(argument: &str) -> Result<f32, String> {
+    match argument.split_once(':') {
+        Some((width, height)) => Ok(number::<f32>("aspect", width)? / number::<f32>("aspect", height)?),
+        None => number("aspect", argument),
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ops = Vec::new();
+        let mut format = None;
+        for step in split_steps(s)? {
+            let step = step.trim();
+            let (name, arguments) = match step.split_once('(') {
+                Some((name, arguments)) => match arguments.strip_suffix(')') {
+                    Some(arguments) if arguments.trim().is_empty() => (name.trim(), Vec::new()),
+                    Some(arguments) => (name.trim(), arguments.split(',').collect::<Vec<&str>>()),
+                    None => return Err(format!("unexpected text after {step:?}")),
+                },
+                None => (step, Vec::new()),
+            };
+            let op = match (name, arguments.as_slice()) {
+                ("", _) => return Err("empty pipeline step".to_string()),
+                ("resize", [width, height]) => Op::Resize(number(name, width)?, number(name, height)?),
+                ("scale", [scale]) => Op::Scale(number(name, scale)?),
+                ("aspect", [ratio]) => Op::Aspect(aspect(ratio)?),
+                ("grayscale", []) => Op::Grayscale,
+                ("blur", [sigma]) => Op::Blur(number(name, sigma)?),
+                ("sharpen", [amount]) => Op::Sharpen(number(name, amount)?, 0),
+                ("sharpen", [amount, threshold]) => {
+                    Op::Sharpen(number(name, amount)?, number(name, threshold)?)
+                }
+                ("rotate", [degrees]) => Op::Rotate(number(name, degrees)?),
+                ("fliph", []) => Op::FlipHorizontal,
+                ("flipv", []) => Op::FlipVertical,
+                ("format", [image_format]) => {
+                    if format.is_some() {
+                        return Err("format can only be given once".to_string());
+                    }
+                    let image_format = image_format.trim();
+                    format = Some(
+                        ImageFormat::from_str(image_format)
+                            .ok_or_else(|| format!("unsupported image format: {image_format}"))?,
+                    );
+                    continue;
+                }
+                (
+                    "resize" | "scale" | "aspect" | "grayscale" | "blur" | "sharpen" | "rotate"
+                    | "fliph" | "flipv" | "format",
+                    arguments,
+                ) => {
+                    return Err(format!(
+                        "wrong number of arguments to {name}: {}",
+                        arguments.len()
+                    ))
+                }
+                (name, _) => return Err(format!("unknown pipeline step: {name}")),
+            };
+            ops.push(op);
+        }
+        Pipeline::new(&ops, format)
+            .ok_or_else(|| format!("pipeline exceeds {MAX_PIPELINE_OPS} steps"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_parse_in_order() {
+        let pipeline: Pipeline = "resize(200, 100), grayscale,blur(2),aspect(16:9),sharpen(1.5),format(webp),rotate(90)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            pipeline.ops(),
+            [
+                Op::Resize(200, 100),
+                Op::Grayscale,
+                Op::Blur(2.0),
+                Op::Aspect(16.0 / 9.0),
+                Op::Sharpen(1.5, 0),
+                Op::Rotate(90),
+            ]
+        );
+        assert_eq!(pipeline.format(), Some(ImageFormat::WEBP));
+        assert_eq!(pipeline.to_string().parse::<Pipeline>().unwrap(), pipeline);
+    }
+
+    #[test]
+    fn malformed_pipelines_are_refused() {
+        for pipeline in [
+            "",
+            "resize(200)",
+            "resize(200,100",
+            "blur((2))",
+            "grayscale(1)",
+            "explode",
+            "format(webp),format(png)",
+            "format(gif)",
+            "blur(two)",
+        ] {
+            assert!(pipeline.parse::<Pipeline>().is_err(), "{pipeline:?}");
+        }
+        let too_long = vec!["grayscale"; MAX_PIPELINE_OPS + 1].join(",");
+        assert!(too_long.parse::<Pipeline>().is_err());
+    }
+}
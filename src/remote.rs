@@ -0,0 +1,258 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use reqwest::{header, redirect, StatusCode, Url};
+
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    NotAllowed(String),
+    TooLarge,
+    TimedOut,
+    Failed(String),
+}
+
+pub struct Fetched {
+    pub data: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+//Fetches images for URL uploads. Every hop of a redirect is checked again and connected to the
+//addresses that were checked, so DNS can't point the request elsewhere in between.
+pub struct RemoteFetcher {
+    allowed_hosts: Option<Vec<String>>,
+    allow_private: bool,
+    timeout: Duration,
+    max_size: usize,
+}
+
+impl RemoteFetcher {
+    pub fn new(
+        allowed_hosts: Option<Vec<String>>,
+        allow_private: bool,
+        timeout: Duration,
+        max_size: usize,
+    ) -> RemoteFetcher {
+        RemoteFetcher {
+            allowed_hosts: allowed_hosts.map(|hosts| {
+                hosts.into_iter().map(|host| host.to_ascii_lowercase()).collect()
+            }),
+            allow_private,
+            timeout,
+            max_size,
+        }
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Fetched, FetchError> {
+        let url = Url::parse(url).map_err(|e| FetchError::InvalidUrl(format!("invalid url: {e}")))?;
+        tokio::time::timeout(self.timeout, self.fetch_following(url))
+            .await
+            .map_err(|_| FetchError::TimedOut)?
+    }
+
+    //Refuses a url that fetching it would refuse up front, before any work is set aside for it.
+    //Redirects can only be checked once they are followed.
+    pub async fn check_url(&self, url: &str) -> Result<(), FetchError> {
+        let url = Url::parse(url).map_err(|e| FetchError::InvalidUrl(format!("invalid url: {e}")))?;
+        tokio::time::timeout(self.timeout, self.check(&url))
+            .await
+            .map_err(|_| FetchError::TimedOut)?
+            .map(|_| ())
+    }
+
+    async fn fetch_following(&self, mut url: Url) -> Result<Fetched, FetchError> {
+        for _ in 0..=MAX_REDIRECTS {
+            let (host, addresses) = self.check(&url).await?;
+            //A proxy from the environment would connect to other addresses than the checked ones.
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .redirect(redirect::Policy::none())
+                .resolve_to_addrs(&host, &addresses)
+                .build()
+                .map_err(|e| FetchError::Failed(format!("could not build client: {e}")))?;
+            let mut response = client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|e| FetchError::Failed(format!("could not fetch {url}: {e}")))?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or_else(|| FetchError::Failed(format!("redirect from {url} without a location")))?;
+                url = url
+                    .join(location)
+                    .map_err(|e| FetchError::InvalidUrl(format!("invalid redirect location: {e}")))?;
+                continue;
+            }
+            if response.status() != StatusCode::OK {
+                return Err(FetchError::Failed(format!(
+                    "{url} answered {}",
+                    response.status()
+                )));
+            }
+            if response
+                .content_length()
+                .is_some_and(|length| length > self.max_size as u64)
+            {
+                return Err(FetchError::TooLarge);
+            }
+
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_string());
+            //Content-Length can be missing or wrong, the body is counted as it arrives.
+            let mut data = Vec::new();
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .map_err(|e| FetchError::Failed(format!("could not read {url}: {e}")))?
+            {
+                if data.len() + chunk.len() > self.max_size {
+                    return Err(FetchError::TooLarge);
+                }
+                data.extend_from_slice(&chunk);
+            }
+            return Ok(Fetched { data, content_type });
+        }
+        Err(FetchError::Failed(format!(
+            "more than {MAX_REDIRECTS} redirects"
+        )))
+    }
+
+    //The host with the addresses it resolves to, refused unless it is allowed and every address is public.
+    async fn check(&self, url: &Url) -> Result<(String, Vec<SocketAddr>), FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl(format!(
+                "unsupported scheme: {}",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| FetchError::InvalidUrl("url without a host".to_string()))?
+            .to_ascii_lowercase();
+        if let Some(allowed_hosts) = &self.allowed_hosts {
+            if !allowed_hosts.contains(&host) {
+                return Err(FetchError::NotAllowed(format!("host {host} is not allowed")));
+            }
+        }
+
+        let port = url.port_or_known_default().unwrap_or(80);
+        //IPv6 literals are bracketed in urls but not when resolving.
+        let lookup = host.trim_start_matches('[').trim_end_matches(']');
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((lookup, port))
+            .await
+            .map_err(|e| FetchError::Failed(format!("could not resolve {host}: {e}")))?
+            .collect();
+        if addresses.is_empty() {
+            return Err(FetchError::Failed(format!("{host} has no addresses")));
+        }
+        if !self.allow_private {
+            if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+                return Err(FetchError::NotAllowed(format!(
+                    "{host} resolves to the non-public address {}",
+                    address.ip()
+                )));
+            }
+        }
+        Ok((host, addresses))
+    }
+}
+
+//Loopback, private, link-local (cloud metadata services live there) and other reserved ranges aren't public.
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_v4(address),
+        IpAddr::V6(address) => match embedded_ipv4(address) {
+            Some(address) => is_public_v4(address),
+            None => is_public_v6(address),
+        },
+    }
+}
+
+//IPv6 addresses that reach an IPv4 address through a translator or tunnel are only as public as it is.
+fn embedded_ipv4(address: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = address.segments();
+    let [.., a, b, c, d] = address.octets();
+    match segments {
+        //IPv4-mapped, NAT64 (64:ff9b::/96) and the deprecated IPv4-compatible form, `::` and `::1` aside.
+        [0, 0, 0, 0, 0, 0xffff, ..] | [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(Ipv4Addr::new(a, b, c, d)),
+        [0, 0, 0, 0, 0, 0, high, low] if high != 0 || low > 1 => Some(Ipv4Addr::new(a, b, c, d)),
+        //6to4 (2002::/16) carries the address right after the prefix.
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        _ => None,
+    }
+}
+
+fn is_public_v4(address: Ipv4Addr) -> bool {
+    let [first, second, third, _] = address.octets();
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_private()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_multicast()
+        || address.is_documentation()
+        || first == 0
+        || first >= 240
+        //Shared address space for carrier-grade NAT.
+        || (first == 100 && (64..128).contains(&second))
+        || (first == 192 && second == 0 && third == 0)
+        //Benchmarking.
+        || (first == 198 && (18..20).contains(&second)))
+}
+
+fn is_public_v6(address: Ipv6Addr) -> bool {
+    let first = address.segments()[0];
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_multicast()
+        //Unique local.
+        || (first & 0xfe00) == 0xfc00
+        //Link-local.
+        || (first & 0xffc0) == 0xfe80
+        //Documentation.
+        || first == 0x2001 && address.segments()[1] == 0x0db8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(address: &str) -> bool {
+        is_public(address.parse().unwrap())
+    }
+
+    #[test]
+    fn embedded_private_addresses_are_refused() {
+        for address in [
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::10.0.0.1",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "::127.0.0.1",
+            "::192.168.1.1",
+            "::1",
+            "::",
+        ] {
+            assert!(!public(address), "{address}");
+        }
+    }
+
+    #[test]
+    fn embedded_public_addresses_are_allowed() {
+        for address in ["::ffff:8.8.8.8", "64:ff9b::8.8.8.8", "2002:808:808::1", "2606:4700::1111"] {
+            assert!(public(address), "{address}");
+        }
+    }
+}
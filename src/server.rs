@@ -0,0 +1,152 @@
+use std::{
+    error::Error, fs::File, future::Future, io::BufReader, net::SocketAddr, path::Path, sync::Arc,
+};
+
+use axum::{extract::ConnectInfo, middleware::AddExtension, Extension, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tower::Layer;
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    TlsAcceptor,
+};
+use tracing::{debug, warn};
+
+//Replaces axum::serve so HTTP/2 (h2c with prior knowledge) can be switched on and off.
+//Returns once `shutdown` resolves, new connections are no longer accepted from then on.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    enable_http2: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return,
+        };
+        let (stream, remote_address) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Could not accept connection: {e:?}");
+                continue;
+            }
+        };
+        //Wrapping the router leaves its routes as they are, `Router::layer` would rebuild all of them.
+        let app = Extension(ConnectInfo(remote_address)).layer(app.clone());
+        let service = TowerToHyperService::new(app);
+        let tls_acceptor = tls_acceptor.clone();
+
+        tokio::spawn(async move {
+            let result = match tls_acceptor {
+                Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, service, enable_http2).await,
+                    Err(e) => {
+                        debug!("TLS handshake with {remote_address} failed: {e:?}");
+                        return;
+                    }
+                },
+                None => serve_connection(stream, service, enable_http2).await,
+            };
+
+            if let Err(e) = result {
+                debug!("Connection with {remote_address} closed with error: {e:?}");
+            }
+        });
+    }
+}
+
+//Resolves on ctrl-c, or SIGTERM on unix.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Could not listen for ctrl-c: {e:?}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {e:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn serve_connection<I>(
+    stream: I,
+    service: TowerToHyperService<AddExtension<Router, ConnectInfo<SocketAddr>>>,
+    enable_http2: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = Builder::new(TokioExecutor::new());
+    let builder = if enable_http2 {
+        builder
+    } else {
+        builder.http1_only()
+    };
+
+    builder
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+}
+
+pub fn load_tls_acceptor(
+    cert_path: &Path,
+    key_path: &Path,
+    enable_http2: bool,
+) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let mut cert_reader = BufReader::new(File::open(cert_path).map_err(|e| {
+        format!("could not open TLS certificate {}: {e}", cert_path.display())
+    })?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid TLS certificate {}: {e}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_path.display()).into());
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path).map_err(|e| {
+        format!("could not open TLS key {}: {e}", key_path.display())
+    })?);
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|e| format!("invalid TLS key {}: {e}", key_path.display()))?
+        .ok_or_else(|| format!("no private key found in {}", key_path.display()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("TLS certificate and key do not match: {e}"))?;
+    config.alpn_protocols = if enable_http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,28 @@
+use uuid::Uuid;
+
+const ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+//62^22 is the smallest power that covers every u128, so short ids always have this length.
+const SHORT_ID_LENGTH: usize = 22;
+
+//Base62 form of the uuid, zero padded so ids sort and compare the same way as their uuids.
+pub fn encode(uuid: Uuid) -> String {
+    let mut value = uuid.as_u128();
+    let mut digits = [ALPHABET[0]; SHORT_ID_LENGTH];
+    for digit in digits.iter_mut().rev() {
+        *digit = ALPHABET[(value % 62) as usize];
+        value /= 62;
+    }
+    String::from_utf8(digits.to_vec()).unwrap()
+}
+
+pub fn decode(short_id: &str) -> Option<Uuid> {
+    if short_id.len() != SHORT_ID_LENGTH {
+        return None;
+    }
+    let value = short_id.bytes().try_fold(0u128, |value, byte| {
+        let digit = ALPHABET.iter().position(|&c| c == byte)? as u128;
+        value.checked_mul(62)?.checked_add(digit)
+    })?;
+    Some(Uuid::from_u128(value))
+}
@@ -0,0 +1,47 @@
+//The image crate can't decode SVG, so it is recognized by sniffing the start of the document.
+pub fn is_svg(data: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+#[cfg(feature = "svg")]
+const MAX_RASTER_EDGE: u32 = 8192;
+
+#[cfg(feature = "svg")]
+pub fn rasterize(data: &[u8], width: Option<u32>, height: Option<u32>) -> Result<Vec<u8>, String> {
+    use resvg::{tiny_skia, usvg};
+
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+        .map_err(|e| format!("could not parse svg: {e}"))?;
+    let size = tree.size();
+    let (width, height) = match (width, height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (
+            width,
+            (size.height() * width as f32 / size.width()).round() as u32,
+        ),
+        (None, Some(height)) => (
+            (size.width() * height as f32 / size.height()).round() as u32,
+            height,
+        ),
+        (None, None) => (size.width().ceil() as u32, size.height().ceil() as u32),
+    };
+    if width > MAX_RASTER_EDGE || height > MAX_RASTER_EDGE {
+        return Err(format!(
+            "svg raster size {width}x{height} exceeds {MAX_RASTER_EDGE}x{MAX_RASTER_EDGE}"
+        ));
+    }
+
+    let mut pixmap = tiny_skia::Pixmap::new(width.max(1), height.max(1))
+        .ok_or_else(|| format!("invalid svg raster size {width}x{height}"))?;
+    let transform = tiny_skia::Transform::from_scale(
+        pixmap.width() as f32 / size.width(),
+        pixmap.height() as f32 / size.height(),
+    );
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    pixmap
+        .encode_png()
+        .map_err(|e| format!("could not encode rasterized svg: {e}"))
+}
@@ -1,113 +1,2428 @@
-use std::io::Cursor;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufRead, BufReader, Cursor, Seek},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Instant,
+};
 
-use crate::database::Database;
+use crate::database::{checksum, variant_key, Database, GetImageError, ImagePath, Tenant, VariantClaim};
+use crate::decode_cache::DecodeCache;
+use crate::density;
+use crate::icc;
 use crate::image_format::ImageFormat;
+#[cfg(feature = "jpeg-scaling")]
+use crate::jpeg_scaling;
+#[cfg(feature = "optimize")]
+use crate::optimize;
+use crate::pipeline::{Op, Pipeline};
+use crate::transform_limit::TransformLimiter;
+use derive_more::derive::Display;
 use chrono::{Duration, Utc};
-use image::{DynamicImage, ImageError, ImageReader};
+use image::{
+    codecs::{
+        avif::AvifEncoder, gif::GifDecoder, jpeg::JpegEncoder, png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    error::{
+        EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind, UnsupportedError,
+        UnsupportedErrorKind,
+    },
+    imageops, AnimationDecoder, ColorType, DynamicImage, ImageDecoder, Frames, GenericImageView, ImageError, ImageReader,
+    Limits, Rgb, RgbImage, RgbaImage,
+};
+use jpeg_encoder::SamplingFactor;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
+use tokio::sync::oneshot::{self, error::RecvError};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+static TRANSCODE_POOL: OnceLock<ThreadPool> = OnceLock::new();
+static ENCODER_DEFAULTS: OnceLock<EncoderDefaults> = OnceLock::new();
+static WATERMARK_CACHE: OnceLock<Mutex<WatermarkCache>> = OnceLock::new();
+static DECODE_CACHE: OnceLock<Mutex<DecodeCache>> = OnceLock::new();
+static DECODE_LIMITS: OnceLock<Limits> = OnceLock::new();
+static PRE_DOWNSCALE_RATIO: OnceLock<f32> = OnceLock::new();
+static DISABLED_FORMATS: OnceLock<Vec<ImageFormat>> = OnceLock::new();
+static TRANSFORM_LIMITER: OnceLock<Mutex<TransformLimiter>> = OnceLock::new();
+static PRESERVE_ICC: OnceLock<bool> = OnceLock::new();
+static MIN_RESIZE_EDGE: OnceLock<u32> = OnceLock::new();
+//Set when reads are verified, holding whether corrupted variants are discarded.
+static VERIFY_ON_READ: OnceLock<bool> = OnceLock::new();
+static PENDING_TRANSCODES: AtomicUsize = AtomicUsize::new(0);
+static RECENT_TRANSCODE_TIMES: Mutex<VecDeque<std::time::Duration>> = Mutex::new(VecDeque::new());
+static RECENT_FORMAT_SAMPLES: Mutex<Vec<(ImageFormat, VecDeque<FormatSample>)>> = Mutex::new(Vec::new());
+
+type WatermarkCache = HashMap<(Tenant, Uuid), Arc<RgbaImage>>;
+
+const MAX_CACHED_WATERMARKS: usize = 32;
+//Waiting requests are woken by computed events, this only covers events that were missed.
+const TRANSCODE_WAIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+const TRANSCODE_WAIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const RECENT_TRANSCODE_SAMPLES: usize = 32;
+
+//Output size and time of a finished transcode, relative to the pixels involved.
+#[derive(Debug, Clone, Copy)]
+struct FormatSample {
+    bytes_per_pixel: f64,
+    //Per source and output pixel, decoding and resizing scale with the source.
+    secs_per_pixel: f64,
+}
+
+#[derive(Serialize)]
+pub struct TranscodeEstimate {
+    //None when the original's header can't be read, e.g. for AVIF originals.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: &'static str,
+    pub bytes: Option<u64>,
+    pub millis: Option<u64>,
+    //Recent transcodes to the format the estimate is based on, 0 means there is nothing to go on yet.
+    pub samples: usize,
+}
+
 #[derive(Debug)]
 pub enum TranscoderError {
     ImageError(ImageError),
     NotComputed,
     NotFound,
-    InternalServerError(Box<dyn std::error::Error>),
+    WatermarkNotFound,
+    TooManyTransforms,
+    //Only found out once the source's dimensions were known, e.g. a scale that leaves too large an image.
+    InvalidTarget(TranscodeTargetError),
+    InternalServerError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TranscodeTarget {
+    pub image_format: Option<ImageFormat>,
+    pub image_width: Option<u32>,
+    pub image_height: Option<u32>,
+    //Width over height, the image is center cropped to it before resizing.
+    pub aspect: Option<f32>,
+    pub scale: Option<f32>,
+    pub quality: Option<u8>,
+    pub speed: Option<u8>,
+    pub colorspace: Option<ColorSpace>,
+    pub bit_depth: Option<u8>,
+    pub subsampling: Option<ChromaSubsampling>,
+    pub sharpen: Option<Sharpen>,
+    pub watermark: Option<Watermark>,
+    //Replaces the geometry and sharpening above with steps applied in the given order.
+    pub pipeline: Option<Pipeline>,
+}
+
+//Parameters that are out of range or contradict each other, the message names the offending ones.
+#[derive(Debug, Display)]
+#[display("{_0}")]
+pub struct TranscodeTargetError(String);
+
+impl std::error::Error for TranscodeTargetError {}
+
+//Builds a target and checks it as a whole, so a constructed target is always one that can be transcoded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TranscodeTargetBuilder(TranscodeTarget);
+
+impl TranscodeTargetBuilder {
+    pub fn with_format(mut self, image_format: impl Into<Option<ImageFormat>>) -> Self {
+        self.0.image_format = image_format.into();
+        self
+    }
+
+    //Either may be left out to keep the aspect ratio.
+    pub fn with_size(mut self, width: Option<u32>, height: Option<u32>) -> Self {
+        self.0.image_width = width;
+        self.0.image_height = height;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: impl Into<Option<f32>>) -> Self {
+        self.0.scale = scale.into();
+        self
+    }
+
+    //Center crops to width over height before resizing.
+    pub fn with_crop(mut self, aspect: impl Into<Option<f32>>) -> Self {
+        self.0.aspect = aspect.into();
+        self
+    }
+
+    pub fn with_quality(mut self, quality: impl Into<Option<u8>>) -> Self {
+        self.0.quality = quality.into();
+        self
+    }
+
+    pub fn with_speed(mut self, speed: impl Into<Option<u8>>) -> Self {
+        self.0.speed = speed.into();
+        self
+    }
+
+    pub fn with_colorspace(mut self, colorspace: impl Into<Option<ColorSpace>>) -> Self {
+        self.0.colorspace = colorspace.into();
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: impl Into<Option<u8>>) -> Self {
+        self.0.bit_depth = bit_depth.into();
+        self
+    }
+
+    pub fn with_subsampling(mut self, subsampling: impl Into<Option<ChromaSubsampling>>) -> Self {
+        self.0.subsampling = subsampling.into();
+        self
+    }
+
+    pub fn with_sharpen(mut self, sharpen: impl Into<Option<Sharpen>>) -> Self {
+        self.0.sharpen = sharpen.into();
+        self
+    }
+
+    pub fn with_watermark(mut self, watermark: impl Into<Option<Watermark>>) -> Self {
+        self.0.watermark = watermark.into();
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: impl Into<Option<Pipeline>>) -> Self {
+        self.0.pipeline = pipeline.into();
+        self
+    }
+
+    //A pipeline's `format(...)` is the output format unless one was set directly.
+    pub fn build(mut self) -> Result<TranscodeTarget, TranscodeTargetError> {
+        if self.0.image_format.is_none() {
+            self.0.image_format = self.0.pipeline.and_then(|pipeline| pipeline.format());
+        }
+        self.0.validate().map(|_| self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    Gray,
+    Rgb,
+    Rgba,
+}
+
+//How much chroma resolution JPEG output keeps, named after the ratio the query parameter uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ChromaSubsampling {
+    #[serde(rename = "444")]
+    Full,
+    #[serde(rename = "422")]
+    Half,
+    #[serde(rename = "420")]
+    Quarter,
+}
+
+impl ChromaSubsampling {
+    fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            ChromaSubsampling::Full => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Half => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Quarter => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncoderDefaults {
+    pub jpeg_quality: Option<u8>,
+    pub webp_quality: Option<u8>,
+    pub avif_quality: Option<u8>,
+    pub avif_speed: Option<u8>,
+    pub optimize_output: bool,
+}
+
+impl EncoderDefaults {
+    fn quality_for(&self, image_format: ImageFormat) -> Option<u8> {
+        match image_format.format() {
+            image::ImageFormat::Jpeg => self.jpeg_quality,
+            image::ImageFormat::WebP => self.webp_quality,
+            image::ImageFormat::Avif => self.avif_quality,
+            _ => None,
+        }
+    }
+}
+
+//The image crate's own JPEG default, jpeg-encoder has none.
+const JPEG_DEFAULT_QUALITY: u8 = 75;
+
+//The image crate's own AVIF defaults, used when only one of speed and quality is given.
+const AVIF_DEFAULT_SPEED: u8 = 4;
+const AVIF_DEFAULT_QUALITY: u8 = 80;
+
+fn valid_quality(quality: u8) -> bool {
+    (1..=100).contains(&quality)
+}
+
+fn valid_speed(speed: u8) -> bool {
+    (1..=10).contains(&speed)
+}
+
+//Unsharp mask, `amount` is the blur sigma and differences below `threshold` are left alone.
+#[derive(Debug, Clone, Copy)]
+pub struct Sharpen {
+    pub amount: f32,
+    pub threshold: u8,
+}
+
+//Sigmas beyond this cost a lot of blurring and only produce halos.
+const MAX_SHARPEN_AMOUNT: f32 = 10.0;
+//Upscaling past this only burns memory and time.
+const MAX_RESIZE_EDGE: u32 = 16_384;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Watermark {
+    pub image_id: Uuid,
+    pub position: WatermarkPosition,
+    pub opacity: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    fn offset(&self, base: (u32, u32), overlay: (u32, u32)) -> (i64, i64) {
+        let right = base.0 as i64 - overlay.0 as i64;
+        let bottom = base.1 as i64 - overlay.1 as i64;
+        match self {
+            WatermarkPosition::TopLeft => (0, 0),
+            WatermarkPosition::TopRight => (right, 0),
+            WatermarkPosition::BottomLeft => (0, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => (right / 2, bottom / 2),
+        }
+    }
+}
+
+impl TranscodeTarget {
+    pub fn builder() -> TranscodeTargetBuilder {
+        TranscodeTargetBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<(), TranscodeTargetError> {
+        self.check().map_err(TranscodeTargetError)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if let Some(aspect) = self.aspect {
+            if !aspect.is_finite() || aspect <= 0.0 {
+                return Err(format!("invalid aspect: {aspect}"));
+            }
+        }
+        if let Some(scale) = self.scale {
+            if !scale.is_finite() || scale <= 0.0 {
+                return Err(format!("invalid scale: {scale}"));
+            }
+        }
+        if let Some(width) = self.image_width {
+            check_resize_edge("width", width)?;
+        }
+        if let Some(height) = self.image_height {
+            check_resize_edge("height", height)?;
+        }
+        self.validate_geometry()?;
+        self.validate_pipeline()?;
+        if let Some(quality) = self.quality {
+            if !valid_quality(quality) {
+                return Err(format!("invalid quality: {quality}, expected 1-100"));
+            }
+        }
+        if let Some(speed) = self.speed {
+            if !valid_speed(speed) {
+                return Err(format!("invalid speed: {speed}, expected 1-10"));
+            }
+            if self.image_format != Some(ImageFormat::AVIF) {
+                return Err("speed is only supported for avif".to_string());
+            }
+        }
+        if let Some(sharpen) = self.sharpen {
+            if !(sharpen.amount > 0.0 && sharpen.amount <= MAX_SHARPEN_AMOUNT) {
+                return Err(format!(
+                    "invalid sharpen amount: {}, expected above 0 and at most {MAX_SHARPEN_AMOUNT}",
+                    sharpen.amount
+                ));
+            }
+        }
+        if let Some(watermark) = self.watermark {
+            if !(0.0..=1.0).contains(&watermark.opacity) {
+                return Err(format!("invalid watermark opacity: {}", watermark.opacity));
+            }
+        }
+        self.validate_color()
+    }
+
+    //Combinations where one parameter would silently override another are refused instead.
+    fn validate_geometry(&self) -> Result<(), String> {
+        if self.scale.is_some() {
+            match (self.image_width, self.image_height) {
+                (Some(_), Some(_)) => {
+                    return Err("scale conflicts with width and height, pass either".to_string())
+                }
+                (Some(_), None) => return Err("scale conflicts with width, pass either".to_string()),
+                (None, Some(_)) => return Err("scale conflicts with height, pass either".to_string()),
+                (None, None) => {}
+            }
+        }
+        //A box of a different shape would fit the crop inside it, leaving one of the two unmet.
+        if let (Some(aspect), Some(width), Some(height)) =
+            (self.aspect, self.image_width, self.image_height)
+        {
+            let aspect = aspect as f64;
+            let matches = (height as f64 * aspect).round() as u32 == width
+                || (width as f64 / aspect).round() as u32 == height;
+            if !matches {
+                return Err(format!(
+                    "aspect {aspect:.3} conflicts with width {width} and height {height}, pass at most one of width and height"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    //Steps outside the pipeline would have no place in its order.
+    fn validate_pipeline(&self) -> Result<(), String> {
+        let Some(pipeline) = self.pipeline else {
+            return Ok(());
+        };
+        let conflicting: Vec<&str> = [
+            ("width", self.image_width.is_some()),
+            ("height", self.image_height.is_some()),
+            ("scale", self.scale.is_some()),
+            ("aspect", self.aspect.is_some()),
+            ("sharpen", self.sharpen.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if !conflicting.is_empty() {
+            return Err(format!(
+                "pipeline conflicts with {}, make them steps of the pipeline",
+                conflicting.join(", ")
+            ));
+        }
+        if let Some(format) = pipeline.format() {
+            if self.image_format.is_some_and(|image_format| image_format != format) {
+                return Err("format conflicts with the format of the pipeline".to_string());
+            }
+        }
+        pipeline.validate(MAX_SHARPEN_AMOUNT)?;
+        for op in pipeline.ops() {
+            if let Op::Resize(width, height) = *op {
+                check_resize_edge("resize width", width)?;
+                check_resize_edge("resize height", height)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_color(&self) -> Result<(), String> {
+        if self.colorspace.is_none() && self.bit_depth.is_none() {
+            return Ok(());
+        }
+        let image_format = self.image_format.unwrap_or_default();
+        if image_format == ImageFormat::HDR {
+            return Err("hdr output does not support colorspace or bitdepth".to_string());
+        }
+        match self.bit_depth {
+            None | Some(8) => {}
+            Some(16) if image_format == ImageFormat::PNG => {}
+            Some(16) => {
+                return Err(format!(
+                    "bitdepth 16 is not supported for {}",
+                    image_format.to_str()
+                ))
+            }
+            Some(bit_depth) => return Err(format!("invalid bitdepth: {bit_depth}, expected 8 or 16")),
+        }
+        if self.colorspace == Some(ColorSpace::Rgba) && image_format == ImageFormat::JPG {
+            return Err("jpg does not support an alpha channel".to_string());
+        }
+        Ok(())
+    }
+
+    //Drops parameters that wouldn't change the output, so equivalent requests are served from
+    //the same stored variant instead of each being transformed on the fly.
+    //A colorspace or bit depth the source already has only converts when the pixels are changed
+    //into another color type first, which pipelines can do.
+    pub fn canonical(
+        mut self,
+        source_dimensions: Option<(u32, u32)>,
+        source_color: Option<ColorType>,
+    ) -> TranscodeTarget {
+        let source_color = source_color
+            .and_then(color_parameters)
+            .filter(|_| self.pipeline.is_none());
+        if let Some((colorspace, bit_depth)) = source_color {
+            if self.colorspace == Some(colorspace) {
+                self.colorspace = None;
+            }
+            if self.bit_depth == Some(bit_depth) {
+                self.bit_depth = None;
+            }
+        }
+        if let Some((width, height)) = source_dimensions {
+            if self.crop(width, height).is_none() {
+                self.aspect = None;
+            }
+            let (width, height) = self.cropped_dimensions(width, height);
+            if self.resizes() && self.dimensions(width, height) == (width, height) {
+                self.image_width = None;
+                self.image_height = None;
+                self.scale = None;
+            }
+            //Resolved against the original, so decoders that already shrink the source don't get scaled again.
+            if self.scale.is_some() {
+                let (width, height) = self.dimensions(width, height);
+                self.image_width = Some(width);
+                self.image_height = Some(height);
+                self.scale = None;
+            }
+        }
+        let defaults = ENCODER_DEFAULTS.get().copied().unwrap_or_default();
+        let image_format = self.image_format.unwrap_or_default();
+        if self.quality.is_some() && self.quality == defaults.quality_for(image_format) {
+            self.quality = None;
+        }
+        if self.speed.is_some() && self.speed == defaults.avif_speed {
+            self.speed = None;
+        }
+        if self.jpeg_subsampling().is_none() {
+            self.subsampling = None;
+        }
+        if self.watermark.is_some_and(|watermark| watermark.opacity == 0.0) {
+            self.watermark = None;
+        }
+        self
+    }
+
+    //Equal for targets that produce the same output once canonicalized. Transforms without a format
+    //are encoded in the default one, so leaving it out and naming it share a key.
+    pub fn cache_key(&self) -> String {
+        let mut target = *self;
+        if target.transforms() {
+            target.image_format = Some(target.image_format.unwrap_or_default());
+        }
+        format!("{target:?}")
+    }
+
+    //Whether the source's header is needed to tell if the target changes anything.
+    fn needs_source_header(&self) -> bool {
+        self.reshapes() || self.colorspace.is_some() || self.bit_depth.is_some()
+    }
+
+    //Whether the output size depends on the source's, directly or through a pipeline step.
+    pub fn scales(&self) -> bool {
+        self.scale.is_some()
+            || self.pipeline.is_some_and(|pipeline| {
+                pipeline.ops().iter().any(|op| matches!(op, Op::Scale(_)))
+            })
+    }
+
+    pub fn resizes(&self) -> bool {
+        self.image_width.is_some() || self.image_height.is_some() || self.scale.is_some()
+    }
+
+    //Whether the source's dimensions are needed to tell if the target changes anything.
+    pub fn reshapes(&self) -> bool {
+        self.resizes() || self.aspect.is_some()
+    }
+
+    //Anything beyond a format change produces an image that must not be stored as a format variant.
+    pub fn transforms(&self) -> bool {
+        self.reshapes()
+            || self.quality.is_some()
+            || self.speed.is_some()
+            || self.colorspace.is_some()
+            || self.bit_depth.is_some()
+            || self.jpeg_subsampling().is_some()
+            || self.sharpen.is_some()
+            || self.watermark.is_some()
+            || self.pipeline.is_some()
+    }
+
+    //Subsampling is ignored for every other format.
+    fn jpeg_subsampling(&self) -> Option<ChromaSubsampling> {
+        self.subsampling
+            .filter(|_| self.image_format == Some(ImageFormat::JPG))
+    }
+
+    //The centered `(x, y, width, height)` box with the target's aspect, None when nothing would be cut off.
+    fn crop(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        center_crop(width, height, self.aspect?)
+    }
+
+    //The dimensions resizing starts from.
+    fn cropped_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        self.crop(width, height)
+            .map_or((width, height), |(_, _, width, height)| (width, height))
+    }
+
+    //What `apply_transforms` turns a source of these dimensions into.
+    pub fn output_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        if let Some(pipeline) = self.pipeline {
+            return pipeline
+                .ops()
+                .iter()
+                .fold((width, height), |dimensions, op| op_dimensions(dimensions, *op));
+        }
+        let (width, height) = self.cropped_dimensions(width, height);
+        if !self.resizes() {
+            return (width, height);
+        }
+        let (max_width, max_height) = self.dimensions(width, height);
+        fit_dimensions(width, height, max_width, max_height)
+    }
+
+    //Scales resolve against the source, so the size they ask for can only be checked against its dimensions.
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), TranscodeTargetError> {
+        self.check_scaled(width, height).map_err(TranscodeTargetError)
+    }
+
+    fn check_scaled(&self, width: u32, height: u32) -> Result<(), String> {
+        if let Some(pipeline) = self.pipeline {
+            //Each step scales what the steps before it left, so every intermediate size is checked.
+            let mut dimensions = (width, height);
+            for op in pipeline.ops() {
+                dimensions = op_dimensions(dimensions, *op);
+                if let Op::Scale(_) = op {
+                    check_resize_edge("scaled width", dimensions.0)?;
+                    check_resize_edge("scaled height", dimensions.1)?;
+                }
+            }
+            return Ok(());
+        }
+        if self.scale.is_some() {
+            let (width, height) = self.cropped_dimensions(width, height);
+            let (scaled_width, scaled_height) = self.dimensions(width, height);
+            check_resize_edge("scaled width", scaled_width)?;
+            check_resize_edge("scaled height", scaled_height)?;
+        }
+        Ok(())
+    }
+
+    fn dimensions(&self, source_width: u32, source_height: u32) -> (u32, u32) {
+        match self.scale {
+            Some(scale) => scaled_dimensions(source_width, source_height, scale),
+            None => (
+                self.image_width.unwrap_or(source_width),
+                self.image_height.unwrap_or(source_height),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnimationPolicy {
+    #[default]
+    FirstFrame,
+    LastFrame,
+    Error,
+}
+
+impl FromStr for AnimationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first_frame" => Ok(AnimationPolicy::FirstFrame),
+            "last_frame" => Ok(AnimationPolicy::LastFrame),
+            "error" => Ok(AnimationPolicy::Error),
+            other => Err(format!("unknown animation policy: {other}")),
+        }
+    }
+}
+
+pub fn is_animated(data: &[u8]) -> bool {
+    match image::guess_format(data) {
+        Ok(image::ImageFormat::Gif) => GifDecoder::new(Cursor::new(data))
+            .is_ok_and(|decoder| decoder.into_frames().take(2).count() > 1),
+        Ok(image::ImageFormat::WebP) => {
+            WebPDecoder::new(Cursor::new(data)).is_ok_and(|decoder| decoder.has_animation())
+        }
+        Ok(image::ImageFormat::Png) => PngDecoder::new(Cursor::new(data))
+            .and_then(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+//Decodes a still image, animated sources are reduced to a single frame according to the policy.
+pub fn decode_still<R>(reader: ImageReader<R>, policy: AnimationPolicy) -> Result<DynamicImage, ImageError>
+where
+    R: BufRead + Seek,
+{
+    let format = match (reader.format(), policy) {
+        (Some(format), AnimationPolicy::LastFrame | AnimationPolicy::Error) => format,
+        _ => return reader.decode(),
+    };
+
+    //Unwrapping the reader drops its limits, so the decoders get them again.
+    let frames: Frames = match format {
+        image::ImageFormat::Gif => limited(GifDecoder::new(reader.into_inner())?)?.into_frames(),
+        image::ImageFormat::WebP => {
+            let decoder = limited(WebPDecoder::new(reader.into_inner())?)?;
+            if !decoder.has_animation() {
+                return DynamicImage::from_decoder(decoder);
+            }
+            decoder.into_frames()
+        }
+        image::ImageFormat::Png => {
+            let decoder = limited(PngDecoder::with_limits(reader.into_inner(), decode_limits())?)?;
+            if !decoder.is_apng()? {
+                return DynamicImage::from_decoder(decoder);
+            }
+            decoder.apng()?.into_frames()
+        }
+        _ => return reader.decode(),
+    };
+
+    let mut last_frame = None;
+    for (index, frame) in frames.enumerate() {
+        if index > 0 && policy == AnimationPolicy::Error {
+            return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Exact(format),
+                UnsupportedErrorKind::GenericFeature("animation".to_string()),
+            )));
+        }
+        last_frame = Some(frame?);
+    }
+
+    last_frame
+        .map(|frame| DynamicImage::ImageRgba8(frame.into_buffer()))
+        .ok_or_else(|| {
+            ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::NoMoreData))
+        })
+}
+
+//What ImageReader::decode does before decoding, the canvas counts against the allocation limit.
+fn limited<D: ImageDecoder>(mut decoder: D) -> Result<D, ImageError> {
+    let mut limits = decode_limits();
+    limits.reserve(decoder.total_bytes())?;
+    decoder.set_limits(limits)?;
+    Ok(decoder)
+}
+
+//CPU bound image work runs on its own pool so it can't starve tokio's blocking pool used for fs operations.
+pub fn init_pool(num_threads: Option<usize>) -> Result<(), ThreadPoolBuildError> {
+    let pool = build_pool(num_threads)?;
+    if TRANSCODE_POOL.set(pool).is_err() {
+        warn!("Transcode pool was already initialized");
+    }
+    Ok(())
+}
+
+fn build_pool(num_threads: Option<usize>) -> Result<ThreadPool, ThreadPoolBuildError> {
+    ThreadPoolBuilder::new()
+        .num_threads(num_threads.unwrap_or(0))
+        .thread_name(|index| format!("transcode-{index}"))
+        .panic_handler(|panic| warn!("Transcode thread panicked: {panic:?}"))
+        .build()
+}
+
+//Without a budget nothing is cached and every transform decodes its source again.
+pub fn init_decode_cache(max_bytes: Option<usize>) {
+    if let Some(max_bytes) = max_bytes {
+        if DECODE_CACHE.set(Mutex::new(DecodeCache::new(max_bytes))).is_err() {
+            warn!("Decode cache was already initialized");
+        }
+    }
+}
+
+//Called whenever a source file goes away or is written again.
+pub fn forget_decoded(tenant: &Tenant, image_id: Uuid) {
+    if let Some(cache) = DECODE_CACHE.get() {
+        cache.lock().unwrap().remove(tenant, image_id);
+    }
+}
+
+//Guards decodes against decompression bombs, limits that aren't configured stay unbounded.
+pub fn init_decode_limits(
+    max_image_width: Option<u32>,
+    max_image_height: Option<u32>,
+    max_alloc: Option<u64>,
+) {
+    let mut limits = Limits::no_limits();
+    limits.max_image_width = max_image_width;
+    limits.max_image_height = max_image_height;
+    limits.max_alloc = max_alloc;
+    if DECODE_LIMITS.set(limits).is_err() {
+        warn!("Decode limits were already initialized");
+    }
+}
+
+//Downscales by more than `ratio` first shrink with a cheap box filter to twice the target,
+//the final Lanczos3 pass then only covers the last 2x.
+pub fn init_pre_downscale(ratio: Option<f32>) -> Result<(), String> {
+    let Some(ratio) = ratio else {
+        return Ok(());
+    };
+    if !(ratio > 2.0 && ratio.is_finite()) {
+        return Err(format!("invalid pre-downscale ratio: {ratio}, expected above 2"));
+    }
+    if PRE_DOWNSCALE_RATIO.set(ratio).is_err() {
+        warn!("Pre-downscale ratio was already initialized");
+    }
+    Ok(())
+}
+
+//The centered `(x, y, width, height)` box with the aspect, None when nothing would be cut off.
+fn center_crop(width: u32, height: u32, aspect: f32) -> Option<(u32, u32, u32, u32)> {
+    let aspect = aspect as f64;
+    let (crop_width, crop_height) = if width as f64 / height as f64 > aspect {
+        (((height as f64 * aspect).round() as u32).clamp(1, width), height)
+    } else {
+        (width, ((width as f64 / aspect).round() as u32).clamp(1, height))
+    };
+    if (crop_width, crop_height) == (width, height) {
+        return None;
+    }
+    Some((
+        (width - crop_width) / 2,
+        (height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    ))
+}
+
+//Saturates at `u32::MAX` rather than wrapping, so oversized results fail the edge checks.
+fn scaled_dimensions(width: u32, height: u32, scale: f32) -> (u32, u32) {
+    (
+        ((width as f64 * scale as f64).round() as u32).max(1),
+        ((height as f64 * scale as f64).round() as u32).max(1),
+    )
+}
+
+fn op_dimensions((width, height): (u32, u32), op: Op) -> (u32, u32) {
+    match op {
+        Op::Resize(max_width, max_height) => fit_dimensions(width, height, max_width, max_height),
+        Op::Scale(scale) => scaled_dimensions(width, height, scale),
+        Op::Aspect(aspect) => center_crop(width, height, aspect)
+            .map_or((width, height), |(_, _, width, height)| (width, height)),
+        Op::Rotate(90 | 270) => (height, width),
+        _ => (width, height),
+    }
+}
+
+//Resizes to fewer pixels per edge are refused, so tiny thumbnails can't be requested in bulk.
+pub fn init_min_resize_edge(min_edge: Option<u32>) -> Result<(), String> {
+    let Some(min_edge) = min_edge else {
+        return Ok(());
+    };
+    if !(1..=MAX_RESIZE_EDGE).contains(&min_edge) {
+        return Err(format!(
+            "invalid minimum resize edge: {min_edge}, expected 1-{MAX_RESIZE_EDGE}"
+        ));
+    }
+    if MIN_RESIZE_EDGE.set(min_edge).is_err() {
+        warn!("Minimum resize edge was already initialized");
+    }
+    Ok(())
+}
+
+//Width and height bound the output in both directions, upscales included.
+fn check_resize_edge(name: &str, edge: u32) -> Result<(), String> {
+    let min_edge = MIN_RESIZE_EDGE.get().copied().unwrap_or(1);
+    if !(min_edge..=MAX_RESIZE_EDGE).contains(&edge) {
+        return Err(format!(
+            "invalid {name}: {edge}, expected {min_edge}-{MAX_RESIZE_EDGE}"
+        ));
+    }
+    Ok(())
+}
+
+//Same fit as `DynamicImage::resize`, so both strategies agree on the output dimensions.
+fn fit_dimensions(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let ratio = f64::min(
+        max_width as f64 / width as f64,
+        max_height as f64 / height as f64,
+    );
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1),
+    )
+}
+
+fn resize(image: &DynamicImage, max_width: u32, max_height: u32) -> DynamicImage {
+    let (width, height) = fit_dimensions(image.width(), image.height(), max_width, max_height);
+    if (width, height) == image.dimensions() {
+        return image.clone();
+    }
+    let downscale_ratio = f32::min(
+        image.width() as f32 / width as f32,
+        image.height() as f32 / height as f32,
+    );
+    match PRE_DOWNSCALE_RATIO.get() {
+        Some(&threshold) if downscale_ratio > threshold => image
+            .thumbnail_exact(width * 2, height * 2)
+            .resize_exact(width, height, imageops::FilterType::Lanczos3),
+        _ => image.resize_exact(width, height, imageops::FilterType::Lanczos3),
+    }
+}
+
+//Encodes a tiny image in every format, so encoders missing from the build show up at startup
+//rather than as errors on the first request for them.
+pub fn probe_formats() -> Vec<(ImageFormat, Result<(), ImageError>)> {
+    let image = DynamicImage::new_rgb8(2, 2);
+    ImageFormat::ALL
+        .into_iter()
+        .map(|image_format| {
+            let probe = fit_to_format(image.clone(), image_format);
+            (image_format, encode(&probe, image_format, None, None).map(|_| ()))
+        })
+        .collect()
+}
+
+//With `disable_unavailable` formats that failed the probe are refused like unknown ones.
+pub fn init_format_check(disable_unavailable: bool) {
+    let mut available = Vec::new();
+    let mut unavailable = Vec::new();
+    for (image_format, result) in probe_formats() {
+        match result {
+            Ok(()) => available.push(image_format.to_str()),
+            Err(e) => {
+                warn!("{} output is unavailable: {e}", image_format.to_str());
+                unavailable.push(image_format);
+            }
+        }
+    }
+    info!("Available output formats: {}", available.join(", "));
+    if disable_unavailable && DISABLED_FORMATS.set(unavailable).is_err() {
+        warn!("Disabled formats were already initialized");
+    }
+}
+
+pub fn format_enabled(image_format: ImageFormat) -> bool {
+    DISABLED_FORMATS
+        .get()
+        .is_none_or(|disabled| !disabled.contains(&image_format))
+}
+
+//Caps how many distinct transforms one image may have computed per window, so a single upload can't be
+//turned into an unbounded number of transcodes. Stored formats are served as before once they exist.
+pub fn init_transform_limit(max_transforms: Option<usize>, window_secs: u64) {
+    if let Some(max_transforms) = max_transforms {
+        let limiter = TransformLimiter::new(max_transforms, std::time::Duration::from_secs(window_secs));
+        if TRANSFORM_LIMITER.set(Mutex::new(limiter)).is_err() {
+            warn!("Transform limit was already initialized");
+        }
+    }
+}
+
+//Only checked once the source was found, so unknown ids never take up room in the limiter.
+fn check_transform_limit(image_id: Uuid, settings: &TranscodeTarget) -> Result<(), TranscoderError> {
+    let Some(limiter) = TRANSFORM_LIMITER.get() else {
+        return Ok(());
+    };
+    let mut hasher = DefaultHasher::new();
+    settings.cache_key().hash(&mut hasher);
+    if limiter.lock().unwrap().allow(image_id, hasher.finish()) {
+        Ok(())
+    } else {
+        Err(TranscoderError::TooManyTransforms)
+    }
+}
+
+//Output carries the source's ICC profile, or sRGB when it had none, wherever the format can hold one.
+pub fn init_icc(preserve: bool) {
+    if PRESERVE_ICC.set(preserve).is_err() {
+        warn!("ICC handling was already initialized");
+    }
+}
+
+pub fn preserve_icc() -> bool {
+    PRESERVE_ICC.get().copied().unwrap_or(false)
+}
+
+//Stored files are checked against the checksum recorded when they were written before being served.
+//Corrupted variants can be discarded to be transcoded again, sources never are.
+pub fn init_verify_on_read(verify: bool, discard_corrupted: bool) {
+    if verify && VERIFY_ON_READ.set(discard_corrupted).is_err() {
+        warn!("Read verification was already initialized");
+    }
+}
+
+//Files written before checksums were recorded pass unchecked.
+async fn verify_stored(
+    tenant: &Tenant,
+    image_id: Uuid,
+    image_format: ImageFormat,
+    data: Vec<u8>,
+    database: &Database,
+) -> Result<Vec<u8>, TranscoderError> {
+    let Some(&discard_corrupted) = VERIFY_ON_READ.get() else {
+        return Ok(data);
+    };
+    let stored = database
+        .stored_checksum(tenant, &image_id, image_format)
+        .await
+        .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
+    let Some((expected, source)) = stored else {
+        return Ok(data);
+    };
+    //Hashing a large file would hold up the runtime thread.
+    let (data, actual) = run_blocking(move || {
+        let actual = checksum(&data);
+        (data, actual)
+    })
+    .await
+    .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
+    if actual == expected {
+        return Ok(data);
+    }
+    warn!(
+        "Stored {} file of image: {image_id} does not match its checksum, it is corrupted",
+        image_format.to_str()
+    );
+    if discard_corrupted && !source {
+        database.discard_variant(tenant, &image_id, image_format).await;
+    }
+    Err(TranscoderError::InternalServerError(
+        format!("stored file of image {image_id} is corrupted").into(),
+    ))
+}
+
+//Only the header is read, None when profiles aren't preserved or the image has none.
+pub fn read_icc_profile<R: BufRead + Seek>(imagereader: ImageReader<R>) -> Option<Vec<u8>> {
+    if !preserve_icc() {
+        return None;
+    }
+    let mut decoder = imagereader.with_guessed_format().ok()?.into_decoder().ok()?;
+    decoder.icc_profile().ok().flatten()
+}
+
+//Adds the profile the encoded image should carry, see `init_icc`.
+pub fn embed_icc_profile(
+    data: Vec<u8>,
+    image_format: ImageFormat,
+    color: ColorType,
+    source_profile: Option<&[u8]>,
+) -> Vec<u8> {
+    if !preserve_icc() {
+        return data;
+    }
+    match source_profile.or_else(|| icc::default_profile(color)) {
+        Some(profile) => icc::embed(data, image_format, color, profile),
+        None => data,
+    }
+}
+
+async fn source_icc_profile(image_path: ImagePath) -> Result<Option<Vec<u8>>, TranscoderError> {
+    if !preserve_icc() {
+        return Ok(None);
+    }
+    run_blocking(move || ImageReader::open(image_path).ok().and_then(read_icc_profile))
+        .await
+        .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))
+}
+
+pub fn decode_limits() -> Limits {
+    DECODE_LIMITS.get().cloned().unwrap_or_else(Limits::no_limits)
+}
+
+//Used whenever a request doesn't pick a quality or speed itself, unset defaults keep the encoder's own.
+pub fn init_encoder_defaults(defaults: EncoderDefaults) -> Result<(), String> {
+    let qualities = [
+        defaults.jpeg_quality,
+        defaults.webp_quality,
+        defaults.avif_quality,
+    ];
+    for quality in qualities.into_iter().flatten() {
+        if !valid_quality(quality) {
+            return Err(format!("invalid default quality: {quality}, expected 1-100"));
+        }
+    }
+    if let Some(speed) = defaults.avif_speed.filter(|speed| !valid_speed(*speed)) {
+        return Err(format!("invalid default avif speed: {speed}, expected 1-10"));
+    }
+    if defaults.optimize_output && !cfg!(feature = "optimize") {
+        return Err("output optimization needs the server built with the 'optimize' feature".to_string());
+    }
+    if ENCODER_DEFAULTS.set(defaults).is_err() {
+        warn!("Encoder defaults were already initialized");
+    }
+    Ok(())
+}
+
+fn pool() -> &'static ThreadPool {
+    TRANSCODE_POOL.get_or_init(|| build_pool(None).expect("Could not build transcode pool"))
+}
+
+//Jobs queued on or running in the transcode pool.
+pub fn pending_transcodes() -> usize {
+    PENDING_TRANSCODES.load(Ordering::Relaxed)
+}
+
+fn record_transcode_time(duration: std::time::Duration) {
+    let mut times = RECENT_TRANSCODE_TIMES.lock().unwrap();
+    if times.len() == RECENT_TRANSCODE_SAMPLES {
+        times.pop_front();
+    }
+    times.push_back(duration);
+}
+
+fn record_format_sample(
+    image_format: ImageFormat,
+    source_pixels: u64,
+    output_pixels: u64,
+    bytes: usize,
+    duration: std::time::Duration,
+) {
+    if output_pixels == 0 {
+        return;
+    }
+    let sample = FormatSample {
+        bytes_per_pixel: bytes as f64 / output_pixels as f64,
+        secs_per_pixel: duration.as_secs_f64() / (source_pixels + output_pixels) as f64,
+    };
+    let mut formats = RECENT_FORMAT_SAMPLES.lock().unwrap();
+    let samples = match formats.iter().position(|(format, _)| *format == image_format) {
+        Some(index) => &mut formats[index].1,
+        None => {
+            formats.push((image_format, VecDeque::new()));
+            &mut formats.last_mut().unwrap().1
+        }
+    };
+    if samples.len() == RECENT_TRANSCODE_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+//Averages over the last transcodes to the format, with the number of samples behind them.
+fn format_rates(image_format: ImageFormat) -> Option<(FormatSample, usize)> {
+    let formats = RECENT_FORMAT_SAMPLES.lock().unwrap();
+    let (_, samples) = formats.iter().find(|(format, _)| *format == image_format)?;
+    let count = samples.len();
+    (count > 0).then(|| {
+        let average = samples.iter().fold(
+            FormatSample {
+                bytes_per_pixel: 0.0,
+                secs_per_pixel: 0.0,
+            },
+            |total, sample| FormatSample {
+                bytes_per_pixel: total.bytes_per_pixel + sample.bytes_per_pixel / count as f64,
+                secs_per_pixel: total.secs_per_pixel + sample.secs_per_pixel / count as f64,
+            },
+        );
+        (average, count)
+    })
+}
+
+//Rolling average over the last transcodes, None until one has finished.
+pub fn estimated_transcode_time() -> Option<std::time::Duration> {
+    let times = RECENT_TRANSCODE_TIMES.lock().unwrap();
+    let total: std::time::Duration = times.iter().sum();
+    (!times.is_empty()).then(|| total / times.len() as u32)
+}
+
+//Decrements on drop so panicking jobs are not counted forever.
+struct PendingGuard;
+
+impl PendingGuard {
+    fn new() -> PendingGuard {
+        PENDING_TRANSCODES.fetch_add(1, Ordering::Relaxed);
+        PendingGuard
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        PENDING_TRANSCODES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn spawn<F>(work: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let guard = PendingGuard::new();
+    pool().spawn(move || {
+        let _guard = guard;
+        work();
+    });
+}
+
+pub async fn run_blocking<F, T>(work: F) -> Result<T, RecvError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let guard = PendingGuard::new();
+    pool().spawn(move || {
+        let _guard = guard;
+        let _ = tx.send(work());
+    });
+    rx.await
+}
+
+//Everything up to the encode, shared by encoded and raw output.
+fn apply_transforms(
+    image: Arc<DynamicImage>,
+    settings: TranscodeTarget,
+    watermark: Option<Arc<RgbaImage>>,
+) -> DynamicImage {
+    let mut image = match settings.pipeline {
+        Some(pipeline) => apply_pipeline(image, &pipeline),
+        None => apply_geometry(image, settings),
+    };
+
+    if let (Some(overlay), Some(watermark)) = (watermark, settings.watermark) {
+        apply_watermark(&mut image, &overlay, watermark);
+    }
+
+    convert_color(image, settings.colorspace, settings.bit_depth)
+}
+
+//Each step works on the result of the one before it.
+fn apply_pipeline(image: Arc<DynamicImage>, pipeline: &Pipeline) -> DynamicImage {
+    pipeline
+        .ops()
+        .iter()
+        .fold(Arc::unwrap_or_clone(image), |image, op| match *op {
+            Op::Resize(max_width, max_height) => resize(&image, max_width, max_height),
+            Op::Scale(scale) => {
+                let (width, height) = scaled_dimensions(image.width(), image.height(), scale);
+                resize(&image, width, height)
+            }
+            Op::Aspect(aspect) => match center_crop(image.width(), image.height(), aspect) {
+                Some((x, y, width, height)) => image.crop_imm(x, y, width, height),
+                None => image,
+            },
+            Op::Grayscale => image.grayscale(),
+            Op::Blur(sigma) => image.blur(sigma),
+            Op::Sharpen(amount, threshold) => image.unsharpen(amount, threshold.into()),
+            Op::Rotate(90) => image.rotate90(),
+            Op::Rotate(180) => image.rotate180(),
+            Op::Rotate(270) => image.rotate270(),
+            Op::Rotate(_) => image,
+            Op::FlipHorizontal => image.fliph(),
+            Op::FlipVertical => image.flipv(),
+        })
+}
+
+fn apply_geometry(image: Arc<DynamicImage>, settings: TranscodeTarget) -> DynamicImage {
+    //Cropped first so width and height apply to what is left.
+    let image = match settings.crop(image.width(), image.height()) {
+        Some((x, y, width, height)) => Arc::new(image.crop_imm(x, y, width, height)),
+        None => image,
+    };
+    let mut image = if settings.resizes() {
+        let (width, height) = settings.dimensions(image.width(), image.height());
+        resize(&image, width, height)
+    } else {
+        Arc::unwrap_or_clone(image)
+    };
+
+    //Applied to the resized image so downscaled thumbnails get their edges back.
+    if let Some(sharpen) = settings.sharpen {
+        image = image.unsharpen(sharpen.amount, sharpen.threshold.into());
+    }
+    image
+}
+
+//Returns the encoded bytes together with the dimensions they were encoded at,
+//`stored` output is written to disk and worth optimizing when that is enabled.
+pub async fn transcode(
+    image: Arc<DynamicImage>,
+    settings: TranscodeTarget,
+    watermark: Option<Arc<RgbaImage>>,
+    icc_profile: Option<Vec<u8>>,
+    stored: bool,
+) -> Result<(Vec<u8>, (u32, u32)), ImageError> {
+    run_blocking(move || {
+        let started = Instant::now();
+        let source_pixels = image.width() as u64 * image.height() as u64;
+        let image = apply_transforms(image, settings, watermark);
+
+        let image_format = settings
+            .image_format
+            .unwrap_or(ImageFormat(image::ImageFormat::Png));
+        let defaults = ENCODER_DEFAULTS.get().copied().unwrap_or_default();
+        let quality = settings
+            .quality
+            .or_else(|| defaults.quality_for(image_format));
+        let speed = settings.speed.or(defaults.avif_speed);
+        let image = fit_to_format(image, image_format);
+
+        let data = if stored && defaults.optimize_output {
+            encode_optimized(&image, image_format, quality, speed, settings)
+        } else {
+            encode_target(&image, image_format, quality, speed, settings)
+        }?;
+        let data = embed_icc_profile(data, image_format, image.color(), icc_profile.as_deref());
+        let elapsed = started.elapsed();
+        record_transcode_time(elapsed);
+        let output_pixels = image.width() as u64 * image.height() as u64;
+        record_format_sample(image_format, source_pixels, output_pixels, data.len(), elapsed);
+        Ok((data, image.dimensions()))
+    })
+    .await
+    .expect("Could not join threads")
+}
+
+fn encode_target(
+    image: &DynamicImage,
+    image_format: ImageFormat,
+    quality: Option<u8>,
+    speed: Option<u8>,
+    settings: TranscodeTarget,
+) -> Result<Vec<u8>, ImageError> {
+    match settings.jpeg_subsampling() {
+        Some(subsampling) => encode_jpeg(image, quality, subsampling),
+        None => encode(image, image_format, quality, speed),
+    }
+}
+
+#[cfg(feature = "optimize")]
+fn encode_optimized(
+    image: &DynamicImage,
+    image_format: ImageFormat,
+    quality: Option<u8>,
+    speed: Option<u8>,
+    settings: TranscodeTarget,
+) -> Result<Vec<u8>, ImageError> {
+    match image_format.format() {
+        image::ImageFormat::Jpeg => optimize::encode_jpeg(
+            image,
+            quality.unwrap_or(JPEG_DEFAULT_QUALITY),
+            settings.subsampling.unwrap_or(ChromaSubsampling::Full),
+        ),
+        image::ImageFormat::Png => optimize::optimize_png(&encode(image, image_format, quality, speed)?),
+        _ => encode_target(image, image_format, quality, speed, settings),
+    }
+}
+
+//Unreachable, init_encoder_defaults refuses to enable optimization without the feature.
+#[cfg(not(feature = "optimize"))]
+fn encode_optimized(
+    image: &DynamicImage,
+    image_format: ImageFormat,
+    quality: Option<u8>,
+    speed: Option<u8>,
+    settings: TranscodeTarget,
+) -> Result<Vec<u8>, ImageError> {
+    encode_target(image, image_format, quality, speed, settings)
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct TranscodeTarget {
-    pub image_format: Option<ImageFormat>,
-    pub image_width: Option<u32>,
-    pub image_height: Option<u32>,
+fn encode(
+    image: &DynamicImage,
+    image_format: ImageFormat,
+    quality: Option<u8>,
+    speed: Option<u8>,
+) -> Result<Vec<u8>, ImageError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    match (image_format.format(), quality) {
+        (image::ImageFormat::Jpeg, Some(quality)) => {
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?
+        }
+        (image::ImageFormat::Avif, quality) if quality.is_some() || speed.is_some() => {
+            image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut bytes,
+                speed.unwrap_or(AVIF_DEFAULT_SPEED),
+                quality.unwrap_or(AVIF_DEFAULT_QUALITY),
+            ))?
+        }
+        //The image crate only encodes lossless WebP, lossy output goes through libwebp.
+        (image::ImageFormat::WebP, Some(quality)) => {
+            let rgba = image.to_rgba8();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(quality as f32);
+            bytes.extend_from_slice(&encoded);
+        }
+        (format, _) => image.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+
+    Ok(bytes)
 }
 
-pub async fn transcode(
-    image: DynamicImage,
-    settings: TranscodeTarget,
+//The image crate's encoder has no subsampling option, so explicit requests go through jpeg-encoder.
+fn encode_jpeg(
+    image: &DynamicImage,
+    quality: Option<u8>,
+    subsampling: ChromaSubsampling,
 ) -> Result<Vec<u8>, ImageError> {
-    tokio::task::spawn_blocking(move || {
-        let image = if settings.image_width.is_some() || settings.image_height.is_some() {
-            let width = settings.image_width.unwrap_or(image.width());
-            let height = settings.image_height.unwrap_or(image.height());
-            image.resize(width, height, image::imageops::FilterType::Lanczos3)
-        } else {
-            image
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, quality.unwrap_or(JPEG_DEFAULT_QUALITY));
+    encoder.set_sampling_factor(subsampling.sampling_factor());
+
+    //fit_to_format already reduced the image to 8 bit gray or rgb.
+    let (data, color_type) = match image {
+        DynamicImage::ImageLuma8(image) => (image.as_raw().as_slice(), jpeg_encoder::ColorType::Luma),
+        DynamicImage::ImageRgb8(image) => (image.as_raw().as_slice(), jpeg_encoder::ColorType::Rgb),
+        other => {
+            return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                ImageFormatHint::Exact(image::ImageFormat::Jpeg),
+                UnsupportedErrorKind::Color(other.color().into()),
+            )))
+        }
+    };
+    let too_large = |_| {
+        ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch))
+    };
+    let width = u16::try_from(image.width()).map_err(too_large)?;
+    let height = u16::try_from(image.height()).map_err(too_large)?;
+
+    encoder
+        .encode(data, width, height, color_type)
+        .map_err(|e| {
+            ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Exact(image::ImageFormat::Jpeg),
+                e,
+            ))
+        })?;
+    Ok(bytes)
+}
+
+//Enough samples to tell a photo from a graphic without walking every pixel of a large image.
+const CONTENT_SAMPLES: u32 = 65536;
+//Graphics rarely use more colors than this, even a small photo easily does.
+const MAX_GRAPHIC_COLORS: usize = 1024;
+//Share of samples that match their right neighbor beyond which an image counts as mostly flat.
+const MIN_FLAT_SHARE: f64 = 0.5;
+
+//Lossy JPEG for photographic content, lossless PNG for transparency, few colors or large flat areas.
+//High bit depths keep a format that can hold them.
+pub fn content_store_format(image: &DynamicImage) -> ImageFormat {
+    match image.color() {
+        ColorType::Rgb32F | ColorType::Rgba32F => return ImageFormat::HDR,
+        color if color.bytes_per_pixel() > color.channel_count() => return ImageFormat::PNG,
+        _ => {}
+    }
+
+    let (width, height) = image.dimensions();
+    let step = ((width as f64 * height as f64 / CONTENT_SAMPLES as f64).sqrt().ceil() as u32).max(1);
+    let mut colors = HashSet::new();
+    let mut samples = 0u32;
+    let mut flat = 0u32;
+    for y in (0..height).step_by(step as usize) {
+        for x in (0..width).step_by(step as usize) {
+            let pixel = image.get_pixel(x, y);
+            if pixel[3] < u8::MAX {
+                return ImageFormat::PNG;
+            }
+            if x + 1 < width && image.get_pixel(x + 1, y) == pixel {
+                flat += 1;
+            }
+            colors.insert(pixel);
+            samples += 1;
+        }
+    }
+    let flat_share = flat as f64 / samples.max(1) as f64;
+    if colors.len() <= MAX_GRAPHIC_COLORS || flat_share > MIN_FLAT_SHARE {
+        ImageFormat::PNG
+    } else {
+        ImageFormat::JPG
+    }
+}
+
+//Alpha is kept wherever the target format can store it, JPEG and HDR get it flattened onto white.
+pub fn fit_to_format(image: DynamicImage, image_format: ImageFormat) -> DynamicImage {
+    let color = image.color();
+    match image_format.format() {
+        image::ImageFormat::Jpeg if color.has_alpha() => {
+            DynamicImage::ImageRgb8(flatten_alpha(&image))
+        }
+        image::ImageFormat::Jpeg if !color.has_color() => DynamicImage::ImageLuma8(image.to_luma8()),
+        image::ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        image::ImageFormat::Hdr if color.has_alpha() => {
+            DynamicImage::ImageRgb32F(DynamicImage::ImageRgb8(flatten_alpha(&image)).to_rgb32f())
+        }
+        image::ImageFormat::Hdr => DynamicImage::ImageRgb32F(image.to_rgb32f()),
+        //The WebP encoder only takes 8 bit channels.
+        image::ImageFormat::WebP if color.bytes_per_pixel() > color.channel_count() => {
+            if color.has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            }
+        }
+        _ => image,
+    }
+}
+
+fn flatten_alpha(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [red, green, blue, alpha] = rgba.get_pixel(x, y).0;
+        let blend = |channel: u8| {
+            ((channel as u32 * alpha as u32 + 255 * (255 - alpha as u32)) / 255) as u8
         };
+        Rgb([blend(red), blend(green), blend(blue)])
+    })
+}
+
+//Without an explicit colorspace the source's channels are kept, without a bit depth its depth is.
+//The `colorspace` and `bitdepth` that `convert_color` leaves a source of this color type untouched with.
+fn color_parameters(color: ColorType) -> Option<(ColorSpace, u8)> {
+    match color {
+        ColorType::L8 => Some((ColorSpace::Gray, 8)),
+        ColorType::L16 => Some((ColorSpace::Gray, 16)),
+        ColorType::Rgb8 => Some((ColorSpace::Rgb, 8)),
+        ColorType::Rgb16 => Some((ColorSpace::Rgb, 16)),
+        ColorType::Rgba8 => Some((ColorSpace::Rgba, 8)),
+        ColorType::Rgba16 => Some((ColorSpace::Rgba, 16)),
+        _ => None,
+    }
+}
 
-        let mut bytes: Vec<u8> = Vec::new();
-        let mut cursor = Cursor::new(&mut bytes);
+fn convert_color(
+    image: DynamicImage,
+    colorspace: Option<ColorSpace>,
+    bit_depth: Option<u8>,
+) -> DynamicImage {
+    if colorspace.is_none() && bit_depth.is_none() {
+        return image;
+    }
+    let color = image.color();
+    let colorspace = colorspace.unwrap_or(if color.has_alpha() {
+        ColorSpace::Rgba
+    } else if color.has_color() {
+        ColorSpace::Rgb
+    } else {
+        ColorSpace::Gray
+    });
+    let wide = match bit_depth {
+        Some(bit_depth) => bit_depth == 16,
+        None => color.bytes_per_pixel() / color.channel_count() > 1,
+    };
 
-        image.write_to(
-            &mut cursor,
-            settings
-                .image_format
-                .unwrap_or(ImageFormat(image::ImageFormat::Png))
-                .format(),
-        )?;
+    match (colorspace, wide) {
+        (ColorSpace::Gray, false) => DynamicImage::ImageLuma8(image.to_luma8()),
+        (ColorSpace::Gray, true) => DynamicImage::ImageLuma16(image.to_luma16()),
+        (ColorSpace::Rgb, false) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (ColorSpace::Rgb, true) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        (ColorSpace::Rgba, false) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        (ColorSpace::Rgba, true) => DynamicImage::ImageRgba16(image.to_rgba16()),
+    }
+}
+
+fn apply_watermark(image: &mut DynamicImage, overlay: &RgbaImage, watermark: Watermark) {
+    let mut overlay = overlay.clone();
+    if watermark.opacity < 1.0 {
+        for pixel in overlay.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * watermark.opacity).round() as u8;
+        }
+    }
+    let (x, y) = watermark
+        .position
+        .offset(image.dimensions(), overlay.dimensions());
+    imageops::overlay(image, &overlay, x, y);
+}
+
+fn watermark_cache() -> &'static Mutex<WatermarkCache> {
+    WATERMARK_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+//The stored original, which every transform starts from.
+async fn source_location(
+    tenant: &Tenant,
+    image_id: Uuid,
+    database: &Database,
+) -> Result<ImagePath, TranscoderError> {
+    match database
+        .get_source_location(tenant, &image_id, &Utc::now())
+        .await
+    {
+        Ok(image_path) => Ok(image_path),
+        Err(GetImageError::FoundButNotInFormat(..)) | Err(GetImageError::NotFound) => {
+            Err(TranscoderError::NotFound)
+        }
+        Err(GetImageError::NotComputed | GetImageError::BeingWritten(..)) => {
+            Err(TranscoderError::NotComputed)
+        }
+        Err(GetImageError::InternalServerError(e)) => {
+            Err(TranscoderError::InternalServerError(Box::new(e)))
+        }
+    }
+}
+
+//Like watermarks the database lookup always runs, hot sources skip the decode through the decode cache.
+//Only the source's header is read, to tell whether a resize would keep its dimensions or a color
+//conversion its color type. Targets left without transforms are served like plain format requests,
+//straight from the stored file when the format matches it.
+async fn canonical_target(
+    settings: TranscodeTarget,
+    image_path: &ImagePath,
+) -> Result<TranscodeTarget, TranscoderError> {
+    let source_header = if settings.needs_source_header() || settings.scales() {
+        source_header(image_path.clone()).await
+    } else {
+        None
+    };
+    if let Some(((width, height), _)) = source_header {
+        settings
+            .check_dimensions(width, height)
+            .map_err(TranscoderError::InvalidTarget)?;
+    }
+    Ok(settings.canonical(
+        source_header.map(|(dimensions, _)| dimensions),
+        source_header.map(|(_, color)| color),
+    ))
+}
+
+async fn source_dimensions(image_path: ImagePath) -> Option<(u32, u32)> {
+    source_header(image_path)
+        .await
+        .map(|(dimensions, _)| dimensions)
+}
+
+//Read from the header of the stored original, None when it can't be read.
+async fn source_header(image_path: ImagePath) -> Option<((u32, u32), ColorType)> {
+    run_blocking(move || {
+        let decoder = ImageReader::open(image_path)
+            .and_then(ImageReader::with_guessed_format)
+            .ok()?
+            .into_decoder()
+            .ok()?;
+        Some((decoder.dimensions(), decoder.color_type()))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+//Dimensions of the result along with its size and transcode time extrapolated from recent transcodes
+//to the same format, nothing is decoded or encoded.
+pub async fn estimate(
+    tenant: &Tenant,
+    image_id: Uuid,
+    settings: TranscodeTarget,
+    database: &Database,
+) -> Result<TranscodeEstimate, TranscoderError> {
+    let image_path = source_location(tenant, image_id, database).await?;
+    let source_dimensions = source_dimensions(image_path).await;
+    if let Some((source_width, source_height)) = source_dimensions {
+        settings
+            .check_dimensions(source_width, source_height)
+            .map_err(TranscoderError::InvalidTarget)?;
+    }
+    let image_format = settings.image_format.unwrap_or_default();
+    let rates = format_rates(image_format);
+    //Sizes and times scale with the pixels, so without dimensions there is nothing to extrapolate.
+    let pixels = source_dimensions.map(|(source_width, source_height)| {
+        let (width, height) = settings.output_dimensions(source_width, source_height);
+        let source_pixels = source_width as f64 * source_height as f64;
+        ((width, height), source_pixels, width as f64 * height as f64)
+    });
+    let extrapolated = rates.zip(pixels);
+    Ok(TranscodeEstimate {
+        width: pixels.map(|((width, _), _, _)| width),
+        height: pixels.map(|((_, height), _, _)| height),
+        format: image_format.to_str(),
+        bytes: extrapolated.map(|((rates, _), (_, _, output_pixels))| {
+            (rates.bytes_per_pixel * output_pixels).round() as u64
+        }),
+        millis: extrapolated.map(|((rates, _), (_, source_pixels, output_pixels))| {
+            (rates.secs_per_pixel * (source_pixels + output_pixels) * 1000.0).round() as u64
+        }),
+        samples: rates.map_or(0, |(_, samples)| samples),
+    })
+}
+
+pub async fn decode_source(
+    tenant: &Tenant,
+    image_id: Uuid,
+    database: &Database,
+) -> Result<Arc<DynamicImage>, TranscoderError> {
+    let image_path = source_location(tenant, image_id, database).await?;
+    decode_source_at(tenant, image_id, image_path).await
+}
+
+//A contact sheet cell counts against the transform limit like a resize into it, and large JPEGs can
+//be decoded at a reduced size for it.
+pub async fn decode_for_thumbnail(
+    tenant: &Tenant,
+    image_id: Uuid,
+    cell: u32,
+    database: &Database,
+) -> Result<Arc<DynamicImage>, TranscoderError> {
+    let image_path = source_location(tenant, image_id, database).await?;
+    let target = TranscodeTarget::builder()
+        .with_size(Some(cell), Some(cell))
+        .build()
+        .map_err(TranscoderError::InvalidTarget)?;
+    check_transform_limit(image_id, &target)?;
+    decode_for_target(tenant, image_id, target, image_path).await
+}
+
+//For callers that already looked the source up.
+async fn decode_source_at(
+    tenant: &Tenant,
+    image_id: Uuid,
+    image_path: ImagePath,
+) -> Result<Arc<DynamicImage>, TranscoderError> {
+    if let Some(cache) = DECODE_CACHE.get() {
+        if let Some(image) = cache.lock().unwrap().get(tenant, image_id) {
+            return Ok(image);
+        }
+    }
 
-        Ok(bytes)
+    let image = run_blocking(move || {
+        let mut imagereader = ImageReader::open(image_path).map_err(ImageError::IoError)?;
+        imagereader.limits(decode_limits());
+        imagereader.decode().map(Arc::new)
     })
     .await
     .expect("Could not join threads")
+    .map_err(TranscoderError::ImageError)?;
+    debug!("Decoded source of image {image_id}");
+
+    if let Some(cache) = DECODE_CACHE.get() {
+        cache
+            .lock()
+            .unwrap()
+            .insert(tenant.clone(), image_id, image.clone());
+    }
+    Ok(image)
 }
 
-pub async fn get_image(
+//Resizes of JPEG sources that shrink them a lot decode at a reduced size, unless a full decode is cached.
+//Scaled decodes aren't cached themselves, other transforms of the same source need the full image.
+#[cfg(feature = "jpeg-scaling")]
+async fn decode_for_target(
+    tenant: &Tenant,
     image_id: Uuid,
     settings: TranscodeTarget,
+    image_path: ImagePath,
+) -> Result<Arc<DynamicImage>, TranscoderError> {
+    //A scale left unresolved because the header couldn't be read would apply to the scaled decode again.
+    if !settings.resizes() || settings.scale.is_some() {
+        return decode_source_at(tenant, image_id, image_path).await;
+    }
+    let cached = DECODE_CACHE
+        .get()
+        .is_some_and(|cache| cache.lock().unwrap().get(tenant, image_id).is_some());
+    if cached || image_path.image_format() != ImageFormat::JPG {
+        return decode_source_at(tenant, image_id, image_path).await;
+    }
+
+    let scaled_path = image_path.clone();
+    let scaled = run_blocking(move || {
+        let data = std::fs::read(scaled_path).map_err(ImageError::IoError)?;
+        jpeg_scaling::decode_scaled(&data, decode_limits(), |width, height| {
+            let (crop_width, crop_height) = settings.cropped_dimensions(width, height);
+            let (max_width, max_height) = settings.dimensions(crop_width, crop_height);
+            let (target_width, target_height) =
+                fit_dimensions(crop_width, crop_height, max_width, max_height);
+            //The crop is taken from the scaled image, so the whole image has to be scaled for it.
+            (
+                (target_width as u64 * width as u64).div_ceil(crop_width as u64) as u32,
+                (target_height as u64 * height as u64).div_ceil(crop_height as u64) as u32,
+            )
+        })
+    })
+    .await
+    .expect("Could not join threads")
+    .map_err(TranscoderError::ImageError)?;
+    match scaled {
+        Some(image) => {
+            debug!("Decoded source of image {image_id} at {}x{}", image.width(), image.height());
+            Ok(Arc::new(image))
+        }
+        None => decode_source_at(tenant, image_id, image_path).await,
+    }
+}
+
+#[cfg(not(feature = "jpeg-scaling"))]
+async fn decode_for_target(
+    tenant: &Tenant,
+    image_id: Uuid,
+    _settings: TranscodeTarget,
+    image_path: ImagePath,
+) -> Result<Arc<DynamicImage>, TranscoderError> {
+    decode_source_at(tenant, image_id, image_path).await
+}
+
+//The database lookup runs every time so deleted or expired watermarks stop applying, only the decode is cached.
+async fn load_watermark(
+    tenant: &Tenant,
+    image_id: Uuid,
     database: &Database,
-    ttl : Option<Duration>
-) -> Result<Vec<u8>, TranscoderError> {
-    let database_result = database
-        .get_image_location(&image_id, settings.image_format.unwrap_or_default(), &Utc::now())
-        .await;
-    match database_result {
-        Ok(image_path) => {
-            if settings.image_width.is_none() && settings.image_height.is_none() {
-                tokio::fs::read(image_path)
+) -> Result<Arc<RgbaImage>, TranscoderError> {
+    let image_path = match source_location(tenant, image_id, database).await {
+        Ok(image_path) => image_path,
+        Err(TranscoderError::NotFound) | Err(TranscoderError::NotComputed) => {
+            return Err(TranscoderError::WatermarkNotFound)
+        }
+        Err(e) => return Err(e),
+    };
+
+    let key = (tenant.clone(), image_id);
+    if let Some(watermark) = watermark_cache().lock().unwrap().get(&key) {
+        return Ok(watermark.clone());
+    }
+
+    let watermark = run_blocking(move || {
+        let mut imagereader = ImageReader::open(image_path).map_err(ImageError::IoError)?;
+        imagereader.limits(decode_limits());
+        imagereader.decode().map(|image| Arc::new(image.to_rgba8()))
+    })
+    .await
+    .expect("Could not join threads")
+    .map_err(TranscoderError::ImageError)?;
+
+    let mut cache = watermark_cache().lock().unwrap();
+    if cache.len() >= MAX_CACHED_WATERMARKS {
+        cache.clear();
+    }
+    cache.insert(key, watermark.clone());
+    Ok(watermark)
+}
+
+pub enum ImageData {
+    Buffered(Vec<u8>),
+    //An unchanged stored file sent as it is read, the checksum recorded when it was written stands in for its hash.
+    Streamed {
+        file: tokio::fs::File,
+        len: u64,
+        checksum: String,
+    },
+}
+
+impl ImageData {
+    //Streamed files are read whole, for responses that need every byte at once.
+    pub async fn into_bytes(self) -> Result<Vec<u8>, TranscoderError> {
+        match self {
+            ImageData::Buffered(data) => Ok(data),
+            ImageData::Streamed { mut file, len, .. } => {
+                let mut data = Vec::with_capacity(len as usize);
+                file.read_to_end(&mut data)
                     .await
-                    .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))
-            } else {
-                let image = tokio::task::spawn_blocking(move || {
-                    ImageReader::open(image_path)
-                        .unwrap()
-                        .decode()
-                        .expect("file path returned by database was unable to be opened")
-                })
+                    .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))?;
+                Ok(data)
+            }
+        }
+    }
+}
+
+pub struct ServedImage {
+    pub data: ImageData,
+    pub image_format: ImageFormat,
+    pub dimensions: Option<(u32, u32)>,
+    pub available_formats: Option<Vec<ImageFormat>>,
+    pub cache_hit: bool,
+    //Set for kept uploads, which can be in a format this server doesn't handle.
+    pub content_type: Option<String>,
+}
+
+impl ServedImage {
+    //Stored files are never decoded to be served, only their header is read for the dimensions.
+    fn hit(data: Vec<u8>, image_format: ImageFormat) -> Self {
+        let dimensions = probe_dimensions(&data, image_format);
+        ServedImage {
+            data: ImageData::Buffered(data),
+            image_format,
+            dimensions,
+            available_formats: None,
+            cache_hit: true,
+            content_type: None,
+        }
+    }
+
+    fn miss((data, dimensions): (Vec<u8>, (u32, u32)), image_format: ImageFormat) -> Self {
+        ServedImage {
+            data: ImageData::Buffered(data),
+            image_format,
+            dimensions: Some(dimensions),
+            available_formats: None,
+            cache_hit: false,
+            content_type: None,
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        self.content_type
+            .as_deref()
+            .unwrap_or_else(|| self.image_format.to_mime_type())
+    }
+}
+
+//None when the header can't be read, e.g. AVIF which this build can only encode.
+fn probe_dimensions(data: &[u8], image_format: ImageFormat) -> Option<(u32, u32)> {
+    ImageReader::with_format(Cursor::new(data), image_format.format())
+        .into_dimensions()
+        .ok()
+}
+
+//Stored files are streamed unless they have to be read whole, either to be verified or to hash
+//an ETag for files written before checksums were recorded.
+async fn read_stored(
+    tenant: &Tenant,
+    image_id: Uuid,
+    image_format: ImageFormat,
+    image_path: ImagePath,
+    database: &Database,
+) -> Result<ServedImage, TranscoderError> {
+    let stored = match VERIFY_ON_READ.get() {
+        Some(_) => None,
+        None => database
+            .stored_checksum(tenant, &image_id, image_format)
+            .await
+            .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?,
+    };
+    let Some((checksum, _)) = stored else {
+        let data = tokio::fs::read(image_path)
+            .await
+            .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))?;
+        let data = verify_stored(tenant, image_id, image_format, data, database).await?;
+        return Ok(ServedImage::hit(data, image_format));
+    };
+
+    let file = tokio::fs::File::open(&image_path)
+        .await
+        .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))?
+        .len();
+    let dimensions = run_blocking(move || {
+        let file = std::fs::File::open(image_path).ok()?;
+        ImageReader::with_format(BufReader::new(file), image_format.format())
+            .into_dimensions()
+            .ok()
+    })
+    .await
+    .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
+    Ok(ServedImage {
+        data: ImageData::Streamed {
+            file,
+            len,
+            checksum,
+        },
+        image_format,
+        dimensions,
+        available_formats: None,
+        cache_hit: true,
+        content_type: None,
+    })
+}
+
+//The kept upload verbatim if there is one, otherwise the stored file in whatever format it was stored in.
+pub async fn get_original(
+    tenant: &Tenant,
+    image_id: Uuid,
+    database: &Database,
+) -> Result<ServedImage, TranscoderError> {
+    match database
+        .get_upload_location(tenant, &image_id, &Utc::now())
+        .await
+    {
+        Ok(Some((upload_path, content_type))) => {
+            let data = tokio::fs::read(upload_path)
+                .await
+                .map_err(|x| TranscoderError::InternalServerError(Box::new(x)))?;
+            let image_format = image::guess_format(&data)
+                .ok()
+                .and_then(ImageFormat::from_image_format)
+                .unwrap_or_default();
+            return Ok(ServedImage {
+                content_type: Some(content_type),
+                ..ServedImage::hit(data, image_format)
+            });
+        }
+        Ok(None) => {}
+        Err(GetImageError::NotFound) | Err(GetImageError::FoundButNotInFormat(..)) => {
+            return Err(TranscoderError::NotFound)
+        }
+        Err(GetImageError::NotComputed | GetImageError::BeingWritten(..)) => {
+            return Err(TranscoderError::NotComputed)
+        }
+        Err(GetImageError::InternalServerError(e)) => {
+            return Err(TranscoderError::InternalServerError(Box::new(e)))
+        }
+    }
+
+    let image_path = source_location(tenant, image_id, database).await?;
+    let image_format = image_path.image_format();
+    read_stored(tenant, image_id, image_format, image_path, database).await
+}
+
+//The transformed RGBA8 pixel buffer with its dimensions, never stored since there's nothing to encode.
+pub async fn raw_pixels(
+    tenant: &Tenant,
+    image_id: Uuid,
+    settings: TranscodeTarget,
+    database: &Database,
+) -> Result<(Vec<u8>, (u32, u32)), TranscoderError> {
+    let watermark = match settings.watermark {
+        Some(watermark) => Some(load_watermark(tenant, watermark.image_id, database).await?),
+        None => None,
+    };
+    let image_path = source_location(tenant, image_id, database).await?;
+    let settings = canonical_target(settings, &image_path).await?;
+    check_transform_limit(image_id, &settings)?;
+    let image = decode_for_target(tenant, image_id, settings, image_path).await?;
+    run_blocking(move || {
+        let pixels = apply_transforms(image, settings, watermark).to_rgba8();
+        let dimensions = pixels.dimensions();
+        (pixels.into_raw(), dimensions)
+    })
+    .await
+    .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))
+}
+
+//Sets the density of an image that is already encoded, neither the source nor the image is decoded for it.
+pub async fn with_density(image: ServedImage, dpi: u16) -> Result<ServedImage, TranscoderError> {
+    let image_format = image.image_format;
+    let data = image.data.into_bytes().await?;
+    let data = run_blocking(move || density::set_density(data, image_format, dpi))
+        .await
+        .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
+    Ok(ServedImage {
+        data: ImageData::Buffered(data),
+        ..image
+    })
+}
+
+pub async fn get_image(
+    tenant: &Tenant,
+    image_id: Uuid,
+    settings: TranscodeTarget,
+    database: &Database,
+    ttl : Option<Duration>,
+    store: bool,
+) -> Result<ServedImage, TranscoderError> {
+    //A single lookup of the source serves canonicalizing, decoding and reading its ICC profile.
+    let (settings, source) = if settings.transforms() || settings.image_format.is_none() {
+        let image_path = source_location(tenant, image_id, database).await?;
+        let settings = if settings.transforms() {
+            canonical_target(settings, &image_path).await?
+        } else {
+            settings
+        };
+        (settings, Some(image_path))
+    } else {
+        (settings, None)
+    };
+    let watermark = match settings.watermark {
+        Some(watermark) => Some(load_watermark(tenant, watermark.image_id, database).await?),
+        None => None,
+    };
+
+    let image_format = settings.image_format.unwrap_or_default();
+    if let Some(image_path) = source {
+        //Without a format or anything to change the stored original is served as it is, in its own format.
+        if settings.image_format.is_none() && !settings.transforms() {
+            let image_format = image_path.image_format();
+            return read_stored(tenant, image_id, image_format, image_path, database).await;
+        }
+        //Transforms start from the stored original so they don't compound the artifacts of a lossy variant.
+        if settings.transforms() {
+            check_transform_limit(image_id, &settings)?;
+            let image = decode_for_target(tenant, image_id, settings, image_path.clone()).await?;
+            let icc_profile = source_icc_profile(image_path).await?;
+            return transcode(image, settings, watermark, icc_profile, false)
                 .await
-                .unwrap();
+                .map(|encoded| ServedImage::miss(encoded, image_format))
+                .map_err(TranscoderError::ImageError);
+        }
+    }
 
-                transcode(image, settings)
+    let key = variant_key(tenant, &image_id, image_format);
+    let mut computed_events = database.computed_events();
+    let deadline = Instant::now() + TRANSCODE_WAIT_TIMEOUT;
+    loop {
+        let database_result = database
+            .get_image_location(tenant, &image_id, image_format, &Utc::now())
+            .await;
+        match database_result {
+            Ok(image_path) => {
+                return read_stored(tenant, image_id, image_format, image_path, database).await
+            }
+            Err(GetImageError::NotComputed) => return Err(TranscoderError::NotComputed),
+            //Unstored variants are built from the original like transforms, there is nothing to coordinate.
+            Err(
+                GetImageError::FoundButNotInFormat(image_path, available_formats)
+                | GetImageError::BeingWritten(image_path, available_formats),
+            ) if !store => {
+                check_transform_limit(image_id, &settings)?;
+                let image = decode_source_at(tenant, image_id, image_path.clone()).await?;
+                let icc_profile = source_icc_profile(image_path).await?;
+                return transcode(image, settings, watermark, icc_profile, false)
+                    .await
+                    .map(|encoded| ServedImage {
+                        available_formats: Some(available_formats),
+                        ..ServedImage::miss(encoded, image_format)
+                    })
+                    .map_err(TranscoderError::ImageError);
+            }
+            //While another request, on this or another instance, is writing the format the claim
+            //only succeeds once that claim went stale.
+            Err(
+                GetImageError::FoundButNotInFormat(image_path, available_formats)
+                | GetImageError::BeingWritten(image_path, available_formats),
+            ) => {
+                check_transform_limit(image_id, &settings)?;
+                let claim = database
+                    .claim_variant(tenant, &image_id, image_format, ttl)
                     .await
-                    .map_err(TranscoderError::ImageError)
+                    .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
+                if let Some(claim) = claim {
+                    debug!("Claimed {key} for transcoding");
+                    let served =
+                        transcode_missing(settings, database, claim, watermark, image_path).await?;
+                    return Ok(ServedImage {
+                        available_formats: Some(available_formats),
+                        ..served
+                    });
+                }
+                if Instant::now() >= deadline {
+                    return Err(TranscoderError::NotComputed);
+                }
+            }
+            Err(GetImageError::NotFound) => return Err(TranscoderError::NotFound),
+            Err(GetImageError::InternalServerError(e)) => {
+                return Err(TranscoderError::InternalServerError(Box::new(e)))
+            }
+        }
+        wait_for_variant(&mut computed_events, &key, deadline).await;
+    }
+}
+
+//Until the format is announced as computed or discarded, polling now and then in case an event was missed.
+async fn wait_for_variant(events: &mut broadcast::Receiver<String>, key: &str, deadline: Instant) {
+    let wait_until = deadline.min(Instant::now() + TRANSCODE_WAIT_INTERVAL);
+    let announced = async {
+        loop {
+            match events.recv().await {
+                Ok(computed) if computed == key => return,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
             }
         }
-        Err(crate::database::GetImageError::NotComputed) => Err(TranscoderError::NotComputed),
-        Err(crate::database::GetImageError::FoundButNotInFormat(image_path)) => {
-            let wrong_format_image = tokio::task::spawn_blocking(move || {
-                let mut imagereader = ImageReader::open(image_path).unwrap();
-                imagereader.no_limits();
-                imagereader.decode().unwrap()
+    };
+    let _ = tokio::time::timeout_at(wait_until.into(), announced).await;
+}
+
+//Stores the claimed format, the claim is given up when the transcode fails.
+async fn transcode_missing(
+    settings: TranscodeTarget,
+    database: &Database,
+    claim: VariantClaim,
+    watermark: Option<Arc<RgbaImage>>,
+    image_path: ImagePath,
+) -> Result<ServedImage, TranscoderError> {
+    let image_format = settings.image_format.unwrap_or_default();
+    //The profile comes from the decoder of the decode, so the source is only opened once.
+    let decoded = run_blocking(move || {
+        let mut imagereader = ImageReader::open(image_path)?;
+        imagereader.limits(decode_limits());
+        let mut decoder = imagereader.into_decoder()?;
+        let icc_profile = preserve_icc()
+            .then(|| decoder.icc_profile().ok().flatten())
+            .flatten();
+        DynamicImage::from_decoder(decoder).map(|image| (image, icc_profile))
+    })
+    .await
+    .expect("Could not join threads");
+    let encoded = match decoded {
+        Ok((image, icc_profile)) => {
+            transcode(Arc::new(image), settings, watermark, icc_profile, true).await
+        }
+        Err(e) => Err(e),
+    };
+    match encoded {
+        Ok(encoded) => {
+            database.save_raw_image(claim, encoded.0.clone());
+            Ok(ServedImage::miss(encoded, image_format))
+        }
+        Err(e) => {
+            database.release_claim(claim).await;
+            Err(TranscoderError::ImageError(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    #[test]
+    fn scale_halves_the_source() {
+        let target = TranscodeTarget::builder().with_scale(0.5).build().unwrap();
+        assert_eq!(target.output_dimensions(800, 600), (400, 300));
+        assert!(target.check_dimensions(800, 600).is_ok());
+    }
+
+    #[test]
+    fn scale_conflicts_with_width() {
+        let target = TranscodeTarget::builder()
+            .with_scale(0.5)
+            .with_size(Some(400), None)
+            .build();
+        assert!(target.is_err());
+    }
+
+    #[test]
+    fn builder_keeps_what_it_was_given() {
+        let target = TranscodeTarget::builder()
+            .with_format(ImageFormat::AVIF)
+            .with_size(Some(400), None)
+            .with_quality(60)
+            .with_speed(8)
+            .with_colorspace(ColorSpace::Rgba)
+            .with_sharpen(Sharpen {
+                amount: 1.5,
+                threshold: 4,
             })
+            .build()
+            .unwrap();
+        assert_eq!(target.image_format, Some(ImageFormat::AVIF));
+        assert_eq!((target.image_width, target.image_height), (Some(400), None));
+        assert_eq!((target.quality, target.speed), (Some(60), Some(8)));
+        assert_eq!(target.colorspace, Some(ColorSpace::Rgba));
+        assert!(target.sharpen.is_some_and(|sharpen| sharpen.threshold == 4));
+
+        assert!(TranscodeTarget::builder().build().is_ok());
+    }
+
+    #[test]
+    fn builder_takes_the_format_of_the_pipeline() {
+        let pipeline: Pipeline = "grayscale,format(webp)".parse().unwrap();
+        let target = TranscodeTarget::builder().with_pipeline(pipeline).build().unwrap();
+        assert_eq!(target.image_format, Some(ImageFormat::WEBP));
+
+        let target = TranscodeTarget::builder()
+            .with_pipeline(pipeline)
+            .with_format(ImageFormat::PNG)
+            .build();
+        assert!(target.is_err());
+    }
+
+    #[test]
+    fn builder_refuses_invalid_targets() {
+        let sharpen = |amount| Sharpen {
+            amount,
+            threshold: 0,
+        };
+        let watermark = |opacity| Watermark {
+            image_id: Uuid::nil(),
+            position: WatermarkPosition::default(),
+            opacity,
+        };
+        let pipeline: Pipeline = "grayscale".parse().unwrap();
+        let invalid = [
+            TranscodeTarget::builder().with_quality(0),
+            TranscodeTarget::builder().with_quality(101),
+            TranscodeTarget::builder().with_speed(5),
+            TranscodeTarget::builder().with_format(ImageFormat::AVIF).with_speed(11),
+            TranscodeTarget::builder().with_scale(0.0),
+            TranscodeTarget::builder().with_crop(f32::NAN),
+            TranscodeTarget::builder().with_size(Some(MAX_RESIZE_EDGE + 1), None),
+            TranscodeTarget::builder().with_format(ImageFormat::JPG).with_bit_depth(16),
+            TranscodeTarget::builder().with_bit_depth(12),
+            TranscodeTarget::builder().with_format(ImageFormat::JPG).with_colorspace(ColorSpace::Rgba),
+            TranscodeTarget::builder().with_format(ImageFormat::HDR).with_colorspace(ColorSpace::Gray),
+            TranscodeTarget::builder().with_sharpen(sharpen(0.0)),
+            TranscodeTarget::builder().with_sharpen(sharpen(MAX_SHARPEN_AMOUNT + 1.0)),
+            TranscodeTarget::builder().with_watermark(watermark(1.5)),
+            TranscodeTarget::builder().with_pipeline(pipeline).with_size(Some(400), None),
+        ];
+        for builder in invalid {
+            assert!(builder.build().is_err(), "{builder:?} was built");
+        }
+    }
+
+    fn geometry_error(
+        scale: Option<f32>,
+        width: Option<u32>,
+        height: Option<u32>,
+        aspect: Option<f32>,
+    ) -> Option<String> {
+        TranscodeTarget::builder()
+            .with_scale(scale)
+            .with_size(width, height)
+            .with_crop(aspect)
+            .build()
+            .err()
+            .map(|e| e.to_string())
+    }
+
+    #[test]
+    fn each_geometry_conflict_is_named() {
+        let conflicts = [
+            (
+                (Some(0.5), Some(400), None, None),
+                "scale conflicts with width, pass either",
+            ),
+            (
+                (Some(0.5), None, Some(300), None),
+                "scale conflicts with height, pass either",
+            ),
+            (
+                (Some(0.5), Some(400), Some(300), None),
+                "scale conflicts with width and height, pass either",
+            ),
+            (
+                (None, Some(400), Some(300), Some(2.0)),
+                "aspect 2.000 conflicts with width 400 and height 300, pass at most one of width and height",
+            ),
+        ];
+        for ((scale, width, height, aspect), message) in conflicts {
+            assert_eq!(
+                geometry_error(scale, width, height, aspect).as_deref(),
+                Some(message)
+            );
+        }
+    }
+
+    #[test]
+    fn geometry_without_conflicts_is_accepted() {
+        let combinations = [
+            (None, Some(400), Some(300), None),
+            (None, Some(400), None, Some(2.0)),
+            (None, None, Some(300), Some(2.0)),
+            (None, Some(400), Some(200), Some(2.0)),
+            (Some(0.5), None, None, Some(2.0)),
+        ];
+        for (scale, width, height, aspect) in combinations {
+            assert_eq!(geometry_error(scale, width, height, aspect), None);
+        }
+    }
+
+    #[test]
+    fn canonical_scale_resolves_against_the_source() {
+        let target = TranscodeTarget::builder()
+            .with_scale(0.25)
+            .build()
+            .unwrap()
+            .canonical(Some((800, 600)), None);
+        assert_eq!(target.scale, None);
+        assert_eq!((target.image_width, target.image_height), (Some(200), Some(150)));
+        //A decode that was already shrunk only gets fitted into the same box.
+        assert_eq!(target.output_dimensions(400, 300), (200, 150));
+    }
+
+    #[test]
+    fn pipeline_steps_apply_in_order() {
+        let image = Arc::new(DynamicImage::ImageRgb8(RgbImage::new(200, 100)));
+        let apply = |pipeline: &str| {
+            let pipeline: Pipeline = pipeline.parse().unwrap();
+            apply_pipeline(image.clone(), &pipeline).dimensions()
+        };
+        assert_eq!(apply("rotate(90),resize(100,50)"), (25, 50));
+        assert_eq!(apply("resize(100,50),rotate(90)"), (50, 100));
+        let pipeline: Pipeline = "rotate(90),resize(100,50)".parse().unwrap();
+        let target = TranscodeTarget::builder().with_pipeline(pipeline).build().unwrap();
+        assert_eq!(target.output_dimensions(200, 100), (25, 50));
+    }
+
+    #[test]
+    fn oversized_scale_is_refused() {
+        for scale in [50.0, 1e6] {
+            let target = TranscodeTarget::builder().with_scale(scale).build().unwrap();
+            assert!(target.check_dimensions(800, 600).is_err(), "scale {scale}");
+        }
+    }
+
+    #[test]
+    fn source_width_shares_the_key_of_no_resize() {
+        let resized = TranscodeTarget::builder()
+            .with_format(ImageFormat::WEBP)
+            .with_size(Some(800), None)
+            .build()
+            .unwrap()
+            .canonical(Some((800, 600)), None);
+        let plain = TranscodeTarget::builder()
+            .with_format(ImageFormat::WEBP)
+            .build()
+            .unwrap()
+            .canonical(Some((800, 600)), None);
+        assert_eq!(resized.cache_key(), plain.cache_key());
+    }
+
+    #[test]
+    fn default_format_shares_the_key_of_no_format() {
+        let implicit = TranscodeTarget::builder().with_size(Some(400), None).build().unwrap();
+        let explicit = TranscodeTarget::builder()
+            .with_format(ImageFormat::default())
+            .with_size(Some(400), None)
+            .build()
+            .unwrap();
+        assert_eq!(implicit.cache_key(), explicit.cache_key());
+        let other = TranscodeTarget::builder().with_size(Some(401), None).build().unwrap();
+        assert_ne!(implicit.cache_key(), other.cache_key());
+    }
+
+    #[tokio::test]
+    async fn blocking_work_runs_on_the_transcode_pool() {
+        let name = run_blocking(|| std::thread::current().name().map(str::to_string))
             .await
             .unwrap();
+        assert!(name.is_some_and(|name| name.starts_with("transcode-")));
+    }
 
-            let data = transcode(wrong_format_image, settings)
-                .await
-                .map_err(TranscoderError::ImageError)?;
-            database
-                .save_raw_image(
-                    data.clone(),
-                    image_id,
-                    settings.image_format.unwrap_or_default(),
-                    ttl
-                )
-                .await
-                .map_err(|e| TranscoderError::InternalServerError(Box::new(e)))?;
-            Ok(data)
+    #[tokio::test]
+    async fn file_operations_proceed_while_every_transcode_thread_is_busy() {
+        let threads = pool().current_num_threads();
+        let started = Arc::new(AtomicUsize::new(0));
+        let released = Arc::new(AtomicBool::new(false));
+        for _ in 0..threads {
+            let (started, released) = (started.clone(), released.clone());
+            spawn(move || {
+                started.fetch_add(1, Ordering::SeqCst);
+                while !released.load(Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            });
         }
-        Err(crate::database::GetImageError::NotFound) => Err(TranscoderError::NotFound),
-        Err(crate::database::GetImageError::InternalServerError(e)) => {
-            Err(TranscoderError::InternalServerError(Box::new(e)))
+        while started.load(Ordering::SeqCst) < threads {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
+
+        let folder = tempfile::TempDir::new().unwrap();
+        let path = folder.path().join("file");
+        let file_operations = async {
+            tokio::fs::write(&path, b"data").await.unwrap();
+            tokio::fs::read(&path).await.unwrap()
+        };
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), file_operations).await;
+        released.store(true, Ordering::SeqCst);
+        assert_eq!(read.expect("file operations waited for the transcode pool"), b"data");
+    }
+
+    //The sampling factor byte of every component in the frame header.
+    #[cfg(feature = "optimize")]
+    fn jpeg_sampling_factors(data: &[u8]) -> Vec<u8> {
+        let start = data
+            .windows(2)
+            .position(|marker| marker[0] == 0xFF && (0xC0..=0xC2).contains(&marker[1]))
+            .expect("no frame header");
+        let components = data[start + 9] as usize;
+        (0..components).map(|index| data[start + 11 + index * 3]).collect()
+    }
+
+    #[cfg(feature = "optimize")]
+    #[test]
+    fn optimized_jpeg_keeps_full_chroma() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+        }));
+        let settings = TranscodeTarget::builder().build().unwrap();
+        let data = encode_optimized(&image, ImageFormat::JPG, None, None, settings).unwrap();
+        assert_eq!(jpeg_sampling_factors(&data), vec![0x11, 0x11, 0x11]);
     }
 }
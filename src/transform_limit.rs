@@ -0,0 +1,112 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+//Sweeping every image on each check would be wasteful, stale entries are only dropped once this many are tracked.
+const SWEEP_THRESHOLD: usize = 10_000;
+//Beyond this the image that was transformed least recently is forgotten to make room.
+const MAX_TRACKED_IMAGES: usize = 100_000;
+
+//Distinct transforms computed per image within a sliding window.
+pub struct TransformLimiter {
+    max_transforms: usize,
+    window: Duration,
+    max_images: usize,
+    //Doubles with what is left after a sweep, so images that are all still active aren't swept on every check.
+    next_sweep: usize,
+    images: HashMap<Uuid, VecDeque<(Instant, u64)>>,
+}
+
+impl TransformLimiter {
+    pub fn new(max_transforms: usize, window: Duration) -> TransformLimiter {
+        TransformLimiter {
+            max_transforms,
+            window,
+            max_images: MAX_TRACKED_IMAGES,
+            next_sweep: SWEEP_THRESHOLD,
+            images: HashMap::new(),
+        }
+    }
+
+    //Transforms already seen within the window are always allowed, new ones only while the image is under its limit.
+    pub fn allow(&mut self, image_id: Uuid, transform: u64) -> bool {
+        let now = Instant::now();
+        if self.images.len() >= self.next_sweep {
+            self.sweep(now);
+        }
+        if self.images.len() >= self.max_images && !self.images.contains_key(&image_id) {
+            self.forget_least_recent();
+        }
+
+        let window = self.window;
+        let seen = self.images.entry(image_id).or_default();
+        while seen
+            .front()
+            .is_some_and(|(first_seen, _)| now.duration_since(*first_seen) >= window)
+        {
+            seen.pop_front();
+        }
+        if seen.iter().any(|(_, seen_transform)| *seen_transform == transform) {
+            return true;
+        }
+        if seen.len() >= self.max_transforms {
+            return false;
+        }
+        seen.push_back((now, transform));
+        true
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let window = self.window;
+        self.images.retain(|_, seen| {
+            seen.back()
+                .is_some_and(|(first_seen, _)| now.duration_since(*first_seen) < window)
+        });
+        self.next_sweep = SWEEP_THRESHOLD.max(self.images.len() * 2);
+    }
+
+    fn forget_least_recent(&mut self) {
+        let least_recent = self
+            .images
+            .iter()
+            .min_by_key(|(_, seen)| seen.back().map(|(last_seen, _)| *last_seen))
+            .map(|(image_id, _)| *image_id);
+        if let Some(image_id) = least_recent {
+            self.images.remove(&image_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_transforms_stop_at_the_limit() {
+        let mut limiter = TransformLimiter::new(2, Duration::from_secs(60));
+        let image_id = Uuid::new_v4();
+        assert!(limiter.allow(image_id, 1));
+        assert!(limiter.allow(image_id, 2));
+        assert!(!limiter.allow(image_id, 3));
+        assert!(limiter.allow(image_id, 1));
+        assert!(limiter.allow(Uuid::new_v4(), 3));
+    }
+
+    #[test]
+    fn tracked_images_are_capped() {
+        let mut limiter = TransformLimiter::new(1, Duration::from_secs(60));
+        limiter.max_images = 2;
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for image_id in [first, second, third] {
+            limiter.allow(image_id, 1);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(limiter.images.len(), 2);
+        assert!(!limiter.images.contains_key(&first));
+        assert!(!limiter.allow(third, 2));
+    }
+}
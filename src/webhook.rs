@@ -0,0 +1,112 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{database::Tenant, image_format::ImageFormat};
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    //The uploaded image was written and can be served.
+    Computed,
+    Deleted,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    //Left out for the default tenant.
+    #[serde(skip_serializing_if = "str::is_empty")]
+    tenant: &'a str,
+    id: String,
+    format: Option<&'static str>,
+    timestamp: DateTime<Utc>,
+}
+
+//Posts events to WEBHOOK_URL, signed with an HMAC-SHA256 of the body so receivers can tell they came from here.
+#[derive(Clone)]
+pub struct Webhooks {
+    url: Arc<str>,
+    secret: Arc<str>,
+    http: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(url: &str, secret: &str) -> Result<Webhooks, reqwest::Error> {
+        Ok(Webhooks {
+            url: url.into(),
+            secret: secret.into(),
+            http: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+        })
+    }
+
+    //Delivery happens in the background, failed attempts are logged and retried with a growing delay.
+    pub fn send(
+        &self,
+        event: WebhookEvent,
+        tenant: &Tenant,
+        image_id: Uuid,
+        image_format: Option<ImageFormat>,
+    ) {
+        let payload = Payload {
+            event,
+            tenant: tenant.as_str(),
+            id: image_id.to_string(),
+            format: image_format.map(ImageFormat::to_str),
+            timestamp: Utc::now(),
+        };
+        let body = serde_json::to_vec(&payload).expect("Webhook payloads always serialize");
+        let signature = sign(&self.secret, &body);
+        let webhooks = self.clone();
+        tokio::spawn(async move { webhooks.deliver(body, signature).await });
+    }
+
+    async fn deliver(&self, body: Vec<u8>, signature: String) {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = self
+                .http
+                .post(&*self.url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Delivered webhook on attempt {attempt}");
+                    return;
+                }
+                Ok(response) => warn!(
+                    "Webhook answered {} (attempt {attempt})",
+                    response.status()
+                ),
+                Err(e) => warn!("Could not deliver webhook (attempt {attempt}): {e:?}"),
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        warn!("Giving up on webhook after {MAX_ATTEMPTS} attempts");
+    }
+}
+
+//`sha256=` followed by the hex encoded HMAC of the body.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
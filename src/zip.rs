@@ -0,0 +1,104 @@
+use std::io;
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const VERSION: u16 = 20;
+//Names are always UTF-8.
+const FLAGS: u16 = 1 << 11;
+//Images are already compressed, so entries are stored as is.
+const METHOD_STORED: u16 = 0;
+//1980-01-01 00:00, the earliest DOS date.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+//Writes a zip one entry at a time, only the central directory is kept until `finish`.
+//Without zip64 the archive is limited to 4 GiB and 65535 entries.
+#[derive(Default)]
+pub struct ZipWriter {
+    entries: Vec<CentralEntry>,
+    offset: u64,
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "archive exceeds the zip size limits")
+}
+
+impl ZipWriter {
+    //The bytes of the entry, to be sent before the next one.
+    pub fn entry(&mut self, name: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let name_length = u16::try_from(name.len()).map_err(|_| too_large())?;
+        if self.entries.len() >= u16::MAX as usize {
+            return Err(too_large());
+        }
+        let crc = crc32fast::hash(data);
+
+        let mut bytes = Vec::with_capacity(30 + name.len() + data.len());
+        bytes.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&FLAGS.to_le_bytes());
+        bytes.extend_from_slice(&METHOD_STORED.to_le_bytes());
+        bytes.extend_from_slice(&DOS_TIME.to_le_bytes());
+        bytes.extend_from_slice(&DOS_DATE.to_le_bytes());
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&name_length.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(data);
+
+        self.offset += bytes.len() as u64;
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(bytes)
+    }
+
+    //The central directory and its end record, the last bytes of the archive.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        let directory_offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let mut bytes = Vec::new();
+        for entry in &self.entries {
+            bytes.extend_from_slice(&CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            bytes.extend_from_slice(&VERSION.to_le_bytes());
+            bytes.extend_from_slice(&VERSION.to_le_bytes());
+            bytes.extend_from_slice(&FLAGS.to_le_bytes());
+            bytes.extend_from_slice(&METHOD_STORED.to_le_bytes());
+            bytes.extend_from_slice(&DOS_TIME.to_le_bytes());
+            bytes.extend_from_slice(&DOS_DATE.to_le_bytes());
+            bytes.extend_from_slice(&entry.crc.to_le_bytes());
+            bytes.extend_from_slice(&entry.size.to_le_bytes());
+            bytes.extend_from_slice(&entry.size.to_le_bytes());
+            bytes.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            //Extra field, comment, disk number, internal and external attributes.
+            bytes.extend_from_slice(&[0; 12]);
+            bytes.extend_from_slice(&entry.offset.to_le_bytes());
+            bytes.extend_from_slice(entry.name.as_bytes());
+        }
+        let directory_size = u32::try_from(bytes.len()).map_err(|_| too_large())?;
+        let entry_count = self.entries.len() as u16;
+
+        bytes.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        //Number of this disk and of the disk holding the directory.
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&entry_count.to_le_bytes());
+        bytes.extend_from_slice(&entry_count.to_le_bytes());
+        bytes.extend_from_slice(&directory_size.to_le_bytes());
+        bytes.extend_from_slice(&directory_offset.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        Ok(bytes)
+    }
+}
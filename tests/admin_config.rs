@@ -0,0 +1,46 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{json, Value};
+
+const ADMIN_TOKEN: &str = "admin-config-test-token";
+
+async fn config(server: &TestServer) -> Value {
+    let response = server
+        .get("/api/admin/config")
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn config_reports_the_settings_in_use() {
+    let Some(server) = TestServer::start_with(&[
+        ("ADMIN_TOKEN", ADMIN_TOKEN),
+        ("SHORT_IDS", "true"),
+        ("CACHE_MAX_AGE_SECS", "60"),
+    ])
+    .await
+    else {
+        return;
+    };
+    let body = config(&server).await;
+    assert_eq!(body["short_ids"], true);
+    assert_eq!(body["cache_max_age"], 60);
+    assert_eq!(body["read_only"], false);
+    assert!(!body.to_string().contains(ADMIN_TOKEN));
+
+    let response = server
+        .client
+        .put(server.url("/api/admin/read-only"))
+        .bearer_auth(ADMIN_TOKEN)
+        .json(&json!({"read_only": true}))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(config(&server).await["read_only"], true);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{dimensions, error_code, png, uploaded_id, TestServer};
+
+#[tokio::test]
+async fn images_are_served_by_their_alias() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let data = png(40, 20);
+    let response = server.upload_with(data.clone(), "alias=product-123").await;
+    assert_eq!(response.status(), 200);
+    let id = uploaded_id(response).await;
+
+    let by_alias = server.get("/api/alias/product-123").send().await.unwrap();
+    assert_eq!(by_alias.status(), 200);
+    let by_id = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(by_alias.bytes().await.unwrap(), by_id.bytes().await.unwrap());
+
+    let response = server
+        .get("/api/alias/product-123.webp?width=20")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/webp");
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (20, 10));
+
+    let response = server.get("/api/alias/unknown").send().await.unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn taken_aliases_are_refused() {
+    let Some(mut server) = TestServer::start().await else {
+        return;
+    };
+    let first = server.upload_with(png(8, 8), "alias=taken").await;
+    assert_eq!(first.status(), 200);
+    let first = uploaded_id(first).await;
+
+    let response = server.upload_with(png(16, 16), "alias=taken").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(error_code(response).await, "alias_taken");
+    let response = server.get("/api/alias/taken").send().await.unwrap();
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (8, 8));
+
+    //Aliases are unique per tenant.
+    let tenant = std::mem::replace(&mut server.tenant, format!("{}-other", first.replace('-', "")));
+    let response = server.upload_with(png(16, 16), "alias=taken").await;
+    assert_eq!(response.status(), 200);
+    server.tenant = tenant;
+
+    //Deleting the image frees its alias.
+    let response = server.delete(&format!("/api/{first}")).send().await.unwrap();
+    assert!(response.status().is_success());
+    let response = server.upload_with(png(16, 16), "alias=taken").await;
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn malformed_aliases_are_refused() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    for alias in ["has%20space", "slash%2Fin", &"a".repeat(129)] {
+        let response = server.upload_with(png(8, 8), &format!("alias={alias}")).await;
+        assert_eq!(response.status(), 400, "{alias}");
+        assert_eq!(error_code(response).await, "invalid_alias", "{alias}");
+    }
+}
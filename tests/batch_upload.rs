@@ -0,0 +1,96 @@
+mod common;
+
+use common::{error_code, png, TestServer};
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+
+fn form(images: &[Vec<u8>]) -> Form {
+    images.iter().fold(Form::new(), |form, data| {
+        form.part("file", Part::bytes(data.clone()).file_name("upload"))
+    })
+}
+
+#[tokio::test]
+async fn batches_have_their_own_body_limit() {
+    let image = png(16, 16);
+    //The image fits MAX_IMAGE_SIZE, the multipart framing around it pushes the body over.
+    let max_image_size = (image.len() + 16).to_string();
+    let max_batch_size = (10 * image.len()).to_string();
+    let Some(server) = TestServer::start_with(&[
+        ("MAX_IMAGE_SIZE", max_image_size.as_str()),
+        ("MAX_BATCH_SIZE", max_batch_size.as_str()),
+    ])
+    .await
+    else {
+        return;
+    };
+
+    let response = server
+        .post("/api/upload?sync=true")
+        .multipart(form(std::slice::from_ref(&image)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    assert_eq!(error_code(response).await, "payload_too_large");
+
+    let response = server
+        .post("/api/upload/batch?sync=true")
+        .multipart(form(&[image.clone(), image]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["images"].as_array().unwrap().len(), 2);
+    for entry in body["images"].as_array().unwrap() {
+        let id = entry["id"].as_str().expect("batch image was refused");
+        let served = server.get(&format!("/api/{id}")).send().await.unwrap();
+        assert_eq!(served.status(), 200);
+    }
+}
+
+#[tokio::test]
+async fn batch_images_over_the_image_limit_fail_alone() {
+    let (small, large) = (png(8, 8), png(64, 64));
+    let max_image_size = (small.len() + 16).to_string();
+    let max_batch_size = (10 * large.len()).to_string();
+    let Some(server) = TestServer::start_with(&[
+        ("MAX_IMAGE_SIZE", max_image_size.as_str()),
+        ("MAX_BATCH_SIZE", max_batch_size.as_str()),
+    ])
+    .await
+    else {
+        return;
+    };
+
+    let response = server
+        .post("/api/upload/batch?sync=true")
+        .multipart(form(&[small, large]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["images"][0]["id"].is_string());
+    assert_eq!(body["images"][1]["error"]["code"], "payload_too_large");
+}
+
+#[tokio::test]
+async fn batches_over_their_limit_are_refused() {
+    let image = png(16, 16);
+    let max_batch_size = (2 * image.len()).to_string();
+    let Some(server) = TestServer::start_with(&[("MAX_BATCH_SIZE", max_batch_size.as_str())]).await
+    else {
+        return;
+    };
+
+    let response = server
+        .post("/api/upload/batch?sync=true")
+        .multipart(form(&[image.clone(), image.clone(), image]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    assert_eq!(error_code(response).await, "payload_too_large");
+}
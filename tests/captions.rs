@@ -0,0 +1,117 @@
+mod common;
+
+use common::{error_code, png, uploaded_id, TestServer};
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+
+const CAPTION: &str = "Café at 100% — \"sunset\"";
+
+fn form_with_caption(caption: &str) -> Form {
+    Form::new()
+        .part("file", Part::bytes(png(8, 8)).file_name("upload"))
+        .text("caption", caption.to_string())
+}
+
+//Undoes the percent-encoding of X-Image-Caption.
+fn decode_caption(header: &str) -> String {
+    let bytes = header.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            decoded.push(u8::from_str_radix(&header[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+async fn served_caption(server: &TestServer, id: &str) -> (Option<String>, Option<String>) {
+    let meta: Value = server
+        .get(&format!("/api/{id}/meta"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let header = response
+        .headers()
+        .get("X-Image-Caption")
+        .map(|value| decode_caption(value.to_str().unwrap()));
+    (meta["caption"].as_str().map(str::to_string), header)
+}
+
+#[tokio::test]
+async fn form_field_captions_round_trip() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .post("/api/upload?sync=true")
+        .multipart(form_with_caption(CAPTION))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let id = uploaded_id(response).await;
+
+    let (meta, header) = served_caption(&server, &id).await;
+    assert_eq!(meta.as_deref(), Some(CAPTION));
+    assert_eq!(header.as_deref(), Some(CAPTION));
+}
+
+#[tokio::test]
+async fn query_captions_round_trip() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .upload_with(png(8, 8), "caption=A%20red%20door")
+        .await;
+    assert_eq!(response.status(), 200);
+    let id = uploaded_id(response).await;
+
+    let (meta, header) = served_caption(&server, &id).await;
+    assert_eq!(meta.as_deref(), Some("A red door"));
+    assert_eq!(header.as_deref(), Some("A red door"));
+}
+
+#[tokio::test]
+async fn images_without_a_caption_have_no_header() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(8, 8)).await;
+
+    assert_eq!(served_caption(&server, &id).await, (None, None));
+}
+
+#[tokio::test]
+async fn invalid_captions_are_refused() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .post("/api/upload?sync=true&caption=query")
+        .multipart(form_with_caption("field"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(error_code(response).await, "conflicting_caption");
+
+    let response = server
+        .post("/api/upload?sync=true")
+        .multipart(form_with_caption(&"é".repeat(1001)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(error_code(response).await, "caption_too_long");
+}
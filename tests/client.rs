@@ -0,0 +1,102 @@
+#![cfg(feature = "client")]
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{dimensions, png, TestServer};
+use image_server::{
+    client::{ClientError, ImageClient},
+    ImageFormat, TranscodeTarget,
+};
+use uuid::Uuid;
+
+//Uploads through the client aren't synchronous, variants answer not_computed until they are stored.
+async fn get_when_computed(client: &ImageClient, id: Uuid, target: TranscodeTarget) -> Vec<u8> {
+    let started = Instant::now();
+    loop {
+        match client.get(id, target).await {
+            Ok(data) => return data,
+            Err(ClientError::Api { code, .. })
+                if code == "not_computed" && started.elapsed() < Duration::from_secs(30) =>
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(e) => panic!("get failed: {e}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn images_round_trip_through_the_client() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let client = ImageClient::new(format!("{}/", server.url)).with_tenant(&server.tenant);
+    let original = png(40, 20);
+    let id = client
+        .upload(original.clone(), ImageFormat::PNG, None)
+        .await
+        .unwrap();
+
+    let target = TranscodeTarget::builder()
+        .with_format(ImageFormat::WEBP)
+        .with_size(Some(20), None)
+        .build()
+        .unwrap();
+    let data = get_when_computed(&client, id, target).await;
+    assert_eq!(
+        image::guess_format(&data).unwrap(),
+        image::ImageFormat::WebP
+    );
+    assert_eq!(dimensions(&data), (20, 10));
+
+    assert_eq!(client.get_original(id).await.unwrap(), original);
+    assert!(client
+        .formats(id)
+        .await
+        .unwrap()
+        .contains(&ImageFormat::PNG));
+
+    client.delete(id).await.unwrap();
+    match client.get_original(id).await {
+        Err(ClientError::Api { status, code, .. }) => {
+            assert_eq!(status, 404);
+            assert_eq!(code, "not_found");
+        }
+        other => panic!("deleted image was served: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn api_errors_keep_their_code() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let client = ImageClient::new(&server.url).with_tenant(&server.tenant);
+    match client
+        .upload(b"not an image".to_vec(), ImageFormat::PNG, None)
+        .await
+    {
+        Err(ClientError::Api { status, code, .. }) => {
+            assert_eq!(status, 400);
+            assert!(!code.is_empty());
+        }
+        other => panic!("garbage was accepted: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn clients_only_see_their_tenant() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(8, 8)).await.parse().unwrap();
+    let own = ImageClient::new(&server.url).with_tenant(&server.tenant);
+    let other = ImageClient::new(&server.url).with_tenant(format!("{}-other", server.tenant));
+
+    assert!(own.get_original(id).await.is_ok());
+    assert!(matches!(
+        other.get_original(id).await,
+        Err(ClientError::Api { code, .. }) if code == "not_found"
+    ));
+}
@@ -0,0 +1,217 @@
+//Runs the server binary against the database in DATABASE_URL, every server gets its own port and image folder.
+//Tests are skipped when DATABASE_URL isn't set, each one uses a tenant of its own so they can share the database.
+#![allow(dead_code)]
+
+use std::{
+    io::Cursor,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant},
+};
+
+use image::{DynamicImage, ImageFormat, RgbImage};
+use reqwest::{multipart, Response};
+use serde_json::Value;
+use tempfile::TempDir;
+use uuid::Uuid;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+//The server refuses ports from 25565 on.
+const FIRST_PORT: u16 = 20000;
+const PORT_RANGE: u16 = 5000;
+
+static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+
+pub struct TestServer {
+    child: Child,
+    pub url: String,
+    pub client: reqwest::Client,
+    pub tenant: String,
+    pub images: TempDir,
+    log_path: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Option<TestServer> {
+        Self::start_with(&[]).await
+    }
+
+    //None without a database, the caller returns early then.
+    pub async fn start_with(env: &[(&str, &str)]) -> Option<TestServer> {
+        let Ok(database_url) = std::env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return None;
+        };
+        let images = TempDir::new().unwrap();
+        let log_path = images.path().join("server.log");
+        let port = free_port();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_image_server"));
+        command
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .env("DATABASE_URL", database_url)
+            .env("IMAGE_PATH", images.path().join("images"))
+            .env("BACKEND_PORT", port.to_string())
+            .env("NO_COLOR", "1")
+            .current_dir(images.path())
+            .stdout(Stdio::from(std::fs::File::create(&log_path).unwrap()))
+            .stderr(Stdio::null());
+        for (key, value) in env {
+            command.env(key, value);
+        }
+        let child = command.spawn().expect("could not start the server binary");
+        //Servers with TLS use the self-signed certificate in tests/fixtures, which isn't trusted.
+        let tls = env.iter().any(|(key, _)| *key == "TLS_CERT_PATH");
+        let server = TestServer {
+            child,
+            url: format!("{}://127.0.0.1:{port}", if tls { "https" } else { "http" }),
+            client: reqwest::Client::builder()
+                .danger_accept_invalid_certs(tls)
+                .build()
+                .unwrap(),
+            tenant: format!("test-{}", Uuid::new_v4().simple()),
+            images,
+            log_path,
+        };
+        server.wait_until_ready().await;
+        Some(server)
+    }
+
+    async fn wait_until_ready(&self) {
+        let started = Instant::now();
+        loop {
+            if self.client.get(self.url("/")).send().await.is_ok() {
+                return;
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                panic!("server did not start:\n{}", self.log());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.url)
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(&self.log_path).unwrap_or_default()
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    pub fn image_folder(&self) -> PathBuf {
+        self.images.path().join("images")
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(self.url(path))
+            .header("X-Tenant", &self.tenant)
+    }
+
+    pub fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .post(self.url(path))
+            .header("X-Tenant", &self.tenant)
+    }
+
+    pub fn delete(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .delete(self.url(path))
+            .header("X-Tenant", &self.tenant)
+    }
+
+    //Uploads with `sync=true` unless the query says otherwise, so the image can be served right away.
+    pub async fn upload_with(&self, data: Vec<u8>, query: &str) -> Response {
+        let part = multipart::Part::bytes(data).file_name("upload");
+        let form = multipart::Form::new().part("file", part);
+        let query = if query.contains("sync=") {
+            query.to_string()
+        } else {
+            format!("sync=true&{query}")
+        };
+        self.post(&format!("/api/upload?{query}"))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn upload(&self, data: Vec<u8>) -> String {
+        let response = self.upload_with(data, "").await;
+        assert_eq!(response.status(), 200, "upload failed");
+        uploaded_id(response).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub async fn uploaded_id(response: Response) -> String {
+    let body: Value = response.json().await.unwrap();
+    body["id"].as_str().expect("no id in the upload response").to_string()
+}
+
+pub async fn error_code(response: Response) -> String {
+    let body: Value = response.json().await.unwrap();
+    body["error"]["code"].as_str().unwrap_or_default().to_string()
+}
+
+//Ports are handed out in turn from a range offset by the process id, so parallel test binaries rarely collide.
+fn free_port() -> u16 {
+    let offset = (std::process::id() % PORT_RANGE as u32) as u16;
+    loop {
+        let next = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let port = FIRST_PORT + (offset + next) % PORT_RANGE;
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+//A gradient, so encoders and resizes have something to work with.
+pub fn test_image(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    }))
+}
+
+pub fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, format).unwrap();
+    data.into_inner()
+}
+
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    encode(&test_image(width, height), ImageFormat::Png)
+}
+
+pub fn dimensions(data: &[u8]) -> (u32, u32) {
+    let image = image::load_from_memory(data).unwrap();
+    (image.width(), image.height())
+}
+
+pub fn stored_files(folder: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return files;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(stored_files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::{png, TestServer};
+
+//Two instances sharing the database and image folder, with more concurrent requests than database connections.
+#[tokio::test]
+async fn missing_format_is_transcoded_once_across_instances() {
+    let Some(first) = TestServer::start().await else {
+        return;
+    };
+    let image_folder = first.image_folder();
+    let Some(mut second) =
+        TestServer::start_with(&[("IMAGE_PATH", image_folder.to_str().unwrap())]).await
+    else {
+        return;
+    };
+    second.tenant = first.tenant.clone();
+    let id = first.upload(png(400, 300)).await;
+
+    let path = format!("/api/{id}?format=webp");
+    let requests = (0..40).map(|index| {
+        let server = if index % 2 == 0 { &first } else { &second };
+        server.get(&path).send()
+    });
+    for response in futures::future::join_all(requests).await {
+        let response = response.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "image/webp");
+    }
+
+    let claimed = format!("Claimed {}/{id}.webp", first.tenant);
+    let transcodes = first.log().matches(&claimed).count() + second.log().matches(&claimed).count();
+    assert_eq!(transcodes, 1);
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::TestServer;
+use reqwest::header;
+
+#[tokio::test]
+async fn last_modified_answers_304() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(32, 32)).await;
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+
+    let response = server
+        .get(&format!("/api/{id}"))
+        .header(header::IF_MODIFIED_SINCE, last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+}
+
+#[tokio::test]
+async fn etag_answers_304() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(32, 32)).await;
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    let etag = response.headers()[header::ETAG].clone();
+
+    let response = server
+        .get(&format!("/api/{id}"))
+        .header(header::IF_NONE_MATCH, etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+}
+
+#[tokio::test]
+async fn invalid_query_is_refused_before_304() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(32, 32)).await;
+    let response = server
+        .get(&format!("/api/{id}?width=0"))
+        .header(header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn uncomputed_image_is_not_answered_304() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server.upload_with(common::png(3000, 3000), "sync=false").await;
+    let id = common::uploaded_id(response).await;
+    let response = server
+        .get(&format!("/api/{id}"))
+        .header(header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")
+        .send()
+        .await
+        .unwrap();
+    assert_ne!(response.status(), 304);
+}
+
+#[tokio::test]
+async fn public_responses_vary_on_the_tenant() {
+    let Some(server) = TestServer::start_with(&[
+        ("CACHE_MAX_AGE_SECS", "60"),
+        ("NEGOTIATE_FORMAT", "true"),
+    ])
+    .await
+    else {
+        return;
+    };
+    let id = server.upload(common::png(32, 32)).await;
+    let response = server
+        .get(&format!("/api/{id}?format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=60");
+    assert_eq!(response.headers()[header::VARY], "X-Tenant");
+
+    let response = server
+        .get(&format!("/api/{id}"))
+        .header(header::ACCEPT, "image/webp")
+        .send()
+        .await
+        .unwrap();
+    //Header names in Vary are case-insensitive.
+    let vary = response.headers()[header::VARY].to_str().unwrap();
+    assert!(vary.eq_ignore_ascii_case("Accept, X-Tenant"), "{vary}");
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{png, TestServer};
+
+//The alpha of the center pixel of every cell of a one row sheet.
+async fn cells(server: &TestServer, ids: &[&str]) -> Vec<u8> {
+    let response = server
+        .get(&format!("/api/contact-sheet?ids={}&cols={}&thumb=32", ids.join(","), ids.len()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let sheet = image::load_from_memory(&response.bytes().await.unwrap())
+        .unwrap()
+        .to_rgba8();
+    (0..ids.len() as u32)
+        .map(|cell| sheet.get_pixel(cell * 32 + 16, 16)[3])
+        .collect()
+}
+
+#[tokio::test]
+async fn cells_share_the_decode_cache() {
+    let Some(server) = TestServer::start_with(&[("MAX_DECODE_CACHE_BYTES", "10000000")]).await
+    else {
+        return;
+    };
+    let id = server.upload(png(64, 64)).await;
+    assert_eq!(cells(&server, &[&id]).await, [255]);
+    assert_eq!(cells(&server, &[&id]).await, [255]);
+    let decodes = server
+        .log()
+        .matches(&format!("Decoded source of image {id}"))
+        .count();
+    assert_eq!(decodes, 1);
+}
+
+#[tokio::test]
+async fn cells_count_against_the_transform_limit() {
+    let Some(server) = TestServer::start_with(&[("MAX_TRANSFORMS_PER_IMAGE", "1")]).await else {
+        return;
+    };
+    let (used, fresh) = (server.upload(png(64, 64)).await, server.upload(png(64, 64)).await);
+    let response = server
+        .get(&format!("/api/{used}?width=10"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    assert_eq!(cells(&server, &[&used, &fresh]).await, [0, 255]);
+    //The sheet used up the other image's only transform.
+    let response = server
+        .get(&format!("/api/{fresh}?width=10"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+}
+
+#[tokio::test]
+async fn sources_over_the_limits_leave_a_blank_cell() {
+    let Some(unlimited) = TestServer::start().await else {
+        return;
+    };
+    let (large, small) = (unlimited.upload(png(600, 100)).await, unlimited.upload(png(64, 64)).await);
+    let folder = unlimited.image_folder();
+    let Some(mut limited) = TestServer::start_with(&[
+        ("IMAGE_PATH", folder.to_str().unwrap()),
+        ("MAX_IMAGE_WIDTH", "500"),
+    ])
+    .await
+    else {
+        return;
+    };
+    limited.tenant = unlimited.tenant.clone();
+
+    assert_eq!(cells(&limited, &[&large, &small]).await, [0, 255]);
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::{png, TestServer};
+
+async fn decodes_for_two_transforms(server: &TestServer) -> usize {
+    let id = server.upload(png(200, 100)).await;
+    for width in [50, 60] {
+        let response = server
+            .get(&format!("/api/{id}?width={width}&format=png"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    server
+        .log()
+        .matches(&format!("Decoded source of image {id}"))
+        .count()
+}
+
+#[tokio::test]
+async fn transforms_share_a_cached_decode() {
+    let Some(server) = TestServer::start_with(&[("MAX_DECODE_CACHE_BYTES", "10000000")]).await
+    else {
+        return;
+    };
+    assert_eq!(decodes_for_two_transforms(&server).await, 1);
+}
+
+#[tokio::test]
+async fn transforms_decode_again_without_a_cache() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    assert_eq!(decodes_for_two_transforms(&server).await, 2);
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::{error_code, TestServer};
+use serde_json::Value;
+
+#[tokio::test]
+async fn unknown_image_is_a_json_404() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .get(&format!("/api/{}", uuid::Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(error_code(response).await, "not_found");
+}
+
+#[tokio::test]
+async fn invalid_query_is_a_json_400() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .get(&format!("/api/{}?width=abc", uuid::Uuid::new_v4()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]["code"].is_string());
+    assert!(body["error"]["message"].is_string());
+}
+
+#[tokio::test]
+async fn upload_answers_with_the_id() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(32, 32)).await;
+    assert!(uuid::Uuid::parse_str(&id).is_ok());
+}
+
+async fn invalid_id_value(server: &TestServer, path: &str) -> String {
+    let response = server.get(&format!("/api/{path}")).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_id");
+    assert!(body["error"]["message"].is_string());
+    body["error"]["value"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn malformed_ids_are_echoed_truncated() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    assert_eq!(invalid_id_value(&server, "not-an-id").await, "not-an-id");
+
+    let long = "x".repeat(200);
+    assert_eq!(
+        invalid_id_value(&server, &long).await,
+        format!("{}...", "x".repeat(64))
+    );
+
+    //Cut by characters, never inside one.
+    let accented = "%C3%A9".repeat(100);
+    assert_eq!(
+        invalid_id_value(&server, &accented).await,
+        format!("{}...", "é".repeat(64))
+    );
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::{png, TestServer};
+use serde_json::Value;
+
+async fn estimate(server: &TestServer, id: &str) -> Value {
+    let response = server
+        .get(&format!("/api/{id}/estimate?width=50&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn dimensions_come_from_the_header() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(100, 80)).await;
+    let body = estimate(&server, &id).await;
+    assert_eq!((&body["width"], &body["height"]), (&Value::from(50), &Value::from(40)));
+}
+
+#[tokio::test]
+async fn avif_originals_have_no_dimensions() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server.upload_with(png(100, 80), "store_as=avif").await;
+    assert_eq!(response.status(), 200);
+    let id = common::uploaded_id(response).await;
+    let body = estimate(&server, &id).await;
+    assert!(body["width"].is_null() && body["height"].is_null(), "{body}");
+    assert!(body["bytes"].is_null() && body["millis"].is_null(), "{body}");
+}
@@ -0,0 +1,60 @@
+#![cfg(feature = "jpeg-scaling")]
+mod common;
+
+use common::{dimensions, encode, error_code, test_image, TestServer};
+use image::ImageFormat;
+
+fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    encode(&test_image(width, height), ImageFormat::Jpeg)
+}
+
+#[tokio::test]
+async fn scale_is_decoded_once_at_a_reduced_size() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(jpeg(1600, 1200)).await;
+
+    let response = server
+        .get(&format!("/api/{id}?scale=0.125&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (200, 150));
+
+    let log = server.log();
+    let decodes: Vec<&str> = log
+        .lines()
+        .filter(|line| line.contains(&format!("Decoded source of image {id}")))
+        .collect();
+    assert_eq!(decodes.len(), 1, "{decodes:?}");
+    assert!(decodes[0].ends_with(" at 400x300"), "{decodes:?}");
+}
+
+#[tokio::test]
+async fn scaled_decodes_are_held_to_the_limits() {
+    let Some(unlimited) = TestServer::start().await else {
+        return;
+    };
+    let id = unlimited.upload(jpeg(1600, 1200)).await;
+
+    let folder = unlimited.image_folder();
+    let Some(mut limited) = TestServer::start_with(&[
+        ("IMAGE_PATH", folder.to_str().unwrap()),
+        ("MAX_IMAGE_WIDTH", "1000"),
+    ])
+    .await
+    else {
+        return;
+    };
+    limited.tenant = unlimited.tenant.clone();
+
+    let response = limited
+        .get(&format!("/api/{id}?width=200&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    assert_eq!(error_code(response).await, "limits_exceeded");
+}
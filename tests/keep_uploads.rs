@@ -0,0 +1,94 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{encode, png, stored_files, test_image, TestServer};
+use image::ImageFormat;
+
+const KEEP: [(&str, &str); 1] = [("KEEP_UPLOADS", "true")];
+
+fn kept_uploads(server: &TestServer) -> usize {
+    stored_files(&server.image_folder())
+        .iter()
+        .filter(|path| path.extension().is_some_and(|extension| extension == "upload"))
+        .count()
+}
+
+#[tokio::test]
+async fn kept_uploads_are_served_as_attachments() {
+    let Some(server) = TestServer::start_with(&KEEP).await else {
+        return;
+    };
+    let jpeg = encode(&test_image(40, 30), ImageFormat::Jpeg);
+    let response = server.upload_with(jpeg.clone(), "store_as=png").await;
+    assert_eq!(response.status(), 200);
+    let id = common::uploaded_id(response).await;
+
+    let response = server
+        .get(&format!("/api/{id}?original=true"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+    assert_eq!(headers["content-type"], "image/jpeg");
+    assert_eq!(headers["content-disposition"], "attachment");
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["content-security-policy"], "default-src 'none'; sandbox");
+    assert_eq!(response.bytes().await.unwrap(), jpeg);
+}
+
+#[tokio::test]
+async fn archives_name_kept_jpegs_jpg() {
+    let Some(server) = TestServer::start_with(&KEEP).await else {
+        return;
+    };
+    let id = server
+        .upload(encode(&test_image(40, 30), ImageFormat::Jpeg))
+        .await;
+
+    let response = server
+        .get(&format!("/api/archive?ids={id}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let archive = response.bytes().await.unwrap();
+    let name = format!("{id}.jpg");
+    assert!(archive
+        .windows(name.len())
+        .any(|window| window == name.as_bytes()));
+}
+
+#[tokio::test]
+async fn failed_uploads_leave_no_kept_upload() {
+    let Some(server) = TestServer::start_with(&KEEP).await else {
+        return;
+    };
+    //The header is intact, the pixel data isn't.
+    let mut truncated = png(200, 200);
+    truncated.truncate(truncated.len() / 2);
+    let response = server.upload_with(truncated, "").await;
+    assert!(!response.status().is_success());
+    //The failed original is discarded in the background.
+    for _ in 0..50 {
+        if kept_uploads(&server) == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("the kept upload of a failed upload was left behind");
+}
+
+#[tokio::test]
+async fn deleting_removes_the_kept_upload() {
+    let Some(server) = TestServer::start_with(&KEEP).await else {
+        return;
+    };
+    let id = server.upload(png(20, 20)).await;
+    assert_eq!(kept_uploads(&server), 1);
+
+    let response = server.delete(&format!("/api/{id}")).send().await.unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(kept_uploads(&server), 0);
+}
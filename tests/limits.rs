@@ -0,0 +1,71 @@
+mod common;
+
+use common::{error_code, png, stored_files, TestServer};
+use image::{codecs::gif::GifEncoder, Delay, Frame, Rgba, RgbaImage};
+
+#[tokio::test]
+async fn stored_sources_are_decoded_under_the_limits() {
+    let Some(unlimited) = TestServer::start().await else {
+        return;
+    };
+    let id = unlimited.upload(png(1000, 800)).await;
+
+    //A second server on the same folder with a limit lowered after the upload.
+    let folder = unlimited.image_folder();
+    let Some(mut limited) = TestServer::start_with(&[
+        ("IMAGE_PATH", folder.to_str().unwrap()),
+        ("MAX_IMAGE_WIDTH", "500"),
+    ])
+    .await
+    else {
+        return;
+    };
+    limited.tenant = unlimited.tenant.clone();
+
+    let response = limited
+        .get(&format!("/api/{id}?width=100&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    assert_eq!(error_code(response).await, "limits_exceeded");
+}
+
+//Two frames on a 400x400 canvas, 640000 bytes of RGBA each.
+fn animated_gif() -> Vec<u8> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut data);
+        for shade in [0, 255] {
+            let frame = RgbaImage::from_pixel(400, 400, Rgba([shade, 0, 0, 255]));
+            encoder
+                .encode_frame(Frame::from_parts(frame, 0, 0, Delay::from_numer_denom_ms(100, 1)))
+                .unwrap();
+        }
+    }
+    data
+}
+
+//The limit fits the canvas, which the upload checks up front, but not the frames decoded into it.
+#[tokio::test]
+async fn animation_frames_are_decoded_under_the_limits() {
+    for (policy, accepted) in [("first_frame", true), ("last_frame", false)] {
+        let Some(server) = TestServer::start_with(&[
+            ("MAX_MEMORY_USAGE", "960000"),
+            ("ANIMATION_POLICY", policy),
+        ])
+        .await
+        else {
+            return;
+        };
+        let response = server.upload_with(animated_gif(), "").await;
+        if accepted {
+            assert_eq!(response.status(), 200, "{policy}");
+        } else {
+            //The frames are decoded after the row is created, so this is the answer of a failed decode.
+            assert_eq!(response.status(), 422, "{policy}");
+            assert_eq!(error_code(response).await, "not_stored");
+            assert!(stored_files(&server.image_folder()).is_empty());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use common::{png, TestServer};
+use serde_json::Value;
+
+async fn page(server: &TestServer, query: &str) -> (Vec<String>, Option<String>) {
+    let response = server
+        .get(&format!("/api/images?{query}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    let ids = body["images"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|image| image["id"].as_str().unwrap().to_string())
+        .collect();
+    (ids, body["next_cursor"].as_str().map(str::to_string))
+}
+
+#[tokio::test]
+async fn paging_is_stable_when_images_are_added_between_pages() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let mut uploaded = Vec::new();
+    for _ in 0..4 {
+        uploaded.push(server.upload(png(8, 8)).await);
+    }
+
+    let (first, cursor) = page(&server, "limit=2").await;
+    assert_eq!(first, uploaded[..2]);
+    let cursor = cursor.expect("a second page follows");
+
+    //Images added in between sort after the cursor, the next page neither repeats nor skips any.
+    let mut added = Vec::new();
+    for _ in 0..2 {
+        added.push(server.upload(png(8, 8)).await);
+    }
+    let (second, cursor) = page(&server, &format!("limit=2&after={cursor}")).await;
+    assert_eq!(second, uploaded[2..]);
+    let cursor = cursor.expect("the added images follow");
+
+    let (third, cursor) = page(&server, &format!("limit=2&after={cursor}")).await;
+    assert_eq!(third, added);
+    assert_eq!(cursor, None);
+}
+
+#[tokio::test]
+async fn malformed_cursors_are_refused() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server
+        .get("/api/images?after=not-a-cursor")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(common::error_code(response).await, "invalid_cursor");
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use common::{error_code, png, uploaded_id, TestServer};
+use reqwest::{header, Response};
+
+//Large enough that the async upload is still being stored when it is requested.
+async fn pending_id(server: &TestServer) -> String {
+    uploaded_id(server.upload_with(png(3000, 3000), "sync=false").await).await
+}
+
+async fn pending_image(server: &TestServer) -> Response {
+    let id = pending_id(server).await;
+    server.get(&format!("/api/{id}")).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn accepted_status_carries_polling_hints() {
+    let Some(server) = TestServer::start_with(&[("NOT_COMPUTED_STATUS", "202")]).await else {
+        return;
+    };
+    let response = pending_image(&server).await;
+    assert_eq!(response.status(), 202);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    assert!(response.headers()[header::LOCATION]
+        .to_str()
+        .unwrap()
+        .starts_with("/api/"));
+    assert_eq!(error_code(response).await, "not_computed");
+}
+
+#[tokio::test]
+async fn default_not_found_has_no_polling_hints() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = pending_image(&server).await;
+    assert_eq!(response.status(), 404);
+    assert!(!response.headers().contains_key(header::RETRY_AFTER));
+    assert!(!response.headers().contains_key(header::LOCATION));
+    assert_eq!(error_code(response).await, "not_computed");
+}
+
+#[tokio::test]
+async fn estimates_use_the_configured_status() {
+    let Some(server) = TestServer::start_with(&[("NOT_COMPUTED_STATUS", "202")]).await else {
+        return;
+    };
+    let id = pending_id(&server).await;
+    let response = server
+        .get(&format!("/api/{id}/estimate?width=50"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(error_code(response).await, "not_computed");
+}
+
+#[tokio::test]
+async fn histograms_use_the_configured_status() {
+    let Some(server) = TestServer::start_with(&[("NOT_COMPUTED_STATUS", "202")]).await else {
+        return;
+    };
+    let id = pending_id(&server).await;
+    let response = server
+        .get(&format!("/api/{id}/histogram"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(error_code(response).await, "not_computed");
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{png, TestServer};
+use reqwest::Version;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+async fn served_version(server: &TestServer, client: &reqwest::Client) -> Version {
+    let id = server.upload(png(8, 8)).await;
+    let response = client
+        .get(server.url(&format!("/api/{id}")))
+        .header("X-Tenant", &server.tenant)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let version = response.version();
+    image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+    version
+}
+
+#[tokio::test]
+async fn http2_prior_knowledge_is_served_when_enabled() {
+    let Some(server) = TestServer::start_with(&[("ENABLE_HTTP2", "true")]).await else {
+        return;
+    };
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    assert_eq!(served_version(&server, &client).await, Version::HTTP_2);
+    //HTTP/1.1 keeps working next to it.
+    assert_eq!(
+        served_version(&server, &reqwest::Client::new()).await,
+        Version::HTTP_11
+    );
+}
+
+#[tokio::test]
+async fn http2_prior_knowledge_is_refused_by_default() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    assert!(client.get(server.url("/")).send().await.is_err());
+}
+
+#[tokio::test]
+async fn https_is_served_with_a_certificate() {
+    let (cert, key) = (fixture("cert.pem"), fixture("key.pem"));
+    let Some(server) = TestServer::start_with(&[
+        ("TLS_CERT_PATH", cert.as_str()),
+        ("TLS_KEY_PATH", key.as_str()),
+    ])
+    .await
+    else {
+        return;
+    };
+    assert!(server.url.starts_with("https://"));
+    assert_eq!(
+        served_version(&server, &server.client).await,
+        Version::HTTP_11
+    );
+
+    //Plain HTTP isn't answered on a TLS port.
+    let plain = server.url.replacen("https://", "http://", 1);
+    assert!(reqwest::get(format!("{plain}/")).await.is_err());
+}
+
+#[tokio::test]
+async fn https_negotiates_http2_when_enabled() {
+    let (cert, key) = (fixture("cert.pem"), fixture("key.pem"));
+    let Some(server) = TestServer::start_with(&[
+        ("TLS_CERT_PATH", cert.as_str()),
+        ("TLS_KEY_PATH", key.as_str()),
+        ("ENABLE_HTTP2", "true"),
+    ])
+    .await
+    else {
+        return;
+    };
+    assert_eq!(
+        served_version(&server, &server.client).await,
+        Version::HTTP_2
+    );
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use common::{error_code, TestServer};
+use reqwest::StatusCode;
+
+const FORWARDED_FOR: &str = "X-Forwarded-For";
+
+async fn status_from(server: &TestServer, client: &str) -> StatusCode {
+    server
+        .get("/api/images")
+        .header(FORWARDED_FOR, client)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn limit_is_per_forwarded_client_behind_a_trusted_proxy() {
+    let Some(server) =
+        TestServer::start_with(&[("RATE_LIMIT", "2"), ("TRUST_PROXY", "true")]).await
+    else {
+        return;
+    };
+    for _ in 0..2 {
+        assert_eq!(status_from(&server, "203.0.113.1").await, 200);
+    }
+    assert_eq!(status_from(&server, "203.0.113.1").await, 429);
+    assert_eq!(status_from(&server, "203.0.113.2").await, 200);
+}
+
+#[tokio::test]
+async fn forwarded_clients_share_the_peer_limit_without_trust() {
+    let Some(server) = TestServer::start_with(&[("RATE_LIMIT", "2")]).await else {
+        return;
+    };
+    assert_eq!(status_from(&server, "203.0.113.1").await, 200);
+    assert_eq!(status_from(&server, "203.0.113.2").await, 200);
+    let response = server
+        .get("/api/images")
+        .header(FORWARDED_FOR, "203.0.113.3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key("retry-after"));
+    assert_eq!(error_code(response).await, "rate_limited");
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{png, test_image, TestServer};
+use reqwest::Response;
+
+fn header(response: &Response, name: &str) -> u32 {
+    response.headers()[name].to_str().unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn raw_pixels_match_the_decoded_image() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(40, 20)).await;
+
+    let response = server.get(&format!("/api/{id}/raw")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/octet-stream"
+    );
+    let (width, height) = (
+        header(&response, "X-Image-Width"),
+        header(&response, "X-Image-Height"),
+    );
+    assert_eq!((width, height), (40, 20));
+    assert_eq!(header(&response, "X-Image-Channels"), 4);
+
+    let data = response.bytes().await.unwrap();
+    assert_eq!(data.len(), (width * height * 4) as usize);
+    assert_eq!(data.to_vec(), test_image(40, 20).to_rgba8().into_raw());
+}
+
+#[tokio::test]
+async fn raw_pixels_are_resized() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(40, 20)).await;
+
+    let response = server
+        .get(&format!("/api/{id}/raw?width=10&format=jpeg"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let (width, height) = (
+        header(&response, "X-Image-Width"),
+        header(&response, "X-Image-Height"),
+    );
+    assert_eq!((width, height), (10, 5));
+    assert_eq!(response.bytes().await.unwrap().len(), 10 * 5 * 4);
+}
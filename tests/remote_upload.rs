@@ -0,0 +1,196 @@
+#![cfg(feature = "remote-upload")]
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{dimensions, error_code, png, TestServer};
+use reqwest::Response;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+//Answers every request with `body` as a PNG after `delay`, counting the connections it got.
+struct ImageHost {
+    port: u16,
+    connections: Arc<AtomicUsize>,
+}
+
+impl ImageHost {
+    async fn start(body: Vec<u8>, delay: Duration) -> ImageHost {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                counted.fetch_add(1, Ordering::SeqCst);
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    let _ = stream.read(&mut request).await;
+                    tokio::time::sleep(delay).await;
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        ImageHost { port, connections }
+    }
+}
+
+async fn upload_url(server: &TestServer, url: &str, query: &str) -> Response {
+    server
+        .post(&format!("/api/upload/url?{query}"))
+        .json(&json!({ "url": url }))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn images_are_fetched_from_a_local_server() {
+    let Some(server) = TestServer::start_with(&[
+        ("REMOTE_UPLOADS", "true"),
+        ("REMOTE_UPLOAD_ALLOW_PRIVATE", "true"),
+    ])
+    .await
+    else {
+        return;
+    };
+    let host = ImageHost::start(png(30, 20), Duration::ZERO).await;
+
+    let url = format!("http://127.0.0.1:{}/image.png", host.port);
+    let response = upload_url(&server, &url, "sync=true").await;
+    assert_eq!(response.status(), 200);
+    let id = common::uploaded_id(response).await;
+
+    let response = server
+        .get(&format!("/api/{id}?format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (30, 20));
+}
+
+#[tokio::test]
+async fn private_addresses_are_refused_before_connecting() {
+    let Some(server) = TestServer::start_with(&[("REMOTE_UPLOADS", "true")]).await else {
+        return;
+    };
+    let host = ImageHost::start(png(30, 20), Duration::ZERO).await;
+
+    let port = host.port;
+    for url in [
+        format!("http://127.0.0.1:{port}/"),
+        format!("http://localhost:{port}/"),
+        format!("http://[::ffff:127.0.0.1]:{port}/"),
+        format!("http://[64:ff9b::7f00:1]:{port}/"),
+        format!("http://[2002:7f00:1::]:{port}/"),
+        "http://169.254.169.254/latest/meta-data/".to_string(),
+    ] {
+        let response = upload_url(&server, &url, "sync=true").await;
+        assert_eq!(response.status(), 403, "{url}");
+        assert_eq!(error_code(response).await, "url_not_allowed", "{url}");
+    }
+    assert_eq!(host.connections.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn async_uploads_answer_202_and_are_served_once_fetched() {
+    let Some(server) = TestServer::start_with(&[
+        ("REMOTE_UPLOADS", "true"),
+        ("REMOTE_UPLOAD_ALLOW_PRIVATE", "true"),
+        ("NOT_COMPUTED_STATUS", "202"),
+    ])
+    .await
+    else {
+        return;
+    };
+    let host = ImageHost::start(png(30, 20), Duration::from_secs(1)).await;
+
+    let url = format!("http://127.0.0.1:{}/image.png", host.port);
+    let response = upload_url(&server, &url, "async=true").await;
+    assert_eq!(response.status(), 202);
+    let id = common::uploaded_id(response).await;
+
+    let response = server
+        .get(&format!("/api/{id}?format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    assert_eq!(error_code(response).await, "not_computed");
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        let response = server
+            .get(&format!("/api/{id}?format=png"))
+            .send()
+            .await
+            .unwrap();
+        if response.status() == 200 {
+            assert_eq!(dimensions(&response.bytes().await.unwrap()), (30, 20));
+            break;
+        }
+        assert_eq!(response.status(), 202);
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the image was never stored"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn async_uploads_check_the_url_before_answering() {
+    let Some(server) = TestServer::start_with(&[
+        ("REMOTE_UPLOADS", "true"),
+        ("REMOTE_UPLOAD_HOSTS", "images.example.com,localhost"),
+    ])
+    .await
+    else {
+        return;
+    };
+    let host = ImageHost::start(png(30, 20), Duration::ZERO).await;
+
+    let response = upload_url(&server, "not a url", "async=true").await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(error_code(response).await, "invalid_url");
+
+    let response = upload_url(&server, "http://elsewhere.example.com/", "async=true").await;
+    assert_eq!(response.status(), 403);
+    assert_eq!(error_code(response).await, "url_not_allowed");
+
+    let url = format!("http://localhost:{}/", host.port);
+    let response = upload_url(&server, &url, "async=true").await;
+    assert_eq!(response.status(), 403);
+    assert_eq!(error_code(response).await, "url_not_allowed");
+    assert_eq!(host.connections.load(Ordering::SeqCst), 0);
+
+    //Nothing was reserved for the refused urls.
+    let body: serde_json::Value = server
+        .get("/api/images")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["images"], json!([]));
+}
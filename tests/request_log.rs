@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{png, TestServer};
+
+//The debug line describing how the request for `id` was served, the last one when there are several.
+fn served_line(server: &TestServer, id: &str) -> String {
+    server
+        .log()
+        .lines()
+        .rev()
+        .find(|line| line.contains(&format!("Serving image {id}")))
+        .expect("no serve line was logged")
+        .to_string()
+}
+
+#[tokio::test]
+async fn served_transforms_are_logged() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(64, 32)).await;
+
+    let response = server
+        .get(&format!("/api/{id}?format=webp&width=32&aspect=1:1&quality=80&sharpen=1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let size = response.bytes().await.unwrap().len();
+
+    let line = served_line(&server, &id);
+    for field in [
+        "image_format: Some(ImageFormat(WebP))",
+        "image_width: Some(32)",
+        "aspect: Some(1.0)",
+        "quality: Some(80)",
+        "sharpen: Some(Sharpen { amount: 1.0, threshold: 0 })",
+        "cache miss",
+    ] {
+        assert!(line.contains(field), "{field} is missing from: {line}");
+    }
+    assert!(line.ends_with(&format!(", {size} bytes")), "{line}");
+}
+
+#[tokio::test]
+async fn stored_formats_are_logged_as_cache_hits() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(16, 16)).await;
+    let path = format!("/api/{id}?format=jpg");
+
+    let response = server.get(&path).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(served_line(&server, &id).contains("cache miss"));
+
+    //The variant is stored in the background, it is read back once it is in place.
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = server.get(&path).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        if served_line(&server, &id).contains("cache hit") {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline, "the variant was never served from disk");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{dimensions, error_code, png, TestServer};
+
+#[tokio::test]
+async fn zero_sizes_are_refused() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(64, 32)).await;
+
+    for query in ["width=0", "height=0", "width=0&height=0", "pipeline=resize(0,10)"] {
+        let response = server
+            .get(&format!("/api/{id}?{query}&format=png"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{query}");
+        assert_eq!(error_code(response).await, "invalid_transform", "{query}");
+    }
+}
+
+#[tokio::test]
+async fn small_sizes_are_served() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(png(64, 32)).await;
+
+    let response = server
+        .get(&format!("/api/{id}?width=16&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (16, 8));
+}
+
+#[tokio::test]
+async fn sizes_below_the_minimum_edge_are_refused() {
+    let Some(server) = TestServer::start_with(&[("MIN_RESIZE_EDGE", "32")]).await else {
+        return;
+    };
+    let id = server.upload(png(64, 64)).await;
+
+    let response = server
+        .get(&format!("/api/{id}?width=16&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(error_code(response).await, "invalid_transform");
+
+    let response = server
+        .get(&format!("/api/{id}?width=32&format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (32, 32));
+}
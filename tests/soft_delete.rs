@@ -0,0 +1,79 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{dimensions, error_code, png, uploaded_id, TestServer};
+
+const ADMIN_TOKEN: &str = "soft-delete-test-token";
+
+async fn restore(server: &TestServer, id: &str) -> reqwest::Response {
+    server
+        .post(&format!("/api/admin/restore/{id}"))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn deleted_images_can_be_restored_within_the_window() {
+    let Some(server) =
+        TestServer::start_with(&[("SOFT_DELETE_SECS", "3600"), ("ADMIN_TOKEN", ADMIN_TOKEN)]).await
+    else {
+        return;
+    };
+    let response = server.upload_with(png(24, 12), "alias=kept").await;
+    let id = uploaded_id(response).await;
+
+    let response = server.delete(&format!("/api/{id}")).send().await.unwrap();
+    assert!(response.status().is_success());
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+    let response = server.get("/api/alias/kept").send().await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    //The alias stays with the deleted image while it can be restored.
+    let response = server.upload_with(png(8, 8), "alias=kept").await;
+    assert_eq!(response.status(), 409);
+    assert_eq!(error_code(response).await, "alias_taken");
+
+    let response = restore(&server, &id).await;
+    assert_eq!(response.status(), 204);
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (24, 12));
+    let response = server.get("/api/alias/kept").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    //Only deleted images are restored.
+    let response = restore(&server, &id).await;
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn deleted_images_are_gone_after_the_window() {
+    let Some(server) =
+        TestServer::start_with(&[("SOFT_DELETE_SECS", "1"), ("ADMIN_TOKEN", ADMIN_TOKEN)]).await
+    else {
+        return;
+    };
+    let response = server.upload_with(png(8, 8), "alias=purged").await;
+    let id = uploaded_id(response).await;
+    assert_eq!(common::stored_files(&server.image_folder()).len(), 1);
+
+    let response = server.delete(&format!("/api/{id}")).send().await.unwrap();
+    assert!(response.status().is_success());
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let response = restore(&server, &id).await;
+    assert_eq!(response.status(), 404);
+    let response = server.upload_with(png(8, 8), "alias=purged").await;
+    assert_eq!(response.status(), 200);
+
+    //The purge runs on a timer, the deleted image's file goes with it.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while common::stored_files(&server.image_folder()).len() > 1 {
+        assert!(Instant::now() < deadline, "the deleted image was never purged");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use common::TestServer;
+use image::ImageFormat;
+
+#[tokio::test]
+async fn store_as_writes_the_requested_format() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let jpeg = common::encode(&common::test_image(64, 64), ImageFormat::Jpeg);
+    let response = server.upload_with(jpeg, "store_as=webp").await;
+    assert_eq!(response.status(), 200);
+
+    let files = common::stored_files(&server.image_folder());
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].extension().unwrap(), "webp");
+    let data = std::fs::read(&files[0]).unwrap();
+    assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::WebP);
+}
+
+#[tokio::test]
+async fn original_is_the_uploaded_bytes() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let jpeg = common::encode(&common::test_image(64, 64), ImageFormat::Jpeg);
+    let id = server.upload(jpeg.clone()).await;
+
+    let response = server
+        .get(&format!("/api/{id}?original=true"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap(), jpeg);
+}
+
+#[tokio::test]
+async fn bare_get_serves_the_stored_format_without_storing_a_variant() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let jpeg = common::encode(&common::test_image(64, 64), ImageFormat::Jpeg);
+    let id = server.upload(jpeg.clone()).await;
+
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/jpeg");
+    assert_eq!(response.bytes().await.unwrap(), jpeg);
+
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(common::stored_files(&server.image_folder()).len(), 1);
+}
+
+#[tokio::test]
+async fn format_matching_storage_is_served_without_decoding() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let png = common::png(64, 64);
+    let id = server.upload(png.clone()).await;
+
+    let response = server
+        .get(&format!("/api/{id}?format=png"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap(), png);
+
+    //Density is written into the stored bytes, the pixels are neither decoded nor re-encoded.
+    let response = server
+        .get(&format!("/api/{id}?format=png&dpi=300"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let data = response.bytes().await.unwrap();
+    let offset = data.windows(4).position(|window| window == b"pHYs").unwrap() + 4;
+    assert_eq!(data[offset..offset + 9], [0, 0, 0x2E, 0x23, 0, 0, 0x2E, 0x23, 1]);
+    assert_eq!(
+        image::load_from_memory(&data).unwrap().to_rgb8(),
+        image::load_from_memory(&png).unwrap().to_rgb8()
+    );
+
+    let decodes = server.log().matches(&format!("Decoded source of image {id}")).count();
+    assert_eq!(decodes, 0);
+}
+
+#[tokio::test]
+async fn zero_dpi_is_refused() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(8, 8)).await;
+
+    let response = server.get(&format!("/api/{id}?dpi=0")).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(common::error_code(response).await, "invalid_transform");
+}
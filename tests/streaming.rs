@@ -0,0 +1,57 @@
+mod common;
+
+use common::TestServer;
+use futures::future::join_all;
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+const CONCURRENT_REQUESTS: usize = 16;
+
+//Noise doesn't compress, so the PNG is about as large as its pixels.
+fn noise(width: u32, height: u32) -> Vec<u8> {
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let image = RgbImage::from_fn(width, height, |_, _| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        image::Rgb([state as u8, (state >> 8) as u8, (state >> 16) as u8])
+    });
+    common::encode(&DynamicImage::ImageRgb8(image), ImageFormat::Png)
+}
+
+//The peak resident memory of the server process in bytes, None where /proc isn't available.
+fn peak_memory(server: &TestServer) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", server.pid())).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[tokio::test]
+async fn large_files_are_streamed_without_buffering_each_request() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let large = noise(2000, 2000);
+    let id = server.upload(large.clone()).await;
+    let Some(before) = peak_memory(&server) else {
+        return;
+    };
+
+    let responses = join_all((0..CONCURRENT_REQUESTS).map(|_| async {
+        let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        response.bytes().await.unwrap()
+    }))
+    .await;
+    for body in responses {
+        assert!(body == large, "a streamed response differs from the upload");
+    }
+
+    //Buffered, every request would hold a copy of the file at once.
+    let growth = peak_memory(&server).unwrap().saturating_sub(before);
+    assert!(
+        growth < 4 * large.len() as u64,
+        "serving {CONCURRENT_REQUESTS} requests for a {} byte file grew peak memory by {growth} bytes",
+        large.len()
+    );
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use common::{dimensions, png, TestServer};
+use uuid::Uuid;
+
+fn stored_path(server: &TestServer, tenant: &str, id: &str) -> std::path::PathBuf {
+    let id = Uuid::parse_str(id).unwrap();
+    server
+        .image_folder()
+        .join(tenant)
+        .join(format!("{}.png", id.simple()))
+}
+
+#[tokio::test]
+async fn images_are_invisible_to_other_tenants() {
+    let Some(mut server) = TestServer::start().await else {
+        return;
+    };
+    let owner = server.tenant.clone();
+    let id = server.upload(png(16, 16)).await;
+    assert!(stored_path(&server, &owner, &id).exists());
+
+    server.tenant = format!("{owner}-other");
+    for path in [format!("/api/{id}"), format!("/api/{id}/meta"), format!("/api/{id}?original=true")] {
+        let response = server.get(&path).send().await.unwrap();
+        assert_eq!(response.status(), 404, "{path}");
+    }
+    let response = server.delete(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    //The tenant can also be picked by path prefix instead of the header.
+    let response = server
+        .client
+        .get(server.url(&format!("/api/tenants/{owner}/{id}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dimensions(&response.bytes().await.unwrap()), (16, 16));
+}
+
+#[tokio::test]
+async fn the_same_id_in_two_tenants_resolves_to_different_files() {
+    let Some(mut server) = TestServer::start().await else {
+        return;
+    };
+    let first_tenant = server.tenant.clone();
+    let id = server.upload(png(16, 16)).await;
+    let second_tenant = format!("{first_tenant}-second");
+    server.tenant = second_tenant.clone();
+    let other_id = server.upload(png(32, 32)).await;
+
+    //Uploads always get a fresh id, the second tenant's image is moved under the first one's id.
+    let database_url = std::env::var("DATABASE_URL").unwrap();
+    let pool = sqlx::PgPool::connect(&database_url).await.unwrap();
+    sqlx::query("UPDATE images SET image_identifier=$1 WHERE tenant=$2 AND image_identifier=$3")
+        .bind(Uuid::parse_str(&id).unwrap())
+        .bind(&second_tenant)
+        .bind(Uuid::parse_str(&other_id).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+    std::fs::rename(
+        stored_path(&server, &second_tenant, &other_id),
+        stored_path(&server, &second_tenant, &id),
+    )
+    .unwrap();
+
+    for (tenant, size) in [(&first_tenant, 16), (&second_tenant, 32)] {
+        server.tenant = tenant.clone();
+        let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+        assert_eq!(response.status(), 200, "{tenant}");
+        assert_eq!(dimensions(&response.bytes().await.unwrap()), (size, size), "{tenant}");
+    }
+
+    //Deleting in one tenant leaves the other's image alone.
+    server.tenant = first_tenant.clone();
+    let response = server.delete(&format!("/api/{id}")).send().await.unwrap();
+    assert!(response.status().is_success());
+    assert!(!stored_path(&server, &first_tenant, &id).exists());
+    server.tenant = second_tenant.clone();
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(stored_path(&server, &second_tenant, &id).exists());
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{error_code, png, TestServer};
+use uuid::Uuid;
+
+async fn status(server: &TestServer, path: &str) -> u16 {
+    server.get(path).send().await.unwrap().status().as_u16()
+}
+
+#[tokio::test]
+async fn new_transforms_are_throttled() {
+    let Some(server) = TestServer::start_with(&[("MAX_TRANSFORMS_PER_IMAGE", "3")]).await else {
+        return;
+    };
+    let id = server.upload(png(100, 100)).await;
+    for width in [10, 20, 30] {
+        assert_eq!(status(&server, &format!("/api/{id}?width={width}")).await, 200);
+    }
+
+    let response = server
+        .get(&format!("/api/{id}?width=40"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert_eq!(error_code(response).await, "too_many_transforms");
+    //Transforms already computed in the window and plain formats keep being served.
+    assert_eq!(status(&server, &format!("/api/{id}?width=10")).await, 200);
+    assert_eq!(status(&server, &format!("/api/{id}?format=png")).await, 200);
+    //Other images have limits of their own.
+    let other = server.upload(png(100, 100)).await;
+    assert_eq!(status(&server, &format!("/api/{other}?width=40")).await, 200);
+}
+
+#[tokio::test]
+async fn unknown_images_are_not_counted() {
+    let Some(server) = TestServer::start_with(&[("MAX_TRANSFORMS_PER_IMAGE", "1")]).await else {
+        return;
+    };
+    let id = Uuid::new_v4();
+    for width in [10, 20, 30] {
+        assert_eq!(status(&server, &format!("/api/{id}?width={width}")).await, 404);
+    }
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{error_code, uploaded_id, TestServer};
+
+//A valid header in front of pixel data that doesn't inflate, so the upload checks pass and the decode fails.
+fn broken_png() -> Vec<u8> {
+    let mut data = common::png(64, 64);
+    let header_end = 8 + 25;
+    for byte in &mut data[header_end + 8..] {
+        *byte = 0x55;
+    }
+    data
+}
+
+#[tokio::test]
+async fn sync_upload_is_served_right_away() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let id = server.upload(common::png(64, 48)).await;
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::dimensions(&response.bytes().await.unwrap()), (64, 48));
+}
+
+#[tokio::test]
+async fn undecodable_sync_upload_is_discarded() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server.upload_with(broken_png(), "").await;
+    assert_eq!(response.status(), 422);
+    assert_eq!(error_code(response).await, "not_stored");
+    assert!(common::stored_files(&server.image_folder()).is_empty());
+}
+
+#[tokio::test]
+async fn undecodable_upload_turns_into_not_found() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = server.upload_with(broken_png(), "sync=false").await;
+    assert_eq!(response.status(), 200);
+    let id = uploaded_id(response).await;
+    for _ in 0..100 {
+        let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+        if response.status() == 404 && error_code(response).await == "not_found" {
+            assert!(!server.log().contains("panicked"));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("the upload was never discarded");
+}
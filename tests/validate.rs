@@ -0,0 +1,43 @@
+mod common;
+
+use common::{error_code, png, TestServer};
+use reqwest::multipart;
+use serde_json::Value;
+
+async fn validate(server: &TestServer, data: Vec<u8>, query: &str) -> reqwest::Response {
+    let part = multipart::Part::bytes(data).file_name("upload");
+    server
+        .post(&format!("/api/validate?{query}"))
+        .multipart(multipart::Form::new().part("file", part))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn valid_image_is_described() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let response = validate(&server, png(64, 32), "store_as=webp").await;
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["format"], "png");
+    assert_eq!(body["stored_format"], "webp");
+    assert_eq!(
+        (body["width"].as_u64(), body["height"].as_u64()),
+        (Some(64), Some(32))
+    );
+}
+
+#[tokio::test]
+async fn undecodable_image_is_unsupported() {
+    let Some(server) = TestServer::start().await else {
+        return;
+    };
+    let mut data = png(64, 64);
+    data.truncate(data.len() / 2);
+    let response = validate(&server, data, "").await;
+    assert_eq!(response.status(), 415);
+    assert_eq!(error_code(response).await, "invalid_image");
+}
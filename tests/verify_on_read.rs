@@ -0,0 +1,81 @@
+mod common;
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use common::{error_code, png, TestServer};
+
+fn stored_file(server: &TestServer, extension: &str) -> Option<PathBuf> {
+    common::stored_files(&server.image_folder())
+        .into_iter()
+        .find(|path| path.extension().is_some_and(|found| found == extension))
+}
+
+//Flips a byte in the middle of the file, the way bit-rot would.
+fn corrupt(path: &PathBuf) {
+    let mut data = std::fs::read(path).unwrap();
+    let middle = data.len() / 2;
+    data[middle] ^= 0xFF;
+    std::fs::write(path, data).unwrap();
+}
+
+#[tokio::test]
+async fn corrupted_originals_are_detected() {
+    let Some(server) = TestServer::start_with(&[("VERIFY_ON_READ", "true")]).await else {
+        return;
+    };
+    let id = server.upload(png(32, 32)).await;
+
+    let response = server.get(&format!("/api/{id}")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let original = stored_file(&server, "png").unwrap();
+    corrupt(&original);
+    for query in ["", "?original=true"] {
+        let response = server.get(&format!("/api/{id}{query}")).send().await.unwrap();
+        assert_eq!(response.status(), 500, "{query}");
+        assert_eq!(error_code(response).await, "internal_error", "{query}");
+    }
+    assert!(server.log().contains("does not match its checksum"));
+    assert!(original.exists());
+}
+
+#[tokio::test]
+async fn corrupted_variants_are_discarded_and_transcoded_again() {
+    let Some(server) =
+        TestServer::start_with(&[("VERIFY_ON_READ", "true"), ("DISCARD_CORRUPTED", "true")]).await
+    else {
+        return;
+    };
+    let id = server.upload(png(32, 32)).await;
+    let path = format!("/api/{id}?format=jpg");
+    let response = server.get(&path).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    //Only a variant read back from its file is verified, so wait until it is served as a cache hit.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !server.log().contains("cache hit") {
+        assert!(Instant::now() < deadline, "the variant was never stored");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = server.get(&path).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    let variant = stored_file(&server, "jpg").unwrap();
+
+    corrupt(&variant);
+    let response = server.get(&path).send().await.unwrap();
+    assert_eq!(response.status(), 500);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = server.get(&path).send().await.unwrap();
+        if response.status() == 200 {
+            image::load_from_memory(&response.bytes().await.unwrap()).unwrap();
+            break;
+        }
+        assert!(Instant::now() < deadline, "the corrupted variant was never replaced");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,100 @@
+#![cfg(feature = "webhooks")]
+mod common;
+
+use std::time::Duration;
+
+use common::{png, TestServer};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+const SECRET: &str = "webhook-test-secret";
+
+//A delivered webhook, its signature header and body.
+struct Delivery {
+    signature: String,
+    body: Vec<u8>,
+}
+
+//Accepts webhooks on a local port, answering every one with 200 and handing it to the test.
+async fn receiver() -> (u16, mpsc::UnboundedReceiver<Delivery>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, deliveries) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let head_end = loop {
+                    if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                };
+                let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                let header = |name: &str| {
+                    head.lines()
+                        .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+                        .map(|value| value.trim().to_string())
+                };
+                let length: usize = header("content-length").unwrap().parse().unwrap();
+                let signature = header("x-webhook-signature").unwrap_or_default();
+                while request.len() < head_end + length {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+                let body = request[head_end..head_end + length].to_vec();
+                let _ = sender.send(Delivery { signature, body });
+            });
+        }
+    });
+    (port, deliveries)
+}
+
+#[tokio::test]
+async fn computed_uploads_are_announced_with_a_valid_signature() {
+    let (port, mut deliveries) = receiver().await;
+    let url = format!("http://127.0.0.1:{port}/hook");
+    let Some(server) =
+        TestServer::start_with(&[("WEBHOOK_URL", url.as_str()), ("WEBHOOK_SECRET", SECRET)]).await
+    else {
+        return;
+    };
+    let id = server.upload(png(16, 16)).await;
+
+    let delivery = tokio::time::timeout(Duration::from_secs(10), deliveries.recv())
+        .await
+        .expect("no webhook was delivered")
+        .unwrap();
+    let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+    mac.update(&delivery.body);
+    assert_eq!(
+        delivery.signature,
+        format!("sha256={:x}", mac.finalize().into_bytes())
+    );
+
+    let payload: Value = serde_json::from_slice(&delivery.body).unwrap();
+    assert_eq!(payload["event"], "computed");
+    assert_eq!(payload["id"], id);
+    assert_eq!(payload["tenant"], server.tenant);
+    assert_eq!(payload["format"], "png");
+    assert!(payload["timestamp"].is_string());
+}